@@ -20,6 +20,7 @@ if exist "lab-control-deployment" (
 mkdir "lab-control-deployment"
 mkdir "lab-control-deployment\frontend"
 mkdir "lab-control-deployment\frontend\icons"
+mkdir "lab-control-deployment\recipes"
 
 :: Copy executable
 echo Copying executable...
@@ -30,6 +31,10 @@ echo Copying frontend files...
 copy "frontend\index.html" "lab-control-deployment\frontend\"
 copy "frontend\icons\*" "lab-control-deployment\frontend\icons\" 2>nul
 
+:: Copy experiment recipes
+echo Copying recipes...
+copy "recipes\*" "lab-control-deployment\recipes\" 2>nul
+
 :: Create batch runner
 echo Creating run script...
 (
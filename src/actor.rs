@@ -0,0 +1,227 @@
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{info_span, warn};
+use crate::transport::Transport;
+
+// Used when a command doesn't ask for its own timeout
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often a command is retried after a transient bus error
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one
+    pub attempts: u32,
+    /// Wait between attempts, doubled after each retry
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+enum Operation {
+    Write(String),
+    Query(String),
+    Lock,
+    Unlock,
+}
+
+struct Request {
+    operation: Operation,
+    timeout: Duration,
+    /// Only set where sending the command twice is harmless
+    retry: bool,
+    reply: oneshot::Sender<io::Result<String>>,
+}
+
+/// Cloneable handle to an instrument owned by its own I/O thread.
+/// Commands from all clones are executed one at a time, in the order they arrive.
+#[derive(Clone)]
+pub struct InstrumentHandle {
+    name: &'static str,
+    sender: mpsc::Sender<Request>,
+}
+
+// Timeouts, dropped bus handshakes and interrupted calls are worth another try,
+// anything else (parse errors, invalid setup) would just fail again.
+// A timed out command may still have reached the device, so only commands marked `retry` are repeated.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::BrokenPipe | io::ErrorKind::Interrupted
+    )
+}
+
+fn execute(transport: &mut dyn Transport, operation: &Operation, timeout: Duration) -> io::Result<String> {
+    match operation {
+        Operation::Write(command) => {
+            transport.set_timeout(timeout)?;
+            transport.write_line(command).map(|_| String::new())
+        },
+        Operation::Query(command) => {
+            transport.set_timeout(timeout)?;
+            transport.write_line(command)?;
+            transport.read_line()
+        },
+        // The lock timeout goes to VISA directly, not through the session timeout
+        Operation::Lock => transport.lock_exclusive(timeout).map(|_| String::new()),
+        Operation::Unlock => transport.unlock().map(|_| String::new()),
+    }
+}
+
+fn run_actor(name: &'static str, mut transport: Box<dyn Transport>, policy: RetryPolicy, receiver: mpsc::Receiver<Request>) {
+    let _span = info_span!("instrument", name).entered();
+
+    // Runs until every handle has been dropped, then closes the transport
+    for request in receiver {
+        let mut backoff = policy.backoff;
+        let mut attempt = 1;
+        let result = loop {
+            match execute(transport.as_mut(), &request.operation, request.timeout) {
+                Err(err) if request.retry && is_transient(&err) && attempt < policy.attempts => {
+                    warn!(attempt, error = %err, "Command failed, retrying");
+                    thread::sleep(backoff);
+                    // Clear the device so a half-finished response doesn't leak into the retry
+                    if let Err(err) = transport.clear() {
+                        warn!(error = %err, "Failed to clear device");
+                    }
+                    backoff *= 2;
+                    attempt += 1;
+                },
+                result => break result,
+            }
+        };
+        // The caller may have given up waiting, that's fine
+        let _ = request.reply.send(result);
+    }
+}
+
+impl InstrumentHandle {
+    /// Moves the transport onto a dedicated I/O thread
+    pub fn spawn(name: &'static str, transport: Box<dyn Transport>, policy: RetryPolicy) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name(format!("instrument-{}", name))
+            .spawn(move || run_actor(name, transport, policy, receiver))?;
+        Ok(InstrumentHandle { name, sender })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn submit(&self, operation: Operation, timeout: Duration, retry: bool) -> io::Result<oneshot::Receiver<io::Result<String>>> {
+        let (reply, receiver) = oneshot::channel();
+        self.sender
+            .send(Request { operation, timeout, retry, reply })
+            .map_err(|_| self.closed())?;
+        Ok(receiver)
+    }
+
+    fn closed(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::NotConnected, format!("{} I/O thread has stopped", self.name))
+    }
+
+    /// Sends a command, blocking the current thread. Not for use on async workers.
+    /// Never retried, the device may have acted on a command that timed out.
+    pub fn write(&self, command: &str) -> io::Result<()> {
+        self.submit(Operation::Write(command.to_string()), DEFAULT_TIMEOUT, false)?
+            .blocking_recv()
+            .map_err(|_| self.closed())?
+            .map(|_| ())
+    }
+
+    /// Sends a query and waits for the response line, blocking the current thread
+    pub fn query(&self, command: &str) -> io::Result<String> {
+        self.query_timeout(command, DEFAULT_TIMEOUT)
+    }
+
+    /// Like `query`, for commands that take longer than the default timeout (e.g. reading a long log)
+    pub fn query_timeout(&self, command: &str, timeout: Duration) -> io::Result<String> {
+        self.submit(Operation::Query(command.to_string()), timeout, true)?
+            .blocking_recv()
+            .map_err(|_| self.closed())?
+    }
+
+    /// Sends a query that also does something, e.g. starts an OSA sweep, without retrying it
+    pub fn query_once(&self, command: &str, timeout: Duration) -> io::Result<String> {
+        self.submit(Operation::Query(command.to_string()), timeout, false)?
+            .blocking_recv()
+            .map_err(|_| self.closed())?
+    }
+
+    /// Takes an exclusive lock on the device so no other program can talk to it,
+    /// waiting up to `timeout` for another program to release it
+    pub fn lock_exclusive(&self, timeout: Duration) -> io::Result<()> {
+        self.submit(Operation::Lock, timeout, true)?
+            .blocking_recv()
+            .map_err(|_| self.closed())?
+            .map(|_| ())
+    }
+
+    pub fn unlock(&self) -> io::Result<()> {
+        self.submit(Operation::Unlock, DEFAULT_TIMEOUT, true)?
+            .blocking_recv()
+            .map_err(|_| self.closed())?
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    // Every command times out, counting how often it was sent
+    struct TimingOut(Arc<Mutex<usize>>);
+
+    impl Transport for TimingOut {
+        fn write_line(&mut self, _line: &str) -> io::Result<()> {
+            *self.0.lock().unwrap() += 1;
+            Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+        }
+
+        fn read_line(&mut self) -> io::Result<String> {
+            Ok(String::new())
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn timing_out() -> (InstrumentHandle, Arc<Mutex<usize>>) {
+        let sent = Arc::new(Mutex::new(0));
+        let policy = RetryPolicy { attempts: 3, backoff: Duration::ZERO };
+        (InstrumentHandle::spawn("osa", Box::new(TimingOut(sent.clone())), policy).unwrap(), sent)
+    }
+
+    #[test]
+    fn queries_are_retried() {
+        let (handle, sent) = timing_out();
+        assert_eq!(handle.query("MKA?;").unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(*sent.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn writes_are_sent_once() {
+        let (handle, sent) = timing_out();
+        assert!(handle.write("MEAS").is_err());
+        assert_eq!(*sent.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn queries_with_effects_are_sent_once() {
+        let (handle, sent) = timing_out();
+        assert!(handle.query_once("TS;DONE?;", DEFAULT_TIMEOUT).is_err());
+        assert_eq!(*sent.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,212 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use crate::config;
+use crate::lock::lock_or_recover;
+
+// Append only, the server never rotates or truncates it
+const AUDIT_DIR: &str = "audit";
+const AUDIT_FILE: &str = "audit.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    OutputOn,
+    OutputOff,
+    Current,
+    Power,
+    /// Raw SCPI written by an admin, which can change anything
+    RawCommand,
+}
+
+/// One change to a laser's output state or level
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub instrument: String,
+    /// Laser channel, for instruments with more than one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    pub kind: AuditKind,
+    /// New current in mA or power in dBm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    /// The raw command, or a level without a number such as DEF
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Last current or power set on this laser, when its output goes on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub setpoint: Option<f64>,
+    /// How long the output was on, when it goes off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_duration_s: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<u64>,
+}
+
+/// Who the driver calls on the current thread are made for
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub user: Option<String>,
+    pub run_id: Option<String>,
+    pub job_id: Option<u64>,
+    /// Replays drive simulated instruments, nothing is recorded
+    pub simulated: bool,
+}
+
+/// Restores the previous context when dropped
+pub struct ContextGuard {
+    previous: Context,
+}
+
+// What is known about each laser output between events
+#[derive(Default)]
+struct LaserState {
+    on_since: Option<Instant>,
+    setpoint: Option<f64>,
+}
+
+#[derive(Default)]
+struct AuditLog {
+    file: Option<File>,
+    lasers: HashMap<(String, Option<u8>), LaserState>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+static LOG: LazyLock<Mutex<AuditLog>> = LazyLock::new(|| Mutex::new(AuditLog::default()));
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn audit_path() -> PathBuf {
+    config::data_path(AUDIT_DIR).join(AUDIT_FILE)
+}
+
+/// Attributes audited changes made on this thread to `context` until the guard is dropped
+pub fn enter(context: Context) -> ContextGuard {
+    ContextGuard { previous: CONTEXT.with(|current| current.replace(context)) }
+}
+
+/// True while a replay drives simulated instruments on this thread, its results must not replace real ones
+pub fn is_simulated() -> bool {
+    CONTEXT.with(|current| current.borrow().simulated)
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = std::mem::take(&mut self.previous);
+        CONTEXT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+impl AuditLog {
+    fn append(&mut self, event: &AuditEvent) -> io::Result<()> {
+        if self.file.is_none() {
+            let path = audit_path();
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            self.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        let line = serde_json::to_string(event)?;
+        writeln!(self.file.as_ref().unwrap(), "{}", line)
+    }
+}
+
+fn record(instrument: &str, channel: Option<u8>, kind: AuditKind, value: Option<f64>, detail: Option<String>) {
+    let context = CONTEXT.with(|current| current.borrow().clone());
+    if context.simulated {
+        return;
+    }
+
+    let mut log = lock_or_recover(&LOG);
+    let laser = log.lasers.entry((instrument.to_string(), channel)).or_default();
+    let (setpoint, on_duration_s) = match kind {
+        AuditKind::OutputOn => {
+            laser.on_since.get_or_insert_with(Instant::now);
+            (laser.setpoint, None)
+        },
+        AuditKind::OutputOff => (None, laser.on_since.take().map(|since| since.elapsed().as_secs_f64())),
+        AuditKind::Current | AuditKind::Power => {
+            laser.setpoint = value;
+            (None, None)
+        },
+        AuditKind::RawCommand => (None, None),
+    };
+
+    let event = AuditEvent {
+        timestamp_ms: now_ms(),
+        instrument: instrument.to_string(),
+        channel,
+        kind,
+        value,
+        detail,
+        setpoint,
+        on_duration_s,
+        user: context.user,
+        run_id: context.run_id,
+        job_id: context.job_id,
+    };
+    // Auditing must never break instrument I/O, so failures are only reported
+    if let Err(err) = log.append(&event) {
+        warn!(error = %err, "Failed to write audit log");
+    }
+}
+
+pub fn output(instrument: &str, channel: Option<u8>, on: bool) {
+    let kind = if on { AuditKind::OutputOn } else { AuditKind::OutputOff };
+    record(instrument, channel, kind, None, None);
+}
+
+pub fn current_ma(instrument: &str, current_ma: f64) {
+    record(instrument, None, AuditKind::Current, Some(current_ma), None);
+}
+
+/// A power level in dBm, or a named level such as DEF
+pub fn power_dbm(instrument: &str, channel: Option<u8>, power_dbm: Option<f64>, level: Option<&str>) {
+    record(instrument, channel, AuditKind::Power, power_dbm, level.map(str::to_string));
+}
+
+pub fn raw_command(instrument: &str, command: &str) {
+    record(instrument, None, AuditKind::RawCommand, None, Some(command.to_string()));
+}
+
+/// Events for one instrument, or all, between two times in ms since the Unix epoch
+pub fn query(instrument: Option<&str>, from_ms: Option<u64>, to_ms: Option<u64>) -> io::Result<Vec<AuditEvent>> {
+    let path = audit_path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let Ok(event) = serde_json::from_str::<AuditEvent>(&line?) else {
+            continue;
+        };
+        if instrument.is_some_and(|name| event.instrument != name)
+            || from_ms.is_some_and(|from| event.timestamp_ms < from)
+            || to_ms.is_some_and(|to| event.timestamp_ms > to) {
+            continue;
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
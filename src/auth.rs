@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use crate::config::{self, Config};
+use crate::lock::lock_or_recover;
+
+// Accounts live below the data root, with hashed passwords and API tokens only
+const USERS_FILE: &str = "users.json";
+
+// Sessions end this long after login, API tokens don't expire
+const SESSION_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// What a user may do, each role includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Reads status, results and logs
+    Viewer,
+    /// Runs experiments, queues jobs and controls instruments through the drivers
+    Operator,
+    /// Sends raw SCPI, bypassing the safety limits
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    /// Argon2 hash in PHC string format
+    password_hash: String,
+    /// SHA-256 of each API token, the tokens themselves are only shown when created
+    #[serde(default)]
+    api_tokens: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UserFile {
+    users: Vec<User>,
+}
+
+/// Who made a request
+#[derive(Debug, Clone, Serialize)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+}
+
+struct Session {
+    name: String,
+    expires: Instant,
+}
+
+// Users as last read, reloaded when the file changes so CLI edits apply without a restart
+#[derive(Default)]
+struct UserCache {
+    modified: Option<SystemTime>,
+    users: Vec<User>,
+}
+
+/// Accounts and login sessions of the web server
+pub struct Auth {
+    users: Mutex<UserCache>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+/// Why a request was refused, turned into a 401 or 403 reply by `handle_rejection`
+#[derive(Debug)]
+pub enum AuthError {
+    Unauthorized,
+    Forbidden(Role),
+    /// Sent by another site's page through the operator's browser, or for a host name this server doesn't answer to
+    CrossOrigin,
+}
+
+impl warp::reject::Reject for AuthError {}
+
+#[derive(Serialize)]
+struct AuthErrorReply {
+    success: bool,
+    error: String,
+}
+
+fn load_users() -> Result<Vec<User>, String> {
+    let path = config::data_path(USERS_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    let file: UserFile = serde_json::from_str(&content)
+        .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))?;
+    Ok(file.users)
+}
+
+fn save_users(users: Vec<User>) -> Result<(), String> {
+    let path = config::data_path(USERS_FILE);
+    fs::create_dir_all(&config::get().data_dir)
+        .map_err(|err| format!("Failed to create data directory: {}", err))?;
+    let text = serde_json::to_string_pretty(&UserFile { users })
+        .map_err(|err| format!("Failed to serialize users: {}", err))?;
+    fs::write(&path, text).map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+}
+
+fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| format!("Failed to hash password: {}", err))
+}
+
+fn verify_password(user: &User, password: &str) -> bool {
+    PasswordHash::new(&user.password_hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Creates an account, the name must not be taken
+pub fn add_user(name: &str, role: Role, password: &str) -> Result<(), String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        return Err(format!("Invalid user name: {}", name));
+    }
+    let mut users = load_users()?;
+    if users.iter().any(|user| user.name == name) {
+        return Err(format!("User {} already exists", name));
+    }
+    users.push(User {
+        name: name.to_string(),
+        role,
+        password_hash: hash_password(password)?,
+        api_tokens: Vec::new(),
+    });
+    save_users(users)
+}
+
+pub fn remove_user(name: &str) -> Result<(), String> {
+    let mut users = load_users()?;
+    let count = users.len();
+    users.retain(|user| user.name != name);
+    if users.len() == count {
+        return Err(format!("Unknown user: {}", name));
+    }
+    save_users(users)
+}
+
+pub fn set_password(name: &str, password: &str) -> Result<(), String> {
+    let mut users = load_users()?;
+    let user = users.iter_mut().find(|user| user.name == name)
+        .ok_or_else(|| format!("Unknown user: {}", name))?;
+    user.password_hash = hash_password(password)?;
+    save_users(users)
+}
+
+/// Creates an API token for scripts, returned once and only stored hashed
+pub fn create_api_token(name: &str) -> Result<String, String> {
+    let mut users = load_users()?;
+    let user = users.iter_mut().find(|user| user.name == name)
+        .ok_or_else(|| format!("Unknown user: {}", name))?;
+    let token = random_token();
+    user.api_tokens.push(hash_token(&token));
+    save_users(users)?;
+    Ok(token)
+}
+
+/// Every account with its role and number of API tokens
+pub fn list_users() -> Result<Vec<(String, Role, usize)>, String> {
+    Ok(load_users()?
+        .into_iter()
+        .map(|user| (user.name, user.role, user.api_tokens.len()))
+        .collect())
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Auth {
+            users: Mutex::new(UserCache::default()),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn users(&self) -> Vec<User> {
+        let modified = fs::metadata(config::data_path(USERS_FILE)).and_then(|m| m.modified()).ok();
+        let mut cache = lock_or_recover(&self.users);
+        if cache.modified != modified || modified.is_none() {
+            match load_users() {
+                Ok(users) => *cache = UserCache { modified, users },
+                // Keep the accounts we have rather than locking everyone out over a bad edit
+                Err(err) => tracing::warn!(error = %err, "Failed to reload users"),
+            }
+        }
+        cache.users.clone()
+    }
+
+    /// Until the first account is created the server only listens on this machine, and everyone there is admin
+    pub fn open_access(&self) -> bool {
+        !config::data_path(USERS_FILE).exists() && config::get().bind.is_loopback()
+    }
+
+    /// Any account exists, so requests can be authenticated
+    pub fn has_users(&self) -> bool {
+        !self.users().is_empty()
+    }
+
+    /// Checks the password and starts a session, returning its token
+    pub fn login(&self, name: &str, password: &str) -> Result<(String, Identity), String> {
+        let user = self.users().into_iter().find(|user| user.name == name);
+        let user = match user {
+            Some(user) if verify_password(&user, password) => user,
+            _ => return Err("Invalid user name or password".to_string()),
+        };
+
+        let token = random_token();
+        let mut sessions = lock_or_recover(&self.sessions);
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(token.clone(), Session { name: user.name.clone(), expires: now + SESSION_TTL });
+        Ok((token, Identity { name: user.name, role: user.role }))
+    }
+
+    pub fn logout(&self, token: &str) {
+        lock_or_recover(&self.sessions).remove(token);
+    }
+
+    /// The user behind a session or API token, with their current role. Open access only admits
+    /// `same_origin` requests, it trusts everyone on this machine but not every site open in their browser.
+    pub fn identify(&self, token: Option<&str>, same_origin: bool) -> Option<Identity> {
+        if self.open_access() && same_origin {
+            return Some(Identity { name: "local".to_string(), role: Role::Admin });
+        }
+        let token = token?;
+        let users = self.users();
+
+        let session_user = {
+            let sessions = lock_or_recover(&self.sessions);
+            sessions.get(token)
+                .filter(|session| session.expires > Instant::now())
+                .map(|session| session.name.clone())
+        };
+        let user = match session_user {
+            Some(name) => users.into_iter().find(|user| user.name == name),
+            None => {
+                let hash = hash_token(token);
+                users.into_iter().find(|user| user.api_tokens.contains(&hash))
+            },
+        }?;
+        Some(Identity { name: user.name, role: user.role })
+    }
+}
+
+/// Token from an `Authorization: Bearer <token>` header
+pub fn bearer(header: Option<&str>) -> Option<&str> {
+    header.and_then(|value| value.strip_prefix("Bearer ")).map(str::trim)
+}
+
+// Splits a Host header into its name and port, IPv6 addresses come in brackets
+fn split_host(host: &str) -> Option<(&str, u16)> {
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => {
+            let (name, rest) = rest.split_once(']')?;
+            if rest.is_empty() { (name, None) } else { (name, Some(rest.strip_prefix(':')?)) }
+        },
+        None => match host.split_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (host, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => 80,
+    };
+    (!name.is_empty()).then_some((name, port))
+}
+
+/// True when the Host header names this server: its port, and its bind address, localhost or an allowed
+/// host name. Any other name may be a DNS rebinding page that resolves to this machine.
+pub fn is_allowed_host(host: &str, config: &Config) -> bool {
+    let Some((name, port)) = split_host(host) else {
+        return false;
+    };
+    if port != config.port {
+        return false;
+    }
+    match name.parse::<IpAddr>() {
+        // Addresses can't be rebound, every one of them reaches this server when it listens on all of them
+        Ok(address) => config.bind.is_unspecified() || address == config.bind,
+        Err(_) => name.eq_ignore_ascii_case("localhost")
+            || config.allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(name)),
+    }
+}
+
+/// True for requests to an allowed host from clients that aren't browsers, which send no origin, and
+/// from pages served by this server, whose origin names the same host and port
+pub fn is_same_origin(origin: Option<&str>, host: Option<&str>) -> bool {
+    let Some(host) = host.filter(|host| is_allowed_host(host, config::get())) else {
+        return false;
+    };
+    let Some(origin) = origin else {
+        return true;
+    };
+    // "null" from files and sandboxed frames has no host and never matches
+    let authority = origin.split_once("://").map_or("", |(_, authority)| authority);
+    authority.eq_ignore_ascii_case(host)
+}
+
+/// Rejects requests for another host or carrying another site's origin, before any route sees them
+pub fn same_origin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("host"))
+        .and_then(|origin: Option<String>, host: Option<String>| async move {
+            if is_same_origin(origin.as_deref(), host.as_deref()) {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(AuthError::CrossOrigin))
+            }
+        })
+        .untuple_one()
+}
+
+/// Identifies the caller and rejects the request unless they have at least `role`
+pub fn require(
+    auth: Arc<Auth>,
+    role: Role,
+) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
+        .and_then(move |header: Option<String>, origin: Option<String>, host: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                let same_origin = is_same_origin(origin.as_deref(), host.as_deref());
+                let identity = auth.identify(bearer(header.as_deref()), same_origin)
+                    .ok_or_else(|| warp::reject::custom(AuthError::Unauthorized))?;
+                if identity.role < role {
+                    return Err(warp::reject::custom(AuthError::Forbidden(role)));
+                }
+                Ok(identity)
+            }
+        })
+}
+
+/// Replies 401 or 403 to requests refused by `require` or `same_origin`, other rejections pass through
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    let (status, error) = match rejection.find::<AuthError>() {
+        Some(AuthError::Unauthorized) => (StatusCode::UNAUTHORIZED, "Login required".to_string()),
+        Some(AuthError::Forbidden(role)) => (StatusCode::FORBIDDEN, format!("Requires the {} role", role.as_str())),
+        Some(AuthError::CrossOrigin) => (StatusCode::FORBIDDEN, "Requests from other sites are not allowed".to_string()),
+        None => return Err(rejection),
+    };
+    let reply = warp::reply::json(&AuthErrorReply { success: false, error });
+    Ok(warp::reply::with_status(reply, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn served_pages_and_scripts_are_same_origin() {
+        assert!(is_same_origin(Some("http://127.0.0.1:3000"), Some("127.0.0.1:3000")));
+        assert!(is_same_origin(Some("http://LOCALHOST:3000"), Some("localhost:3000")));
+        // curl, the CLI and scripts send no origin
+        assert!(is_same_origin(None, Some("127.0.0.1:3000")));
+    }
+
+    #[test]
+    fn rebound_host_names_are_refused() {
+        // A page from evil.example resolved to 127.0.0.1 is same-origin with itself, but not this server
+        assert!(!is_same_origin(Some("http://evil.example:3000"), Some("evil.example:3000")));
+        assert!(!is_same_origin(None, Some("evil.example:3000")));
+        assert!(!is_same_origin(None, None));
+    }
+
+    #[test]
+    fn hosts_name_the_bound_address_and_port() {
+        let config = Config { allowed_hosts: vec!["LabPC".to_string()], ..Config::default() };
+        assert!(is_allowed_host("127.0.0.1:3000", &config));
+        assert!(is_allowed_host("localhost:3000", &config));
+        assert!(is_allowed_host("labpc:3000", &config));
+        assert!(!is_allowed_host("127.0.0.1:8080", &config));
+        assert!(!is_allowed_host("127.0.0.1", &config));
+        assert!(!is_allowed_host("192.168.1.5:3000", &config));
+        assert!(!is_allowed_host("other:3000", &config));
+        assert!(!is_allowed_host("", &config));
+
+        let everywhere = Config { bind: "0.0.0.0".parse().unwrap(), port: 80, ..Config::default() };
+        assert!(is_allowed_host("192.168.1.5", &everywhere));
+        assert!(is_allowed_host("[::1]:80", &everywhere));
+        assert!(!is_allowed_host("labpc", &everywhere));
+        assert!(!is_allowed_host("[::1]x", &everywhere));
+    }
+
+    #[test]
+    fn other_sites_are_cross_origin() {
+        assert!(!is_same_origin(Some("https://example.com"), Some("127.0.0.1:3000")));
+        assert!(!is_same_origin(Some("http://127.0.0.1:8080"), Some("127.0.0.1:3000")));
+        assert!(!is_same_origin(Some("null"), Some("127.0.0.1:3000")));
+        assert!(!is_same_origin(Some("http://127.0.0.1:3000"), None));
+    }
+
+    #[test]
+    fn open_access_admits_only_same_origin_requests() {
+        crate::config::init_for_tests();
+        let auth = Auth::new();
+        assert!(auth.open_access());
+        assert_eq!(auth.identify(None, true).map(|identity| identity.role), Some(Role::Admin));
+        assert!(auth.identify(None, false).is_none());
+    }
+
+    #[tokio::test]
+    async fn same_origin_filter_refuses_other_sites() {
+        let request = |origin: &str| warp::test::request().header("host", "127.0.0.1:3000").header("origin", origin);
+        assert!(request("http://127.0.0.1:3000").filter(&same_origin()).await.is_ok());
+        let rejection = request("https://example.com").filter(&same_origin()).await.unwrap_err();
+        assert!(matches!(rejection.find::<AuthError>(), Some(AuthError::CrossOrigin)));
+        let rebound = warp::test::request().header("host", "evil.example:3000").filter(&same_origin()).await.unwrap_err();
+        assert!(matches!(rebound.find::<AuthError>(), Some(AuthError::CrossOrigin)));
+    }
+}
@@ -0,0 +1,193 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::config;
+
+/// How far a stored wavelength table may sit inside the sweep range, because of rounding in the step
+pub const RANGE_TOLERANCE_NM: f64 = 1e-6;
+
+/// Loss of an optical path at one wavelength
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CalibrationPoint {
+    pub wavelength_nm: f64,
+    /// Power lost between the device and the power meter, positive for a loss
+    pub loss_db: f64,
+}
+
+/// Wavelength to loss table of one optical path to the power meter, e.g. fiber and connectors
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathCalibration {
+    #[serde(default)]
+    pub description: String,
+    /// When the table was measured, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub measured_at: Option<u64>,
+    /// Run that measured the table
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    pub points: Vec<CalibrationPoint>,
+}
+
+/// Summary returned by /api/calibrations
+#[derive(Debug, Serialize)]
+pub struct CalibrationSummary {
+    pub name: String,
+    pub description: String,
+    pub measured_at: Option<u64>,
+    pub min_nm: Option<f64>,
+    pub max_nm: Option<f64>,
+    pub points: usize,
+    pub error: Option<String>,
+}
+
+/// Optical path and reference names become file names, so only allow plain names
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn path_file(name: &str) -> Result<PathBuf, String> {
+    if !is_valid_name(name) {
+        return Err(format!("Invalid optical path name: {}", name));
+    }
+    Ok(config::get().calibration_dir.join(format!("{}.toml", name)))
+}
+
+impl PathCalibration {
+    /// Reads the table of a named optical path
+    pub fn load(name: &str) -> Result<PathCalibration, String> {
+        let path = path_file(name)?;
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("No calibration for optical path {} ({}): {}", name, path.display(), e))?;
+        let mut calibration: PathCalibration = toml::from_str(&text)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        calibration.validate().map_err(|e| format!("Invalid calibration {}: {}", path.display(), e))?;
+        calibration.points.sort_by(|a, b| a.wavelength_nm.total_cmp(&b.wavelength_nm));
+        Ok(calibration)
+    }
+
+    /// Writes the table of a named optical path, replacing any earlier one
+    pub fn save(&self, name: &str) -> Result<PathBuf, String> {
+        let path = path_file(name)?;
+        fs::create_dir_all(&config::get().calibration_dir)
+            .map_err(|e| format!("Failed to create calibration directory: {}", e))?;
+        let text = toml::to_string_pretty(self).map_err(|e| format!("Failed to serialize calibration: {}", e))?;
+        fs::write(&path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.points.is_empty() {
+            return Err("no points".to_string());
+        }
+        if self.points.iter().any(|p| !p.wavelength_nm.is_finite() || !p.loss_db.is_finite()) {
+            return Err("every point needs a finite wavelength and loss".to_string());
+        }
+        Ok(())
+    }
+
+    fn range(&self) -> (f64, f64) {
+        (self.points[0].wavelength_nm, self.points[self.points.len() - 1].wavelength_nm)
+    }
+
+    /// Checks the table covers every wavelength from `start_nm` to `stop_nm`, before any instrument is touched
+    pub fn check_covers(&self, start_nm: f64, stop_nm: f64) -> Result<(), String> {
+        let (min, max) = self.range();
+        if start_nm.min(stop_nm) < min - RANGE_TOLERANCE_NM || start_nm.max(stop_nm) > max + RANGE_TOLERANCE_NM {
+            return Err(format!(
+                "Calibration covers {:.3} to {:.3} nm, the experiment needs {:.3} to {:.3} nm",
+                min, max, start_nm.min(stop_nm), start_nm.max(stop_nm),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Loss at a wavelength, interpolated linearly between the nearest points
+    pub fn loss_db(&self, wavelength_nm: f64) -> Result<f64, String> {
+        self.check_covers(wavelength_nm, wavelength_nm)?;
+        let table: Vec<(f64, f64)> = self.points.iter().map(|p| (p.wavelength_nm, p.loss_db)).collect();
+        Ok(interpolate(&table, wavelength_nm))
+    }
+}
+
+/// Value at `x` in a table sorted by x, linear between the nearest points and flat beyond the ends
+pub fn interpolate(table: &[(f64, f64)], x: f64) -> f64 {
+    let upper = table.iter().position(|&(tx, _)| tx >= x).unwrap_or(table.len() - 1);
+    if upper == 0 {
+        return table[0].1;
+    }
+    let ((x0, y0), (x1, y1)) = (table[upper - 1], table[upper]);
+    if x1 == x0 {
+        return y1;
+    }
+    let fraction = ((x - x0) / (x1 - x0)).clamp(0.0, 1.0);
+    y0 + fraction * (y1 - y0)
+}
+
+/// Writes a wavelength and power row, with the meter's reading and the path loss when calibrated,
+/// followed by `extra` columns. Returns the power written, at the device when calibrated.
+pub fn write_power_row(
+    file: &mut impl Write,
+    calibration: Option<&PathCalibration>,
+    wavelength_nm: f64,
+    raw_dbm: f64,
+    extra: &str,
+) -> io::Result<f64> {
+    match calibration {
+        Some(calibration) => {
+            let loss = calibration.loss_db(wavelength_nm)
+                .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+            writeln!(file, "{:.3},{:.6},{:.6},{:.4}{}", wavelength_nm, raw_dbm + loss, raw_dbm, loss, extra)?;
+            Ok(raw_dbm + loss)
+        },
+        None => {
+            writeln!(file, "{:.3},{:.6}{}", wavelength_nm, raw_dbm, extra)?;
+            Ok(raw_dbm)
+        },
+    }
+}
+
+/// Column the power statistics follow, the meter's reading when calibrated
+pub fn raw_power_column(calibration: Option<&PathCalibration>) -> &'static str {
+    match calibration {
+        Some(_) => "Raw Power (dBm)",
+        None => "Power (dBm)",
+    }
+}
+
+/// Lists every calibration file, including ones that fail to load
+pub fn list() -> Vec<CalibrationSummary> {
+    let Ok(entries) = fs::read_dir(&config::get().calibration_dir) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == "toml"))
+        .filter_map(|path| path.file_stem().and_then(|s| s.to_str()).map(str::to_string))
+        .collect();
+    names.sort();
+
+    names.into_iter().map(|name| match PathCalibration::load(&name) {
+        Ok(calibration) => {
+            let (min, max) = calibration.range();
+            CalibrationSummary {
+                name,
+                description: calibration.description,
+                measured_at: calibration.measured_at,
+                min_nm: Some(min),
+                max_nm: Some(max),
+                points: calibration.points.len(),
+                error: None,
+            }
+        },
+        Err(err) => CalibrationSummary {
+            name,
+            description: String::new(),
+            measured_at: None,
+            min_nm: None,
+            max_nm: None,
+            points: 0,
+            error: Some(err),
+        },
+    }).collect()
+}
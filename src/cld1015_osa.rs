@@ -1,45 +1,46 @@
 use std::fs::{self, File, create_dir_all};
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, Write};
 use std::path::Path;
 use std::time::Duration;
-use visa_rs::prelude::*;
-use crate::visa_error::io_to_vs_err;
+use tracing::{info, info_span, warn};
+use crate::drivers;
+use crate::actor::InstrumentHandle;
+use crate::hysteresis::OrderedSweep;
+use crate::settle::{Probe, Settle};
+use crate::stats::{Repeats, SampleLog};
 
 /// Performs a current sweep with the CLD1015 laser diode 
 /// and captures spectral data from the HP-70952B optical spectrum analyzer
 pub fn run_current_sweep(
-    cld1015: &mut Instrument,
-    osa: &mut Instrument,
-    start_ma: f64,
-    stop_ma: f64,
-    step_ma: f64,
-    dwell_time_ms: u64,
-) -> visa_rs::Result<()> {
+    cld1015: &InstrumentHandle,
+    osa: &InstrumentHandle,
+    sweep: &OrderedSweep,
+    settle: &Settle,
+    repeats: &Repeats,
+    output_dir: &Path,
+) -> io::Result<()> {
     // Create a CSV file to save summary results
-    std::fs::create_dir_all("data").unwrap_or_else(|e| {
-        println!("Warning: Failed to create data directory: {}", e);
+    std::fs::create_dir_all(output_dir).unwrap_or_else(|e| {
+        warn!(error = %e, "Failed to create data directory");
     });
-    let mut file = File::create("data/current_sweep_results.csv").unwrap();
-    writeln!(file, "Current (mA),Peak Wavelength (nm),Peak Power (dBm)").unwrap();
+    let mut file = File::create(output_dir.join("current_sweep_results.csv")).unwrap();
+    writeln!(file, "Current (mA),Peak Wavelength (nm),Peak Power (dBm){}{}", repeats.header(&["Peak Wavelength (nm)", "Peak Power (dBm)"]), sweep.header()).unwrap();
+    let mut samples = SampleLog::new(repeats, &output_dir.join("current_sweep_samples.csv"), &["Current (mA)", "Peak Wavelength (nm)", "Peak Power (dBm)"])?;
     
     // Create a directory to store trace data files
-    let trace_dir = "data/current_sweep_trace_data";
-    create_dir_all(trace_dir).unwrap_or_else(|e| {
-        println!("Warning: Failed to create trace data directory: {}", e);
+    let trace_dir = output_dir.join("current_sweep_trace_data");
+    create_dir_all(&trace_dir).unwrap_or_else(|e| {
+        warn!(error = %e, "Failed to create trace data directory");
     });
     
-    // Calculate number of points
-    let num_points = ((stop_ma - start_ma) / step_ma).floor() as usize + 1;
-    println!("Starting current sweep with {} points", num_points);
+    info!(points = sweep.points.len(), order = ?sweep.order, "Starting current sweep");
     
-    // Set the CLD1015 to operate in Constant Current mode
-    cld1015.write_all(b"SOURce:FUNCtion:MODE CURRent\n").map_err(io_to_vs_err)?;
-    // Set current limit to a safe value
-    cld1015.write_all(b"SOURce:CURRent:LIMit:AMPLitude 100MA\n").map_err(io_to_vs_err)?;
+    // Constant current mode with a safe current limit
+    drivers::cld1015::configure(cld1015)?;
 
     // Configure the OSA for measurements
-    osa.write_all(b"SNGLS;\n").map_err(io_to_vs_err)?; // Set to single sweep mode
-    osa.write_all(b"CENTERWL 974.7NM;SPANWL 2NM;\n").map_err(io_to_vs_err)?;
+    osa.write("SNGLS;")?; // Set to single sweep mode
+    osa.write("CENTERWL 974.7NM;SPANWL 2NM;")?;
 
     let center_wl = 974.7; // Center wavelength in nm
     let span_wl = 2.0;    // Span in nm
@@ -47,107 +48,70 @@ pub fn run_current_sweep(
     let stop_wl = center_wl + (span_wl / 2.0);  
 
     // Get number of data points in trace
-    osa.write_all(b"MDS?;\n").map_err(io_to_vs_err)?;
-    let mut mds_response = String::new();
-    {
-        let mut reader = BufReader::new(&*osa);
-        reader.read_line(&mut mds_response).map_err(io_to_vs_err)?;
-    }
+    let mds_response = osa.query("MDS?;")?;
     let num_trace_points = mds_response.trim().parse::<usize>().unwrap_or(800); // Default 800 if parsing fails
-    println!("Trace has {} data points", num_trace_points);
+    info!(points = num_trace_points, "Trace size");
     
     // Turn laser OFF
-    cld1015.write_all(b"OUTPut:STATe 0\n").map_err(io_to_vs_err)?;
-    println!("Laser turned OFF");
+    drivers::cld1015::set_laser_output(cld1015, false)?;
+    info!("Laser turned OFF");
 
     // Turn TEC on before laser activation
-    cld1015.write_all(b"OUTPut2:STATe 1\n").map_err(io_to_vs_err)?;
+    drivers::cld1015::set_tec_output(cld1015, true)?;
 
     // Wait for initial stabilization
     std::thread::sleep(Duration::from_millis(100));
     
     // Turn laser ON
-    cld1015.write_all(b"OUTPut:STATe 1\n").map_err(io_to_vs_err)?;
-    println!("Laser turned ON");
+    drivers::cld1015::set_laser_output(cld1015, true)?;
+    info!("Laser turned ON");
     
     // Wait for initial stabilization
     std::thread::sleep(Duration::from_millis(100));
     
     // Perform the sweep
-    for i in 0..num_points {
-        let current_ma = start_ma + (i as f64 * step_ma);
-        
-        // Convert mA to A for the device
-        let current_a = current_ma / 1000.0;
-        
+    let mut readings = Vec::with_capacity(sweep.points.len());
+    for (index, point) in sweep.points.iter().enumerate() {
+        let current_ma = point.value;
+        let _point = info_span!("point", setpoint = current_ma, unit = "mA").entered();
+
         // Set the current
-        let cmd = format!("SOURce:CURRent:LEVel:IMMediate:AMPLitude {:.6}\n", current_a);
-        cld1015.write_all(cmd.as_bytes()).map_err(io_to_vs_err)?;
+        drivers::cld1015::set_current_ma(cld1015, current_ma)?;
         
-        println!("Set current to {:.2} mA", current_ma);
+        info!(current_ma, "Set current");
         
         // Wait for stabilization
-        std::thread::sleep(Duration::from_millis(dwell_time_ms));
-        println!("Starting sweep");
+        settle.wait(Probe::PeakWavelength(osa))?;
+        info!("Starting sweep");
         
-        // Trigger a new sweep on the OSA and confirm it's done before proceeding
-        osa.write_all(b"TS;DONE?;\n").map_err(io_to_vs_err)?; // Take sweep
-        let mut done_resp = String::new();
-        {
-            let mut reader = BufReader::new(&*osa);
-            reader.read_line(&mut done_resp).map_err(io_to_vs_err)?;
-        }
-        if done_resp.trim() != "1" {
-            println!("Warning: Sweep not confirmed complete. Response: {}", done_resp.trim());
-        }
-        
-        // Find peak
-        osa.write_all(b"MKPK HI;\n").map_err(io_to_vs_err)?; // Mark highest signal level
+        // Trigger a new sweep on the OSA and find its peak, once per repeat, the last sweep's trace is kept
+        let stats = repeats.measure(|| {
+            drivers::osa::take_sweep(osa)?;
+            let (wavelength_nm, power_dbm) = drivers::osa::read_peak(osa)?;
+            Ok(vec![wavelength_nm, power_dbm])
+        })?;
+        let (peak_wavelength_nm, peak_power_dbm) = (stats[0].mean, stats[1].mean);
         
-        // Get peak wavelength
-        osa.write_all(b"MKWL?;\n").map_err(io_to_vs_err)?;
-        let mut peak_wavelength = String::new();
-        {
-            let mut reader = BufReader::new(&*osa);
-            reader.read_line(&mut peak_wavelength).map_err(io_to_vs_err)?;
-        }
-        let peak_wavelength_nm = peak_wavelength.trim().parse::<f64>().unwrap_or(0.0) * 1.0e9; // Convert from meters to nm
-        
-        // Get peak amplitude
-        osa.write_all(b"MKA?;\n").map_err(io_to_vs_err)?;
-        let mut peak_power = String::new();
-        {
-            let mut reader = BufReader::new(&*osa);
-            reader.read_line(&mut peak_power).map_err(io_to_vs_err)?;
-        }
-        let peak_power_dbm = peak_power.trim().parse::<f64>().unwrap_or(-100.0);
-        
-        // Print measured values
-        println!("  Peak Wavelength: {:.3} nm", peak_wavelength_nm);
-        println!("  Peak Power: {:.2} dBm", peak_power_dbm);
+        // Log measured values
+        info!(peak_nm = peak_wavelength_nm, peak_dbm = peak_power_dbm, "Measured peak");
         
         // Write to results file
-        writeln!(file, "{:.2},{:.4},{:.2}", 
-                current_ma, peak_wavelength_nm, peak_power_dbm).unwrap();
+        writeln!(file, "{:.2},{:.4},{:.2}{}{}", 
+                current_ma, peak_wavelength_nm, peak_power_dbm, repeats.columns(&stats), sweep.columns(index)).unwrap();
+        readings.push((*point, vec![peak_wavelength_nm, peak_power_dbm]));
+        samples.write(current_ma, &stats)?;
         
         // Fetch the entire trace data
-        println!("Retrieving trace data...");
-        osa.write_all(b"TRA?;\n").map_err(io_to_vs_err)?;
-        
-        // Read trace data
-        let mut current_sweep_trace_data = String::new();
-        {
-            let mut reader = BufReader::new(&*osa);
-            reader.read_line(&mut current_sweep_trace_data).map_err(io_to_vs_err)?;
-        }
+        info!("Retrieving trace data...");
+        let current_sweep_trace_data = osa.query("TRA?;")?;
         
         // Calculate wavelength array for the x-axis
         let wavelength_step = (stop_wl - start_wl) / (num_trace_points as f64 - 1.0);
         
         // Create trace data file
-        let trace_filename = format!("{}/trace_{:.2}mA.csv", trace_dir, current_ma);
+        let trace_filename = format!("{}/trace_{:.2}mA{}.csv", trace_dir.display(), current_ma, sweep.file_suffix(point));
         let mut trace_file = File::create(&trace_filename).unwrap_or_else(|e| {
-            println!("Warning: Failed to create trace file {}: {}", trace_filename, e);
+            warn!(file = %trace_filename, error = %e, "Failed to create trace file");
             File::create("trace_data_fallback.csv").unwrap()
         });
         
@@ -164,40 +128,33 @@ pub fn run_current_sweep(
             }
         }
         
-        println!("  Trace data saved to {}", trace_filename);
+        info!(file = %trace_filename, "Trace data saved");
     }
     
     // Turn laser OFF
-    cld1015.write_all(b"OUTPut:STATe 0\n").map_err(io_to_vs_err)?;
-    println!("Laser turned OFF");
+    drivers::cld1015::set_laser_output(cld1015, false)?;
+    info!("Laser turned OFF");
 
-    osa.write_all(b"SWEEP OFF;\n").map_err(io_to_vs_err)?; // Turn off
+    osa.write("SWEEP OFF;")?; // Turn off
 
     // Check for errors on CLD1015
-    cld1015.write_all(b"SYST:ERR?\n").map_err(io_to_vs_err)?;
+    let response = cld1015.query("SYST:ERR?")?;
     
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&*cld1015);
-        reader.read_line(&mut response).map_err(io_to_vs_err)?;
-    }
-    
-    println!("Final error check on CLD1015: {}", response.trim());
+    info!(response = %response.trim(), "Final error check on CLD1015");
     
     // Check for errors on OSA
-    osa.write_all(b"XERR?;\n").map_err(io_to_vs_err)?;
-    
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&*osa);
-        reader.read_line(&mut response).map_err(io_to_vs_err)?;
-    }
+    let response = osa.query("XERR?;")?;
     
-    println!("Final error check on OSA: {}", response.trim());
+    info!(response = %response.trim(), "Final error check on OSA");
     
-    println!("Current sweep completed successfully");
-    println!("Summary results saved to current_sweep_results.csv");
-    println!("Trace data saved to {}/trace_*mA.csv files", trace_dir);
+    info!("Current sweep completed successfully");
+    info!(file = %output_dir.join("current_sweep_results.csv").display(), "Results saved");
+    info!(dir = %trace_dir.display(), "Trace data saved as trace_*mA.csv files");
+    sweep.write_hysteresis(
+        &output_dir.join("current_sweep_hysteresis.csv"),
+        &["Current (mA)", "Peak Wavelength (nm)", "Peak Power (dBm)"],
+        &readings,
+    )?;
     
     Ok(())
 }
@@ -0,0 +1,585 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use serde_json::json;
+use crate::devices::Devices;
+use crate::audit;
+use crate::auth::{self, Role};
+use crate::config::{self, Overrides};
+use crate::experiments;
+use crate::instruments::REGISTRY;
+use crate::queue;
+use crate::run_db::RunFilter;
+use crate::samples::SampleDetails;
+
+/// Experiment control server and command-line runner
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Starts the web server when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub settings: Overrides,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the web server
+    Serve,
+    /// Run one experiment and exit, writing the same outputs as the web server
+    Run {
+        /// Registered sample being measured
+        #[arg(long)]
+        sample: String,
+        #[command(subcommand)]
+        experiment: RunCommand,
+    },
+    /// List the instruments VISA can see and which known device each one is
+    Discover,
+    /// Connect to a device and print its identity
+    Idn {
+        /// Device name, e.g. cld1015, n77, power_meter or osa
+        device: String,
+    },
+    /// Re-run a recorded run against its transaction log, without hardware
+    Replay {
+        run_id: String,
+    },
+    /// Add results written before the run database existed to it
+    ImportRuns,
+    /// Manage the registry of samples runs are recorded against
+    Sample {
+        #[command(subcommand)]
+        action: SampleCommand,
+    },
+    /// Manage web server accounts
+    User {
+        #[command(subcommand)]
+        action: UserCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create an account, asking for its password
+    Add {
+        name: String,
+        #[arg(long, value_enum, default_value = "viewer")]
+        role: Role,
+    },
+    /// Delete an account
+    Remove {
+        name: String,
+    },
+    /// Change an account's password
+    Passwd {
+        name: String,
+    },
+    /// Create an API token for scripts, shown only once
+    Token {
+        name: String,
+    },
+    /// List accounts and their roles
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum SampleCommand {
+    /// Register a sample
+    Add {
+        sample_id: String,
+        #[arg(long)]
+        wafer: Option<String>,
+        #[arg(long)]
+        die: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+        /// Custom field, may be repeated
+        #[arg(long = "field", value_name = "KEY=VALUE", value_parser = parse_field)]
+        fields: Vec<(String, String)>,
+    },
+    /// List registered samples
+    List,
+    /// Show a sample's details and runs
+    Show {
+        sample_id: String,
+    },
+}
+
+fn parse_field(field: &str) -> Result<(String, String), String> {
+    field.split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .ok_or_else(|| format!("Expected KEY=VALUE, got {}", field))
+}
+
+/// Adaptive settling, the same fields the web API takes
+#[derive(Args, Serialize)]
+pub struct SettleArgs {
+    /// Settle until consecutive readings (power in dB, OSA peak in nm) differ by at most this
+    #[arg(long)]
+    settle_tolerance: Option<f64>,
+    /// Time between readings while settling [default: 500]
+    #[arg(long, requires = "settle_tolerance")]
+    settle_interval_ms: Option<u64>,
+    /// Measure anyway after this long without settling [default: 30000]
+    #[arg(long, requires = "settle_tolerance")]
+    settle_timeout_ms: Option<u64>,
+}
+
+impl SettleArgs {
+    // Adds the settle fields to an experiment's parameters
+    fn add_to(self, mut params: serde_json::Value) -> serde_json::Value {
+        if let (Some(params), Ok(serde_json::Value::Object(settle))) = (params.as_object_mut(), serde_json::to_value(self)) {
+            params.extend(settle);
+        }
+        params
+    }
+}
+
+/// Repeated readings at each setpoint, the same fields the web API takes
+#[derive(Args, Serialize)]
+pub struct RepeatArgs {
+    /// Readings per setpoint, the results hold their mean with std dev, min and max columns
+    #[arg(long)]
+    repeats: Option<u32>,
+    /// Also write every reading to a samples file
+    #[arg(long, requires = "repeats")]
+    keep_samples: bool,
+    /// Modified z-score above which a reading is flagged as an outlier [default: 3.5]
+    #[arg(long, requires = "repeats")]
+    outlier_threshold: Option<f64>,
+}
+
+impl RepeatArgs {
+    // Adds the repeat fields to an experiment's parameters
+    fn add_to(self, mut params: serde_json::Value) -> serde_json::Value {
+        if let (Some(params), Ok(serde_json::Value::Object(repeat))) = (params.as_object_mut(), serde_json::to_value(self)) {
+            params.extend(repeat);
+        }
+        params
+    }
+}
+
+/// Order a sweep visits its points in, the same fields the web API takes
+#[derive(Args, Serialize)]
+pub struct OrderArgs {
+    /// Sweep direction: up, down, up_down (up then back down) or random [default: up]
+    #[arg(long, value_parser = ["up", "down", "up_down", "random"])]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order: Option<String>,
+    /// Seed to shuffle a random order the same way again
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+impl OrderArgs {
+    // Adds the order fields to an experiment's parameters
+    fn add_to(self, mut params: serde_json::Value) -> serde_json::Value {
+        if let (Some(params), Ok(serde_json::Value::Object(order))) = (params.as_object_mut(), serde_json::to_value(self)) {
+            params.extend(order);
+        }
+        params
+    }
+}
+
+#[derive(Subcommand)]
+pub enum RunCommand {
+    /// Current sweep with OSA peak and trace capture
+    CurrentSweep {
+        #[arg(long)]
+        start_ma: f64,
+        #[arg(long)]
+        stop_ma: f64,
+        #[arg(long)]
+        step_ma: f64,
+        /// Wait after each current step [default: 100]
+        #[arg(long)]
+        dwell_time_ms: Option<u64>,
+        #[command(flatten)]
+        order: OrderArgs,
+        #[command(flatten)]
+        settle: SettleArgs,
+        #[command(flatten)]
+        repeat: RepeatArgs,
+    },
+    /// Single wavelength power measurement
+    WavelengthCheck {
+        /// Wavelength in nm
+        #[arg(long)]
+        wavelength: f64,
+        /// Calibrated optical path to correct the power for
+        #[arg(long)]
+        optical_path: Option<String>,
+        /// Wait after switching the laser on and after setting the wavelength [default: 200]
+        #[arg(long)]
+        stabilization_time_ms: Option<u64>,
+        #[command(flatten)]
+        settle: SettleArgs,
+        #[command(flatten)]
+        repeat: RepeatArgs,
+    },
+    /// Power vs wavelength scan
+    WavelengthSweep {
+        #[arg(long)]
+        start_nm: f64,
+        #[arg(long)]
+        stop_nm: f64,
+        #[arg(long)]
+        step_nm: f64,
+        /// Sweep continuously with the power meter logging on the laser's trigger, instead of stepping
+        #[arg(long)]
+        swept: bool,
+        /// Sweep speed in swept mode [default: 10]
+        #[arg(long, requires = "swept")]
+        speed_nm_s: Option<f64>,
+        /// Calibrated optical path to correct the power for
+        #[arg(long)]
+        optical_path: Option<String>,
+        /// Store the sweep as a reference under this name, measured through the bypass path
+        #[arg(long, conflicts_with = "reference")]
+        capture_reference: Option<String>,
+        /// Minutes a captured reference stays usable, at most a week [default: 240]
+        #[arg(long, requires = "capture_reference")]
+        reference_lifetime_min: Option<u64>,
+        /// Normalize against this stored reference, writing the insertion loss
+        #[arg(long)]
+        reference: Option<String>,
+        /// Wait after switching the laser on and after each wavelength step [default: 10000]
+        #[arg(long)]
+        stabilization_time_ms: Option<u64>,
+        #[command(flatten)]
+        order: OrderArgs,
+        #[command(flatten)]
+        settle: SettleArgs,
+        #[command(flatten)]
+        repeat: RepeatArgs,
+    },
+    /// Wavelength sweep with OSA peak and trace capture
+    WavelengthSweepOsa {
+        #[arg(long)]
+        start_nm: f64,
+        #[arg(long)]
+        stop_nm: f64,
+        #[arg(long)]
+        step_nm: f64,
+        /// Wait after switching the laser on and after each wavelength step [default: 10000]
+        #[arg(long)]
+        stabilization_time_ms: Option<u64>,
+        #[command(flatten)]
+        order: OrderArgs,
+        #[command(flatten)]
+        settle: SettleArgs,
+        #[command(flatten)]
+        repeat: RepeatArgs,
+    },
+    /// Measure the loss of an optical path to the power meter and save it as a calibration table
+    PathCalibration {
+        /// Name the table is saved under
+        #[arg(long)]
+        optical_path: String,
+        #[arg(long)]
+        start_nm: f64,
+        #[arg(long)]
+        stop_nm: f64,
+        #[arg(long)]
+        step_nm: f64,
+        /// Laser power the readings are compared with
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        laser_power_dbm: f64,
+        #[arg(long, default_value = "")]
+        description: String,
+        /// Wait after switching the laser on and after each wavelength step [default: 10000]
+        #[arg(long)]
+        stabilization_time_ms: Option<u64>,
+        #[command(flatten)]
+        settle: SettleArgs,
+        #[command(flatten)]
+        repeat: RepeatArgs,
+    },
+    /// Power or OSA peak at a fixed setpoint over time, with Allan deviation and drift
+    Stability {
+        /// Hold the N77 at this wavelength and read the power meter
+        #[arg(long, required_unless_present = "current_ma", conflicts_with = "current_ma")]
+        wavelength: Option<f64>,
+        /// Hold the CLD1015 at this current and read the OSA peak
+        #[arg(long)]
+        current_ma: Option<f64>,
+        /// Time between readings
+        #[arg(long)]
+        interval_s: f64,
+        #[arg(long)]
+        duration_min: f64,
+        /// Wait after switching the laser on, before the first reading [default: 10000]
+        #[arg(long)]
+        stabilization_time_ms: Option<u64>,
+    },
+    /// Generic sweep declared in a JSON file
+    Sweep {
+        plan: PathBuf,
+    },
+    /// Recipe file from the recipes directory
+    Recipe {
+        name: String,
+    },
+}
+
+impl RunCommand {
+    // Experiment name and parameters in the same form the web API takes them
+    fn experiment(self) -> Result<(String, serde_json::Value), String> {
+        Ok(match self {
+            RunCommand::CurrentSweep { start_ma, stop_ma, step_ma, dwell_time_ms, order, settle, repeat } => (
+                "current_sweep".to_string(),
+                order.add_to(repeat.add_to(settle.add_to(json!({
+                    "start_ma": start_ma,
+                    "stop_ma": stop_ma,
+                    "step_ma": step_ma,
+                    "dwell_time_ms": dwell_time_ms,
+                })))),
+            ),
+            RunCommand::WavelengthCheck { wavelength, optical_path, stabilization_time_ms, settle, repeat } => (
+                "wavelength_check".to_string(),
+                repeat.add_to(settle.add_to(json!({
+                    "wavelength": wavelength,
+                    "optical_path": optical_path,
+                    "stabilization_time_ms": stabilization_time_ms,
+                }))),
+            ),
+            RunCommand::WavelengthSweep {
+                start_nm,
+                stop_nm,
+                step_nm,
+                swept,
+                speed_nm_s,
+                optical_path,
+                capture_reference,
+                reference_lifetime_min,
+                reference,
+                stabilization_time_ms,
+                order,
+                settle,
+                repeat,
+            } => (
+                "wavelength_sweep".to_string(),
+                order.add_to(repeat.add_to(settle.add_to(json!({
+                    "start_nm": start_nm,
+                    "stop_nm": stop_nm,
+                    "step_nm": step_nm,
+                    "mode": if swept { "swept" } else { "stepped" },
+                    "speed_nm_s": speed_nm_s,
+                    "optical_path": optical_path,
+                    "capture_reference": capture_reference,
+                    "reference_lifetime_min": reference_lifetime_min,
+                    "reference": reference,
+                    "stabilization_time_ms": stabilization_time_ms,
+                })))),
+            ),
+            RunCommand::WavelengthSweepOsa { start_nm, stop_nm, step_nm, stabilization_time_ms, order, settle, repeat } => (
+                "wavelength_sweep_osa".to_string(),
+                order.add_to(repeat.add_to(settle.add_to(json!({
+                    "start_nm": start_nm,
+                    "stop_nm": stop_nm,
+                    "step_nm": step_nm,
+                    "stabilization_time_ms": stabilization_time_ms,
+                })))),
+            ),
+            RunCommand::PathCalibration {
+                optical_path,
+                start_nm,
+                stop_nm,
+                step_nm,
+                laser_power_dbm,
+                description,
+                stabilization_time_ms,
+                settle,
+                repeat,
+            } => (
+                "path_calibration".to_string(),
+                repeat.add_to(settle.add_to(json!({
+                    "optical_path": optical_path,
+                    "start_nm": start_nm,
+                    "stop_nm": stop_nm,
+                    "step_nm": step_nm,
+                    "laser_power_dbm": laser_power_dbm,
+                    "description": description,
+                    "stabilization_time_ms": stabilization_time_ms,
+                }))),
+            ),
+            RunCommand::Stability { wavelength, current_ma, interval_s, duration_min, stabilization_time_ms } => (
+                "stability".to_string(),
+                json!({
+                    "source": if current_ma.is_some() { "osa" } else { "power_meter" },
+                    "wavelength": wavelength,
+                    "current_ma": current_ma,
+                    "interval_s": interval_s,
+                    "duration_min": duration_min,
+                    "stabilization_time_ms": stabilization_time_ms,
+                }),
+            ),
+            RunCommand::Sweep { plan } => {
+                let content = fs::read_to_string(&plan)
+                    .map_err(|err| format!("Failed to read {}: {}", plan.display(), err))?;
+                let params = serde_json::from_str(&content)
+                    .map_err(|err| format!("Failed to parse {}: {}", plan.display(), err))?;
+                ("sweep".to_string(), params)
+            },
+            RunCommand::Recipe { name } => (name, serde_json::Value::Null),
+        })
+    }
+}
+
+/// Runs a command other than serve. Blocks on instrument I/O, so call it off the async runtime.
+pub fn execute(command: Command) -> Result<(), String> {
+    match command {
+        Command::Serve => Err("serve runs on the async runtime".to_string()),
+        Command::Run { sample, experiment } => run(&sample, experiment),
+        Command::Discover => discover(),
+        Command::Idn { device } => {
+            let idn = Devices::new().connect(&device)?;
+            println!("{}", idn.trim());
+            Ok(())
+        },
+        Command::Sample { action } => sample(action),
+        Command::User { action } => user(action),
+        Command::ImportRuns => {
+            let count = Devices::new().runs.import_legacy()?;
+            println!("Imported {} runs", count);
+            Ok(())
+        },
+        Command::Replay { run_id } => {
+            let report = experiments::replay_run(&run_id, Devices::new().transactions.clone(), operator().as_deref());
+            println!("{}", serde_json::to_string_pretty(&report).map_err(|err| err.to_string())?);
+            match report.success {
+                true => Ok(()),
+                false => Err(format!("Replay of {} did not match the recording", run_id)),
+            }
+        },
+    }
+}
+
+// CLI runs are recorded under the account of whoever is logged in to this machine
+fn operator() -> Option<String> {
+    std::env::var("USER").or_else(|_| std::env::var("USERNAME")).ok()
+}
+
+fn read_new_password() -> Result<String, String> {
+    let password = rpassword::prompt_password("Password: ").map_err(|err| err.to_string())?;
+    let confirm = rpassword::prompt_password("Repeat password: ").map_err(|err| err.to_string())?;
+    if password != confirm {
+        return Err("Passwords don't match".to_string());
+    }
+    if password.is_empty() {
+        return Err("Password can't be empty".to_string());
+    }
+    Ok(password)
+}
+
+fn user(command: UserCommand) -> Result<(), String> {
+    match command {
+        UserCommand::Add { name, role } => {
+            auth::add_user(&name, role, &read_new_password()?)?;
+            println!("Created {} with the {} role", name, role.as_str());
+        },
+        UserCommand::Remove { name } => {
+            auth::remove_user(&name)?;
+            println!("Removed {}", name);
+        },
+        UserCommand::Passwd { name } => {
+            auth::set_password(&name, &read_new_password()?)?;
+            println!("Changed the password of {}", name);
+        },
+        UserCommand::Token { name } => {
+            println!("{}", auth::create_api_token(&name)?);
+        },
+        UserCommand::List => {
+            for (name, role, tokens) in auth::list_users()? {
+                println!("{}  {}  {} API tokens", name, role.as_str(), tokens);
+            }
+        },
+    }
+    Ok(())
+}
+
+fn sample(command: SampleCommand) -> Result<(), String> {
+    let runs = Devices::new().runs;
+    match command {
+        SampleCommand::Add { sample_id, wafer, die, description, fields } => {
+            let fields = fields.into_iter().map(|(key, value)| (key, value.into())).collect();
+            let details = SampleDetails { wafer, die, description, fields };
+            runs.add_sample(&sample_id, details, operator().as_deref())?;
+            println!("Registered {}", sample_id);
+        },
+        SampleCommand::List => {
+            for sample in runs.list_samples()? {
+                println!(
+                    "{}  wafer {}  die {}  {}",
+                    sample.sample_id,
+                    sample.wafer.as_deref().unwrap_or("-"),
+                    sample.die.as_deref().unwrap_or("-"),
+                    sample.description.as_deref().unwrap_or(""),
+                );
+            }
+        },
+        SampleCommand::Show { sample_id } => {
+            let sample = runs.get_sample(&sample_id)?.ok_or_else(|| format!("Unknown sample: {}", sample_id))?;
+            println!("{}", serde_json::to_string_pretty(&sample).map_err(|err| err.to_string())?);
+            let filter = RunFilter { sample: Some(sample_id), ..Default::default() };
+            for run in runs.search(&filter)? {
+                println!("{}  {}  {}  {}", run.started_at, run.run_id, run.experiment, run.status);
+            }
+        },
+    }
+    Ok(())
+}
+
+fn run(sample: &str, command: RunCommand) -> Result<(), String> {
+    let (experiment, mut params) = command.experiment()?;
+    // Recipes take no parameters, the sample still has to be recorded
+    if params.is_null() {
+        params = json!({});
+    }
+    if let Some(object) = params.as_object_mut() {
+        object.insert("sample_id".to_string(), sample.into());
+    }
+    let devices = Devices::new();
+    devices.runs.require_sample(&params)?;
+
+    // The server connects on demand from the UI, here every instrument the experiment needs is opened up front
+    for name in experiments::experiment_instruments(&experiment, &params)? {
+        let idn = devices.connect(&name)?;
+        println!("{}: {}", name, idn.trim());
+    }
+
+    let run_id = format!("cli-{}", queue::now_secs());
+    let _audit = audit::enter(audit::Context {
+        user: operator(),
+        run_id: Some(run_id.clone()),
+        ..Default::default()
+    });
+    let data_dir = &config::get().data_dir;
+    let result_path = experiments::run_experiment(
+        &devices,
+        &run_id,
+        data_dir,
+        &experiment,
+        params,
+        operator().as_deref(),
+    )?;
+    println!("Run {} finished, results saved to {}", run_id, data_dir.join(result_path).display());
+    Ok(())
+}
+
+fn discover() -> Result<(), String> {
+    let resources = Devices::new().discover()?;
+    if resources.is_empty() {
+        println!("No instruments found");
+    }
+    for resource in resources {
+        match REGISTRY.iter().find(|info| info.resource == resource) {
+            Some(info) => println!("{}  {} ({})", resource, info.name, info.model),
+            None => println!("{}", resource),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use serde::Deserialize;
+
+// Read from the working directory when no config file is given
+const DEFAULT_CONFIG_FILE: &str = "lab.toml";
+
+/// Where the server listens and where it keeps its files
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Host names the server may be reached by besides its address and localhost, e.g. labpc.example.org
+    pub allowed_hosts: Vec<String>,
+    /// Root of experiment results, run and server logs and the queue
+    pub data_dir: PathBuf,
+    pub recipe_dir: PathBuf,
+    /// Loss tables of the optical paths to the power meter
+    pub calibration_dir: PathBuf,
+    /// Static files served next to the built-in page, e.g. icons/
+    pub frontend_dir: PathBuf,
+    /// Runs estimated to take longer are refused before they start
+    pub max_run_duration_min: u64,
+}
+
+/// Settings from the command line or the environment, these win over the config file
+#[derive(Debug, Default, clap::Args)]
+pub struct Overrides {
+    /// Config file, lab.toml in the working directory is used if it exists
+    #[arg(long, env = "LAB_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, 0.0.0.0 for every network interface [default: 127.0.0.1]
+    #[arg(long, env = "LAB_BIND", global = true)]
+    pub bind: Option<IpAddr>,
+    /// Port to listen on [default: 3000]
+    #[arg(long, env = "LAB_PORT", global = true)]
+    pub port: Option<u16>,
+    /// Host names the server may be reached by besides its address and localhost, comma separated
+    #[arg(long = "allowed-host", env = "LAB_ALLOWED_HOSTS", value_delimiter = ',', global = true)]
+    pub allowed_hosts: Vec<String>,
+    /// Root directory for results and logs [default: data]
+    #[arg(long, env = "LAB_DATA_DIR", global = true)]
+    pub data_dir: Option<PathBuf>,
+    /// Directory holding recipe files [default: recipes]
+    #[arg(long, env = "LAB_RECIPE_DIR", global = true)]
+    pub recipe_dir: Option<PathBuf>,
+    /// Directory holding optical path calibration tables [default: calibration]
+    #[arg(long, env = "LAB_CALIBRATION_DIR", global = true)]
+    pub calibration_dir: Option<PathBuf>,
+    /// Directory holding the frontend's static files [default: frontend]
+    #[arg(long, env = "LAB_FRONTEND_DIR", global = true)]
+    pub frontend_dir: Option<PathBuf>,
+    /// Longest a run may be estimated to take, in minutes [default: 120]
+    #[arg(long, env = "LAB_MAX_RUN_DURATION_MIN", global = true)]
+    pub max_run_duration_min: Option<u64>,
+}
+
+// Same settings as Config, anything missing keeps its default
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    allowed_hosts: Option<Vec<String>>,
+    data_dir: Option<PathBuf>,
+    recipe_dir: Option<PathBuf>,
+    calibration_dir: Option<PathBuf>,
+    frontend_dir: Option<PathBuf>,
+    max_run_duration_min: Option<u64>,
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3000,
+            allowed_hosts: Vec::new(),
+            data_dir: PathBuf::from("data"),
+            recipe_dir: PathBuf::from("recipes"),
+            calibration_dir: PathBuf::from("calibration"),
+            frontend_dir: PathBuf::from("frontend"),
+            max_run_duration_min: 120,
+        }
+    }
+}
+
+impl Config {
+    /// Defaults, then the config file, then the environment and command line
+    pub fn load(overrides: Overrides) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        let file = overrides.config.clone().or_else(|| {
+            let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+            default.exists().then_some(default)
+        });
+        if let Some(path) = file {
+            let content = fs::read_to_string(&path)
+                .map_err(|err| format!("Failed to read config file {}: {}", path.display(), err))?;
+            let file: ConfigFile = toml::from_str(&content)
+                .map_err(|err| format!("Failed to parse config file {}: {}", path.display(), err))?;
+
+            // Paths in the file are relative to the file, so a service finds them whatever its working directory
+            let base = path.parent().unwrap_or(Path::new(""));
+            config.bind = file.bind.unwrap_or(config.bind);
+            config.port = file.port.unwrap_or(config.port);
+            config.allowed_hosts = file.allowed_hosts.unwrap_or(config.allowed_hosts);
+            config.data_dir = file.data_dir.map_or(config.data_dir, |dir| base.join(dir));
+            config.recipe_dir = file.recipe_dir.map_or(config.recipe_dir, |dir| base.join(dir));
+            config.calibration_dir = file.calibration_dir.map_or(config.calibration_dir, |dir| base.join(dir));
+            config.frontend_dir = file.frontend_dir.map_or(config.frontend_dir, |dir| base.join(dir));
+            config.max_run_duration_min = file.max_run_duration_min.unwrap_or(config.max_run_duration_min);
+        }
+
+        config.bind = overrides.bind.unwrap_or(config.bind);
+        config.port = overrides.port.unwrap_or(config.port);
+        if !overrides.allowed_hosts.is_empty() {
+            config.allowed_hosts = overrides.allowed_hosts;
+        }
+        config.data_dir = overrides.data_dir.unwrap_or(config.data_dir);
+        config.recipe_dir = overrides.recipe_dir.unwrap_or(config.recipe_dir);
+        config.calibration_dir = overrides.calibration_dir.unwrap_or(config.calibration_dir);
+        config.frontend_dir = overrides.frontend_dir.unwrap_or(config.frontend_dir);
+        config.max_run_duration_min = overrides.max_run_duration_min.unwrap_or(config.max_run_duration_min);
+        Ok(config)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
+
+/// Sets the configuration for the rest of the program, call once at startup before anything reads it
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/// The configuration set at startup, or the defaults if none was set
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// A path below the data root
+pub fn data_path(path: impl AsRef<Path>) -> PathBuf {
+    get().data_dir.join(path)
+}
+
+/// Points every directory below a fresh one in the system temp directory, so tests never touch lab data.
+/// Call before anything reads the configuration.
+#[cfg(test)]
+pub fn init_for_tests() -> &'static Config {
+    let root = std::env::temp_dir().join(format!("automating_experiments_tests_{}", std::process::id()));
+    init(Config {
+        data_dir: root.join("data"),
+        recipe_dir: root.join("recipes"),
+        calibration_dir: root.join("calibration"),
+        ..Config::default()
+    });
+    get()
+}
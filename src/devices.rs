@@ -0,0 +1,389 @@
+use std::ffi::CString;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+use visa_rs::prelude::*;
+use tracing::{info, warn};
+use crate::actor::{InstrumentHandle, RetryPolicy};
+use crate::drivers;
+use crate::health::{Health, HealthRecord};
+use crate::instruments::{self, InstrumentInfo};
+use crate::live::LiveFeeds;
+use crate::run_db::RunDb;
+use crate::sweep::SweepInstruments;
+use crate::transactions::{LoggingTransport, RunLogGuard, TransactionLog};
+use crate::transport::{Transport, VisaTransport};
+use crate::lock::lock_or_recover;
+
+/// One instrument with its own lock, so disjoint experiments can run concurrently
+pub struct DeviceSlot {
+    pub info: &'static InstrumentInfo,
+    instrument: Mutex<Option<InstrumentHandle>>,
+    /// Last identification string, readable while the instrument is busy
+    idn: Mutex<String>,
+    health: Mutex<HealthRecord>,
+}
+
+/// Connection state of a device as seen by status queries
+pub struct DeviceStatus {
+    pub connected: bool,
+    /// The instrument lock is held by a running experiment
+    pub busy: bool,
+    pub idn: String,
+}
+
+/// All instruments known to the server
+pub struct Devices {
+    rm: Mutex<Option<DefaultRM>>,
+    slots: Vec<DeviceSlot>,
+    /// Every command and response of every device
+    pub transactions: Arc<TransactionLog>,
+    /// Index of every run and its results
+    pub runs: Arc<RunDb>,
+    /// Readings of running time series experiments
+    pub live: Arc<LiveFeeds>,
+}
+
+// How long a job waits for another program to release an instrument
+const VISA_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Instrument locks held for the duration of an experiment.
+/// Each device is also locked exclusively on the VISA side until this is dropped.
+pub struct LockedDevices<'a> {
+    /// Declared first so the run log stops before the instruments are released
+    run_log: Option<RunLogGuard>,
+    guards: Vec<(&'static str, MutexGuard<'a, Option<InstrumentHandle>>)>,
+}
+
+impl DeviceSlot {
+    /// Current status without waiting for a running experiment
+    pub fn status(&self) -> DeviceStatus {
+        let idn = lock_or_recover(&self.idn).clone();
+        match self.instrument.try_lock() {
+            Ok(guard) => DeviceStatus { connected: guard.is_some(), busy: false, idn },
+            Err(TryLockError::Poisoned(poisoned)) => DeviceStatus {
+                connected: poisoned.into_inner().is_some(),
+                busy: false,
+                idn,
+            },
+            // Only a connected instrument can be held by an experiment
+            Err(TryLockError::WouldBlock) => DeviceStatus { connected: true, busy: true, idn },
+        }
+    }
+
+    pub fn health(&self) -> HealthRecord {
+        lock_or_recover(&self.health).clone()
+    }
+
+    pub fn with_health<R>(&self, f: impl FnOnce(&mut HealthRecord) -> R) -> R {
+        f(&mut lock_or_recover(&self.health))
+    }
+
+    /// Runs `f` on the device if it's connected and no experiment holds it, keeping the lock until `f` returns
+    /// so an experiment can't start in between. None if the device is busy or not connected.
+    pub fn with_idle<R>(&self, f: impl FnOnce(&InstrumentHandle) -> R) -> Option<R> {
+        let guard = match self.instrument.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        guard.as_ref().map(f)
+    }
+}
+
+impl Devices {
+    pub fn new() -> Self {
+        Devices {
+            rm: Mutex::new(None),
+            slots: instruments::REGISTRY
+                .iter()
+                .map(|info| DeviceSlot {
+                    info,
+                    instrument: Mutex::new(None),
+                    idn: Mutex::new(String::new()),
+                    health: Mutex::new(HealthRecord::default()),
+                })
+                .collect(),
+            transactions: Arc::new(TransactionLog::new()),
+            runs: Arc::new(RunDb::new()),
+            live: Arc::new(LiveFeeds::default()),
+        }
+    }
+
+    /// Devices backed by the given transports instead of VISA, e.g. to replay a recorded run
+    pub fn with_transports(
+        transports: Vec<(String, Box<dyn Transport>)>,
+        transactions: Arc<TransactionLog>,
+    ) -> std::result::Result<Self, String> {
+        let mut devices = Devices::new();
+        devices.transactions = transactions;
+        // Simulated runs are checks of old results, not new ones
+        devices.runs = Arc::new(RunDb::disabled());
+        for (name, transport) in transports {
+            let slot = devices.slot(&name).ok_or_else(|| format!("Unknown device: {}", name))?;
+            let transport = LoggingTransport::new(slot.info.name, transport, devices.transactions.clone());
+            let handle = InstrumentHandle::spawn(slot.info.name, Box::new(transport), RetryPolicy::default())
+                .map_err(|err| format!("Failed to start I/O thread for {}: {}", slot.info.label, err))?;
+            *lock_or_recover(&slot.instrument) = Some(handle);
+        }
+        Ok(devices)
+    }
+
+    pub fn slot(&self, name: &str) -> Option<&DeviceSlot> {
+        self.slots.iter().find(|slot| slot.info.name == name)
+    }
+
+    pub fn slots(&self) -> &[DeviceSlot] {
+        &self.slots
+    }
+
+    // Initializes the resource manager on first use
+    fn resource_manager(&self) -> std::result::Result<MutexGuard<'_, Option<DefaultRM>>, String> {
+        let mut rm = lock_or_recover(&self.rm);
+        if rm.is_none() {
+            *rm = match DefaultRM::new() {
+                Ok(rm) => Some(rm),
+                Err(err) => return Err(format!("Failed to init VISA resource manager: {}", err)),
+            };
+        }
+        Ok(rm)
+    }
+
+    /// Resource strings of every instrument VISA can see, connected to the server or not
+    pub fn discover(&self) -> std::result::Result<Vec<String>, String> {
+        let rm = self.resource_manager()?;
+        let pattern = CString::new("?*::INSTR").unwrap();
+        let resources = rm.as_ref().unwrap().find_res_list(&pattern.into())
+            .map_err(|err| format!("Failed to list VISA resources: {}", err))?;
+        resources
+            .map(|resource| resource
+                .map(|resource| resource.to_string_lossy().into_owned())
+                .map_err(|err| format!("Failed to list VISA resources: {}", err)))
+            .collect()
+    }
+
+    /// Opens the device if needed, then clears it and queries its identity.
+    /// Returns the current identity without touching the device if an experiment is using it.
+    pub fn connect(&self, name: &str) -> std::result::Result<String, String> {
+        let slot = self.slot(name).ok_or_else(|| format!("Unknown device: {}", name))?;
+        let label = slot.info.label;
+
+        let mut instrument = match slot.instrument.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(lock_or_recover(&slot.idn).clone()),
+        };
+
+        // Open device if not already open
+        if instrument.is_none() {
+            let rm = self.resource_manager()?;
+
+            let resource = CString::new(slot.info.resource).unwrap();
+            let inst = match rm.as_ref().unwrap().open(
+                &resource.into(),
+                AccessMode::NO_LOCK,
+                Duration::from_secs(1),
+            ) {
+                Ok(inst) => inst,
+                Err(err) => return Err(format!("Failed to open {}: {}", label, err)),
+            };
+
+            // From here on all I/O with the device goes through its own thread
+            let transport = LoggingTransport::new(slot.info.name, Box::new(VisaTransport::new(inst)), self.transactions.clone());
+            let handle = InstrumentHandle::spawn(slot.info.name, Box::new(transport), RetryPolicy::default())
+                .map_err(|err| format!("Failed to start I/O thread for {}: {}", label, err))?;
+            *instrument = Some(handle);
+        }
+
+        // Get the device
+        let device = instrument.as_ref().unwrap();
+
+        // Clear any errors
+        if let Some(clear) = slot.info.clear_command {
+            drivers::write(device, clear).map_err(|err| format!("Failed to clear {}: {}", label, err))?;
+        }
+
+        // Query device identity
+        let start = Instant::now();
+        let response = drivers::query(device, slot.info.idn_command)
+            .map_err(|err| format!("Failed to query {}: {}", label, err))?;
+
+        // Save info
+        *lock_or_recover(&slot.idn) = response.clone();
+        slot.with_health(|record| record.record_ok(start.elapsed(), "Connected"));
+        Ok(response)
+    }
+
+    /// Drops the current session, if any, and opens a fresh one
+    pub fn reconnect(&self, name: &str) -> std::result::Result<String, String> {
+        let slot = self.slot(name).ok_or_else(|| format!("Unknown device: {}", name))?;
+        match slot.instrument.try_lock() {
+            Ok(mut guard) => *guard = None,
+            Err(TryLockError::Poisoned(poisoned)) => *poisoned.into_inner() = None,
+            Err(TryLockError::WouldBlock) => return Err(format!("{} is busy", slot.info.label)),
+        }
+        self.connect(name)
+    }
+
+    /// Drops a session that stopped answering so experiments fail fast instead of reusing it.
+    /// Does nothing while an experiment holds the device.
+    pub fn disconnect_stale(&self, name: &str, detail: &str) {
+        let Some(slot) = self.slot(name) else { return };
+        let mut guard = match slot.instrument.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+        if guard.take().is_some() {
+            warn!(instrument = name, reason = detail, "Marked disconnected");
+            slot.with_health(|record| record.set(Health::Disconnected, detail));
+        }
+    }
+
+    /// Locks the named devices, always in registry order so two experiments can't deadlock.
+    /// Fails if any of them is not connected.
+    pub fn lock(&self, names: &[&str]) -> std::result::Result<LockedDevices<'_>, String> {
+        for name in names {
+            if self.slot(name).is_none() {
+                return Err(format!("Unknown device: {}", name));
+            }
+        }
+
+        // Devices locked so far are released by the drop if a later one fails
+        let mut locked = LockedDevices { run_log: None, guards: Vec::new() };
+        for slot in self.slots.iter().filter(|s| names.contains(&s.info.name)) {
+            let guard = lock_or_recover(&slot.instrument);
+            let Some(device) = guard.as_ref() else {
+                return Err(format!("{} not connected", slot.info.label));
+            };
+            device.lock_exclusive(VISA_LOCK_TIMEOUT)
+                .map_err(|err| format!("Failed to lock {}, is another program using it? {}", slot.info.label, err))?;
+            locked.guards.push((slot.info.name, guard));
+        }
+        Ok(locked)
+    }
+
+    /// Locks the named devices for a run, then copies their transactions into the run's log until the
+    /// locks are released. Starting the log only now keeps health pings and manual commands out of it.
+    pub fn lock_for_run(&self, run_id: &str, names: &[&str]) -> std::result::Result<LockedDevices<'_>, String> {
+        let mut locked = self.lock(names)?;
+        let instruments: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let run_log = self.transactions.begin_run(run_id, &instruments)
+            .map_err(|err| format!("Failed to create run log: {}", err))?;
+        locked.run_log = Some(run_log);
+        Ok(locked)
+    }
+
+    /// Closes the VISA session so other programs can open the device.
+    /// It stays closed, also for the health monitor, until the next connection check.
+    pub fn disconnect(&self, name: &str) -> std::result::Result<(), String> {
+        let slot = self.slot(name).ok_or_else(|| format!("Unknown device: {}", name))?;
+        let mut guard = match slot.instrument.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(format!("{} is in use by an experiment", slot.info.label)),
+        };
+
+        // The session closes once the I/O thread has no more handles
+        if guard.take().is_some() {
+            info!(instrument = name, "Disconnected on request");
+        }
+        slot.with_health(|record| {
+            if record.health != Health::Unknown {
+                record.set(Health::Released, "Disconnected on request");
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for LockedDevices<'_> {
+    fn drop(&mut self) {
+        for (name, guard) in &self.guards {
+            if let Some(device) = guard.as_ref()
+                && let Err(err) = device.unlock() {
+                warn!(instrument = *name, error = %err, "Failed to unlock");
+            }
+        }
+    }
+}
+
+impl LockedDevices<'_> {
+    pub fn get(&self, name: &str) -> Option<&InstrumentHandle> {
+        self.guards
+            .iter()
+            .find(|(n, _)| *n == name)
+            .and_then(|(_, guard)| guard.as_ref())
+    }
+
+    /// Borrows the locked instruments for the sweep engine
+    pub fn instruments(&self) -> SweepInstruments<'_> {
+        let mut instruments = SweepInstruments::default();
+        for (name, guard) in self.guards.iter() {
+            let instrument = guard.as_ref();
+            match *name {
+                "cld1015" => instruments.cld1015 = instrument,
+                "n77" => instruments.n77 = instrument,
+                "power_meter" => instruments.power_meter = instrument,
+                "osa" => instruments.osa = instrument,
+                _ => {},
+            }
+        }
+        instruments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    struct Answering;
+
+    impl Transport for Answering {
+        fn write_line(&mut self, _line: &str) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn read_line(&mut self) -> io::Result<String> {
+            Ok("OSA".to_string())
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn busy_or_disconnected_devices_are_not_idle() {
+        crate::config::init_for_tests();
+        let transports: Vec<(String, Box<dyn Transport>)> = vec![("osa".to_string(), Box::new(Answering))];
+        let devices = Devices::with_transports(transports, Arc::new(TransactionLog::new())).unwrap();
+        let osa = devices.slot("osa").unwrap();
+        assert_eq!(osa.with_idle(|handle| handle.query("*IDN?").unwrap()).as_deref(), Some("OSA"));
+
+        let locked = devices.lock(&["osa"]).unwrap();
+        assert!(osa.with_idle(|_| ()).is_none());
+        drop(locked);
+        assert!(osa.with_idle(|_| ()).is_some());
+        assert!(devices.slot("cld1015").unwrap().with_idle(|_| ()).is_none());
+    }
+
+    #[test]
+    fn run_log_starts_once_the_devices_are_locked() {
+        crate::config::init_for_tests();
+        let transports: Vec<(String, Box<dyn Transport>)> = vec![("osa".to_string(), Box::new(Answering))];
+        let devices = Devices::with_transports(transports, Arc::new(TransactionLog::new())).unwrap();
+        let run_log = crate::transactions::run_log_path("locked-run");
+        let _ = std::fs::remove_file(&run_log);
+
+        // A health ping just before the run
+        devices.slot("osa").unwrap().with_idle(|handle| handle.query("*IDN?").unwrap());
+        let locked = devices.lock_for_run("locked-run", &["osa"]).unwrap();
+        locked.get("osa").unwrap().query("TRA?;").unwrap();
+        drop(locked);
+        devices.slot("osa").unwrap().with_idle(|handle| handle.query("*IDN?").unwrap());
+
+        let logged: Vec<String> = crate::transactions::read_log(&run_log).unwrap().into_iter().map(|t| t.data).collect();
+        assert_eq!(logged, vec!["TRA?;", "OSA"]);
+    }
+}
@@ -0,0 +1,298 @@
+use std::io;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tracing::warn;
+use crate::actor::InstrumentHandle;
+use crate::audit;
+
+// An OSA sweep can take much longer than a regular command
+const OSA_SWEEP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Reading back a full power meter log transfers up to a million values
+const LOG_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Writes a single command line to an instrument
+pub fn write(inst: &InstrumentHandle, cmd: &str) -> io::Result<()> {
+    inst.write(cmd)
+}
+
+/// Writes a query and reads back one response line
+pub fn query(inst: &InstrumentHandle, cmd: &str) -> io::Result<String> {
+    inst.query(cmd)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// CLD1015 laser diode and TEC controller (USB)
+pub mod cld1015 {
+    use super::*;
+
+    /// Puts the driver in constant current mode with a safe current limit
+    pub fn configure(inst: &InstrumentHandle) -> io::Result<()> {
+        write(inst, "SOURce:FUNCtion:MODE CURRent")?;
+        write(inst, "SOURce:CURRent:LIMit:AMPLitude 100MA")
+    }
+
+    pub fn set_laser_output(inst: &InstrumentHandle, on: bool) -> io::Result<()> {
+        write(inst, &format!("OUTPut:STATe {}", on as u8))?;
+        audit::output(inst.name(), None, on);
+        Ok(())
+    }
+
+    pub fn set_tec_output(inst: &InstrumentHandle, on: bool) -> io::Result<()> {
+        write(inst, &format!("OUTPut2:STATe {}", on as u8))
+    }
+
+    pub fn set_current_ma(inst: &InstrumentHandle, current_ma: f64) -> io::Result<()> {
+        // The device expects amperes
+        write(inst, &format!("SOURce:CURRent:LEVel:IMMediate:AMPLitude {:.6}", current_ma / 1000.0))?;
+        audit::current_ma(inst.name(), current_ma);
+        Ok(())
+    }
+
+    pub fn set_temperature_c(inst: &InstrumentHandle, temperature_c: f64) -> io::Result<()> {
+        write(inst, "UNIT:TEMPerature C")?;
+        write(inst, &format!("SOURce2:TEMPerature:SPOint {:.3}", temperature_c))
+    }
+
+    pub fn read_current_ma(inst: &InstrumentHandle) -> io::Result<f64> {
+        let response = query(inst, "MEASure:CURRent?")?;
+        response.parse::<f64>()
+            .map(|a| a * 1000.0)
+            .map_err(|_| invalid_data(format!("Failed to parse CLD1015 current: {}", response)))
+    }
+
+    pub fn read_laser_output(inst: &InstrumentHandle) -> io::Result<bool> {
+        Ok(query(inst, "OUTPut:STATe?")? == "1")
+    }
+
+    pub fn read_temperature_c(inst: &InstrumentHandle) -> io::Result<f64> {
+        let response = query(inst, "MEASure:TEMPerature?")?;
+        response.parse::<f64>()
+            .map_err(|_| invalid_data(format!("Failed to parse CLD1015 temperature: {}", response)))
+    }
+
+    pub fn read_error(inst: &InstrumentHandle) -> io::Result<String> {
+        query(inst, "SYST:ERR?")
+    }
+}
+
+/// N7714A tunable laser, the experiments use laser 2
+pub mod n77 {
+    use super::*;
+
+    /// Laser channel used by the experiments
+    pub const LASER: u8 = 2;
+    pub const CHANNELS: RangeInclusive<u8> = 1..=4;
+
+    /// Turns the output off and selects dBm at the default power level
+    pub fn configure(inst: &InstrumentHandle) -> io::Result<()> {
+        set_output(inst, false)?;
+        write(inst, ":SOURce2:WAVelength:AUTO 1")?;
+        write(inst, ":SOURce2:POWer:UNit 0")?;  // Set power unit to dBm
+        write(inst, ":SOURce2:POWer:LEVel:IMMediate:AMPLitude DEF")?;  // Set power to default value
+        audit::power_dbm(inst.name(), Some(LASER), None, Some("DEF"));
+        Ok(())
+    }
+
+    pub fn set_output(inst: &InstrumentHandle, on: bool) -> io::Result<()> {
+        set_channel_output(inst, LASER, on)
+    }
+
+    pub fn set_wavelength_nm(inst: &InstrumentHandle, wavelength_nm: f64) -> io::Result<()> {
+        set_channel_wavelength_nm(inst, LASER, wavelength_nm)
+    }
+
+    pub fn set_channel_output(inst: &InstrumentHandle, channel: u8, on: bool) -> io::Result<()> {
+        write(inst, &format!(":SOURce{}:POWer:STATe {}", channel, on as u8))?;
+        audit::output(inst.name(), Some(channel), on);
+        Ok(())
+    }
+
+    pub fn set_channel_wavelength_nm(inst: &InstrumentHandle, channel: u8, wavelength_nm: f64) -> io::Result<()> {
+        write(inst, &format!(":SOURce{}:WAVelength:CW {:.3}NM", channel, wavelength_nm))
+    }
+
+    pub fn read_channel_output(inst: &InstrumentHandle, channel: u8) -> io::Result<bool> {
+        Ok(query(inst, &format!(":SOURce{}:POWer:STATe?", channel))? == "1")
+    }
+
+    pub fn read_channel_wavelength_nm(inst: &InstrumentHandle, channel: u8) -> io::Result<f64> {
+        let response = query(inst, &format!(":SOURce{}:WAVelength:CW?", channel))?;
+        response.parse::<f64>()
+            .map(|m| m * 1.0e9) // Convert from meters to nm
+            .map_err(|_| invalid_data(format!("Failed to parse N77 wavelength: {}", response)))
+    }
+
+    pub fn set_power_dbm(inst: &InstrumentHandle, power_dbm: f64) -> io::Result<()> {
+        write(inst, &format!(":SOURce2:POWer:LEVel:IMMediate:AMPLitude {:.3}DBM", power_dbm))?;
+        audit::power_dbm(inst.name(), Some(LASER), Some(power_dbm), None);
+        Ok(())
+    }
+
+    /// Sets up a single continuous sweep that pulses the trigger output every `step_nm`
+    pub fn configure_sweep(inst: &InstrumentHandle, start_nm: f64, stop_nm: f64, step_nm: f64, speed_nm_s: f64) -> io::Result<()> {
+        write(inst, ":SOURce2:WAVelength:SWEep:MODE CONTinuous")?;
+        write(inst, &format!(":SOURce2:WAVelength:SWEep:STARt {:.4}NM", start_nm))?;
+        write(inst, &format!(":SOURce2:WAVelength:SWEep:STOP {:.4}NM", stop_nm))?;
+        write(inst, &format!(":SOURce2:WAVelength:SWEep:STEP {:.4}NM", step_nm))?;
+        write(inst, &format!(":SOURce2:WAVelength:SWEep:SPEed {:.3}NM/S", speed_nm_s))?;
+        write(inst, ":SOURce2:WAVelength:SWEep:CYCLes 1")?;
+        write(inst, ":TRIGger2:OUTPut STFinished")?;
+
+        // The laser refuses to start a sweep it can't run, ask why up front
+        let check = query(inst, ":SOURce2:WAVelength:SWEep:CHECkparams?")?;
+        if !check.trim_start().starts_with('0') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("N77 rejected the sweep: {}", check)));
+        }
+        Ok(())
+    }
+
+    pub fn start_sweep(inst: &InstrumentHandle) -> io::Result<()> {
+        write(inst, ":SOURce2:WAVelength:SWEep:STATe 1")
+    }
+
+    pub fn stop_sweep(inst: &InstrumentHandle) -> io::Result<()> {
+        write(inst, ":SOURce2:WAVelength:SWEep:STATe 0")
+    }
+
+    pub fn sweep_running(inst: &InstrumentHandle) -> io::Result<bool> {
+        Ok(query(inst, ":SOURce2:WAVelength:SWEep:STATe?")? != "0")
+    }
+
+    pub fn read_error(inst: &InstrumentHandle) -> io::Result<String> {
+        query(inst, "SYST:ERR?")
+    }
+}
+
+/// MPM210-H power meter, the experiments read module 0 port 2
+pub mod power_meter {
+    use super::*;
+
+    /// Port used by the experiments
+    pub const PORT: u8 = 2;
+    pub const PORTS: RangeInclusive<u8> = 1..=4;
+
+    /// Constant wavelength mode, 50 ms averaging, dBm units
+    pub fn configure(inst: &InstrumentHandle) -> io::Result<()> {
+        write(inst, "WMOD CONST1")?;
+        write(inst, "AVG 50")?;
+        write(inst, "UNIT 0")
+    }
+
+    /// Updates the wavelength used for the detector calibration
+    pub fn set_wavelength_nm(inst: &InstrumentHandle, wavelength_nm: f64) -> io::Result<()> {
+        write(inst, &format!("WAV {:.3}", wavelength_nm))
+    }
+
+    pub fn read_power_dbm(inst: &InstrumentHandle) -> io::Result<f64> {
+        read_port_power_dbm(inst, PORT)
+    }
+
+    pub fn read_port_power_dbm(inst: &InstrumentHandle, port: u8) -> io::Result<f64> {
+        let response = query(inst, "READ? 0")?;
+
+        // Format: "power1,power2,power3,power4", one value per port
+        let power_values: Vec<&str> = response.split(',').collect();
+        let value = power_values.get(usize::from(port).wrapping_sub(1))
+            .ok_or_else(|| invalid_data(format!("No port {} reading in response: {}", port, response)))?;
+        value.trim().parse::<f64>()
+            .map_err(|_| invalid_data(format!("Failed to parse power value: {}", value)))
+    }
+
+    /// Swept mode: logs one reading per trigger from the laser while it sweeps from `start_nm` to `stop_nm`
+    pub fn configure_logging(inst: &InstrumentHandle, start_nm: f64, stop_nm: f64, step_nm: f64, speed_nm_s: f64) -> io::Result<()> {
+        write(inst, "WMOD SWEEP1")?;
+        write(inst, &format!("WSET {:.4},{:.4},{:.4}", start_nm, stop_nm, step_nm))?;
+        write(inst, &format!("SPE {:.3}", speed_nm_s))?;
+        write(inst, "TRIG 1")?;
+        write(inst, "UNIT 0")
+    }
+
+    /// Arms the logging, readings start with the first trigger
+    pub fn start_logging(inst: &InstrumentHandle) -> io::Result<()> {
+        write(inst, "MEAS")
+    }
+
+    pub fn stop_logging(inst: &InstrumentHandle) -> io::Result<()> {
+        write(inst, "STOP")
+    }
+
+    /// Returns (done, points logged so far)
+    pub fn logging_status(inst: &InstrumentHandle) -> io::Result<(bool, usize)> {
+        let response = query(inst, "STAT?")?;
+
+        // Format: "status,points", status 1 once the logging completed
+        let parsed = response.split_once(',')
+            .and_then(|(status, points)| Some((status.trim() == "1", points.trim().parse::<usize>().ok()?)));
+        parsed.ok_or_else(|| invalid_data(format!("Failed to parse logging status: {}", response)))
+    }
+
+    /// Reads the logged powers of a port in dBm, in trigger order
+    pub fn read_log_dbm(inst: &InstrumentHandle, port: u8) -> io::Result<Vec<f64>> {
+        let response = inst.query_timeout(&format!("LOGG? 0,{}", port), LOG_READ_TIMEOUT)?;
+        if response.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        response.split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|_| invalid_data(format!("Failed to parse logged power: {}", v))))
+            .collect()
+    }
+
+    pub fn read_error(inst: &InstrumentHandle) -> io::Result<String> {
+        query(inst, "ERR?")
+    }
+}
+
+/// HP-70952B optical spectrum analyzer
+pub mod osa {
+    use super::*;
+
+    /// Single sweep mode with the given center and span
+    pub fn configure(inst: &InstrumentHandle, center_nm: f64, span_nm: f64) -> io::Result<()> {
+        write(inst, "SNGLS;")?;
+        write(inst, &format!("CENTERWL {}NM;SPANWL {}NM;", center_nm, span_nm))
+    }
+
+    pub fn set_center_nm(inst: &InstrumentHandle, center_nm: f64) -> io::Result<()> {
+        write(inst, &format!("CENTERWL {}NM;", center_nm))
+    }
+
+    /// Number of points in a trace, 800 if the response can't be parsed
+    pub fn trace_points(inst: &InstrumentHandle) -> io::Result<usize> {
+        Ok(query(inst, "MDS?;")?.parse::<usize>().unwrap_or(800))
+    }
+
+    /// Takes a sweep and waits for the OSA to confirm it's done, not retried as that would sweep again
+    pub fn take_sweep(inst: &InstrumentHandle) -> io::Result<()> {
+        let done = inst.query_once("TS;DONE?;", OSA_SWEEP_TIMEOUT)?;
+        if done != "1" {
+            warn!(response = %done, "Sweep not confirmed complete");
+        }
+        Ok(())
+    }
+
+    /// Marks the highest signal and returns its (wavelength nm, power dBm)
+    pub fn read_peak(inst: &InstrumentHandle) -> io::Result<(f64, f64)> {
+        write(inst, "MKPK HI;")?;
+        let wavelength_nm = query(inst, "MKWL?;")?.parse::<f64>().unwrap_or(0.0) * 1.0e9; // Convert from meters to nm
+        let power_dbm = query(inst, "MKA?;")?.parse::<f64>().unwrap_or(-100.0);
+        Ok((wavelength_nm, power_dbm))
+    }
+
+    /// Reads the raw trace amplitudes in dBm
+    pub fn read_trace(inst: &InstrumentHandle) -> io::Result<Vec<f64>> {
+        let trace = query(inst, "TRA?;")?;
+        Ok(trace.split(',').map(|v| v.trim().parse::<f64>().unwrap_or(-100.0)).collect())
+    }
+
+    pub fn sweep_off(inst: &InstrumentHandle) -> io::Result<()> {
+        write(inst, "SWEEP OFF;")
+    }
+
+    pub fn read_error(inst: &InstrumentHandle) -> io::Result<String> {
+        query(inst, "XERR?;")
+    }
+}
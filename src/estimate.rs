@@ -0,0 +1,117 @@
+use serde::Serialize;
+use crate::config;
+use crate::sweep::{self, SweepPlan};
+
+// Time to take one reading, on top of any settling, measured on the lab's instruments
+const POWER_METER_READ_S: f64 = 0.1;     // 50 ms averaging and the transfer
+const OSA_ACQUISITION_S: f64 = 5.0;      // Sweep, peak search and trace transfer of the HP-70952B
+const CLD1015_READ_S: f64 = 0.05;
+const N77_SET_S: f64 = 0.05;
+// Reading back the power meter's log after a swept sweep
+const LOG_TRANSFER_S_PER_POINT: f64 = 0.0001;
+
+/// How long a run is expected to take, checked against the configured maximum before it starts
+#[derive(Debug, Clone, Serialize)]
+pub struct RunEstimate {
+    pub points: usize,
+    pub duration_s: f64,
+    pub max_duration_s: u64,
+}
+
+/// Time to read one value from an instrument
+pub fn acquisition_s(instrument: &str) -> f64 {
+    match instrument {
+        "power_meter" => POWER_METER_READ_S,
+        "osa" => OSA_ACQUISITION_S,
+        "cld1015" => CLD1015_READ_S,
+        "n77" => N77_SET_S,
+        _ => 0.0,
+    }
+}
+
+/// Readout of a power meter log of `points` readings
+pub fn log_transfer_s(points: usize) -> f64 {
+    points as f64 * LOG_TRANSFER_S_PER_POINT
+}
+
+impl RunEstimate {
+    /// `setup_s` once, then `per_point_s` for each of `points`
+    pub fn new(points: usize, setup_s: f64, per_point_s: f64) -> Self {
+        RunEstimate {
+            points,
+            duration_s: setup_s + points as f64 * per_point_s,
+            max_duration_s: config::get().max_run_duration_min * 60,
+        }
+    }
+
+    /// A declared sweep: every point waits for the dwell or the slowest settling axis, then takes each measurement.
+    /// Points are counted per axis, a request can't make the server generate them all just to be estimated.
+    pub fn sweep_plan(plan: &SweepPlan) -> Result<Self, String> {
+        let points = plan.point_count();
+        sweep::check_count(points)?;
+        plan.validate()?;
+        let settle_ms = plan.axes.iter().map(|axis| axis.settle_ms).max().unwrap_or(0);
+        let measure_s: f64 = plan.measurements.iter().map(|m| acquisition_s(m.instrument())).sum();
+        Ok(RunEstimate::new(points, 0.0, plan.dwell_ms.max(settle_ms) as f64 / 1000.0 + measure_s))
+    }
+
+    /// Refuses runs expected to take longer than the configured maximum
+    pub fn check(&self) -> Result<(), String> {
+        if self.duration_s > self.max_duration_s as f64 {
+            return Err(format!(
+                "Run would take about {} for {} points, the maximum is {}. Use fewer points.",
+                format_duration(self.duration_s), self.points, format_duration(self.max_duration_s as f64),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Durations as the operator reads them, e.g. "1 h 05 min" or "42 s"
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.ceil() as u64;
+    match seconds {
+        0..60 => format!("{} s", seconds),
+        60..3600 => format!("{} min {:02} s", seconds / 60, seconds % 60),
+        _ => format!("{} h {:02} min", seconds / 3600, (seconds % 3600) / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sweep::{Axis, AxisPoints, Measurement, Parameter};
+
+    fn plan(points: usize) -> SweepPlan {
+        let log = |parameter, start, stop| Axis { parameter, points: AxisPoints::Log { start, stop, points }, settle_ms: 0 };
+        SweepPlan {
+            axes: vec![log(Parameter::Cld1015CurrentMa, 1.0, 50.0), log(Parameter::N77WavelengthNm, 1530.0, 1570.0)],
+            measurements: vec![Measurement::PowerMeterDbm],
+            dwell_ms: 100,
+            osa_center_nm: None,
+            osa_span_nm: None,
+            optical_path: None,
+        }
+    }
+
+    #[test]
+    fn sweep_plan_is_estimated_from_its_axes() {
+        crate::config::init_for_tests();
+        let estimate = RunEstimate::sweep_plan(&plan(10)).unwrap();
+        assert_eq!(estimate.points, 100);
+        assert!((estimate.duration_s - 100.0 * (0.1 + POWER_METER_READ_S)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn huge_sweep_plan_is_refused_without_generating_it() {
+        let err = RunEstimate::sweep_plan(&plan(1_000_000_000)).unwrap_err();
+        assert!(err.contains("Too many points"), "{}", err);
+    }
+
+    #[test]
+    fn durations_read_naturally() {
+        assert_eq!(format_duration(42.0), "42 s");
+        assert_eq!(format_duration(305.0), "5 min 05 s");
+        assert_eq!(format_duration(3900.0), "1 h 05 min");
+    }
+}
@@ -0,0 +1,956 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tracing::{info, info_span, warn};
+use crate::audit;
+use crate::calibration::{self, PathCalibration};
+use crate::cld1015_osa;
+use crate::config;
+use crate::devices::Devices;
+use crate::drivers;
+use crate::hysteresis::{self, OrderedSweep, SweepOrder};
+use crate::estimate::{self, RunEstimate};
+use crate::n77_wavelength_check;
+use crate::n77_wavelength_sweep;
+use crate::n77_osa;
+use crate::n77_path_calibration;
+use crate::n77_swept_sweep;
+use crate::queue;
+use crate::recipe::{self, Recipe};
+use crate::reference::{self, Reference};
+use crate::replay::{self, ReplayReport};
+use crate::safety;
+use crate::settle::{self, SettleParams};
+use crate::stability::{self, Schedule, Source};
+use crate::stats::{RepeatParams, Repeats};
+use crate::sweep::{self, SweepInstruments, SweepPlan};
+use crate::run_db;
+use crate::transactions::{self, RunInfo, TransactionLog};
+
+// Pre-set timing values
+const DEFAULT_DWELL_TIME_MS: u64 = 100;        // For current sweep
+const DEFAULT_STABILIZATION_TIME_MS: u64 = 200; // For wavelength check
+const DEFAULT_WAVELENGTH_SWEEP_STABILIZATION_MS: u64 = 10000; // For wavelength sweeps
+const DEFAULT_SWEEP_SPEED_NM_S: f64 = 10.0;     // For swept wavelength sweeps
+const DEFAULT_STABILITY_WARMUP_MS: u64 = 10000; // For stability monitoring
+
+#[derive(Serialize)]
+pub struct BuiltinExperiment {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub instruments: &'static [&'static str],
+}
+
+pub const BUILTIN_EXPERIMENTS: &[BuiltinExperiment] = &[
+    BuiltinExperiment {
+        name: "current_sweep",
+        description: "Current sweep with OSA peak and trace capture",
+        instruments: &["cld1015", "osa"],
+    },
+    BuiltinExperiment {
+        name: "wavelength_check",
+        description: "Single wavelength power measurement",
+        instruments: &["n77", "power_meter"],
+    },
+    BuiltinExperiment {
+        name: "wavelength_sweep",
+        description: "Power vs wavelength scan",
+        instruments: &["n77", "power_meter"],
+    },
+    BuiltinExperiment {
+        name: "wavelength_sweep_osa",
+        description: "Wavelength sweep with OSA peak and trace capture",
+        instruments: &["n77", "osa"],
+    },
+    BuiltinExperiment {
+        name: "path_calibration",
+        description: "Loss table of the optical path to the power meter",
+        instruments: &["n77", "power_meter"],
+    },
+    BuiltinExperiment {
+        name: "stability",
+        description: "Power or OSA peak at a fixed setpoint over time, with Allan deviation and drift",
+        instruments: &[],
+    },
+    BuiltinExperiment {
+        name: "sweep",
+        description: "Generic sweep declared in the request body",
+        instruments: &[],
+    },
+];
+
+#[derive(Deserialize)]
+struct CurrentSweepParams {
+    start_ma: f64,
+    stop_ma: f64,
+    step_ma: f64,
+    #[serde(default)]
+    order: SweepOrder,
+    /// Shuffles a random order the same way again
+    #[serde(default)]
+    seed: Option<u64>,
+    /// Wait after each current step, overrides DEFAULT_DWELL_TIME_MS
+    #[serde(default)]
+    dwell_time_ms: Option<u64>,
+    #[serde(flatten)]
+    settle: SettleParams,
+    #[serde(flatten)]
+    repeat: RepeatParams,
+}
+
+#[derive(Deserialize)]
+struct WavelengthCheckParams {
+    wavelength: f64,
+    /// Calibrated optical path to the power meter, see calibration.rs
+    #[serde(default)]
+    optical_path: Option<String>,
+    /// Wait after switching the laser on and after setting the wavelength
+    #[serde(default)]
+    stabilization_time_ms: Option<u64>,
+    #[serde(flatten)]
+    settle: SettleParams,
+    #[serde(flatten)]
+    repeat: RepeatParams,
+}
+
+/// How the N77 moves through the wavelengths of a power meter sweep
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SweepMode {
+    /// Sets each wavelength and waits for it to settle, slow but works with any meter setting
+    #[default]
+    Stepped,
+    /// Continuous laser sweep with the power meter logging on its trigger, for dense spectra
+    Swept,
+}
+
+#[derive(Deserialize)]
+struct WavelengthSweepParams {
+    start_nm: f64,
+    stop_nm: f64,
+    step_nm: f64,
+    #[serde(default)]
+    mode: SweepMode,
+    #[serde(default)]
+    order: SweepOrder,
+    /// Shuffles a random order the same way again
+    #[serde(default)]
+    seed: Option<u64>,
+    /// Sweep speed in swept mode
+    #[serde(default)]
+    speed_nm_s: Option<f64>,
+    /// Wait after switching the laser on and after each wavelength step
+    #[serde(default)]
+    stabilization_time_ms: Option<u64>,
+    #[serde(flatten)]
+    settle: SettleParams,
+    #[serde(flatten)]
+    repeat: RepeatParams,
+    #[serde(default)]
+    optical_path: Option<String>,
+    /// Stores the sweep as a reference under this name, measured through the bypass path
+    #[serde(default)]
+    capture_reference: Option<String>,
+    /// Minutes a captured reference stays usable
+    #[serde(default)]
+    reference_lifetime_min: Option<u64>,
+    /// Normalizes the sweep against this stored reference
+    #[serde(default)]
+    reference: Option<String>,
+}
+
+/// What a stability run holds constant and reads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StabilitySource {
+    /// N77 at `wavelength` into the power meter
+    #[default]
+    PowerMeter,
+    /// CLD1015 at `current_ma`, peak from the OSA
+    Osa,
+}
+
+#[derive(Deserialize)]
+struct StabilityParams {
+    #[serde(default)]
+    source: StabilitySource,
+    #[serde(default)]
+    wavelength: Option<f64>,
+    #[serde(default)]
+    current_ma: Option<f64>,
+    /// Time between readings
+    interval_s: f64,
+    duration_min: f64,
+    /// Wait after switching the laser on, before the first reading
+    #[serde(default)]
+    stabilization_time_ms: Option<u64>,
+}
+
+impl StabilityParams {
+    fn instruments(&self) -> &'static [&'static str] {
+        match self.source {
+            StabilitySource::PowerMeter => &["n77", "power_meter"],
+            StabilitySource::Osa => &["cld1015", "osa"],
+        }
+    }
+
+    // Checks the request before any instrument is touched
+    fn schedule(&self) -> std::result::Result<Schedule, String> {
+        match self.source {
+            StabilitySource::PowerMeter => {
+                let wavelength = self.wavelength.ok_or("The power meter source needs a wavelength")?;
+                safety::check("Wavelength", "nm", &safety::N77_WAVELENGTH_NM, wavelength)?;
+                if self.current_ma.is_some() {
+                    return Err("current_ma applies to the osa source".to_string());
+                }
+            },
+            StabilitySource::Osa => {
+                let current = self.current_ma.ok_or("The OSA source needs a current_ma")?;
+                safety::check("Current", "mA", &safety::CLD1015_CURRENT_MA, current)?;
+                if self.wavelength.is_some() {
+                    return Err("wavelength applies to the power_meter source".to_string());
+                }
+            },
+        }
+        if !stability::INTERVAL_S.contains(&self.interval_s) {
+            return Err(format!(
+                "interval_s {} is outside {}..={} s",
+                self.interval_s, stability::INTERVAL_S.start(), stability::INTERVAL_S.end(),
+            ));
+        }
+        let duration_s = self.duration_min * 60.0;
+        if !(duration_s.is_finite() && duration_s >= self.interval_s) {
+            return Err("duration_min must cover at least one interval".to_string());
+        }
+        let warmup_ms = self.stabilization_time_ms.unwrap_or(DEFAULT_STABILITY_WARMUP_MS);
+        if !settle::WAIT_MS.contains(&warmup_ms) {
+            return Err(format!(
+                "stabilization_time_ms {} ms is outside {}..={} ms",
+                warmup_ms, settle::WAIT_MS.start(), settle::WAIT_MS.end(),
+            ));
+        }
+        Ok(Schedule { interval_s: self.interval_s, duration_s, warmup_ms })
+    }
+}
+
+#[derive(Deserialize)]
+struct PathCalibrationParams {
+    /// Name the table is saved under
+    optical_path: String,
+    start_nm: f64,
+    stop_nm: f64,
+    step_nm: f64,
+    /// Known laser power the meter's readings are compared with
+    #[serde(default)]
+    laser_power_dbm: f64,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    stabilization_time_ms: Option<u64>,
+    #[serde(flatten)]
+    settle: SettleParams,
+    #[serde(flatten)]
+    repeat: RepeatParams,
+}
+
+// Run experiment functions
+fn run_current_sweep(
+    devices: &Devices,
+    run_id: &str,
+    params: CurrentSweepParams,
+    output_dir: &Path,
+) -> std::result::Result<String, String> {
+    // Validate parameters
+    if !safety::CLD1015_CURRENT_MA.contains(&params.start_ma) ||
+       !safety::CLD1015_CURRENT_MA.contains(&params.stop_ma) ||
+       params.stop_ma <= params.start_ma || params.step_ma <= 0.0 {
+        return Err("Invalid current sweep parameters".to_string());
+    }
+    let settle = params.settle.resolve("dwell_time_ms", params.dwell_time_ms, DEFAULT_DWELL_TIME_MS)?;
+    let repeats = params.repeat.resolve()?;
+    let currents = ordered_points((params.start_ma, params.stop_ma, params.step_ma), params.order, params.seed)?;
+    
+    // Run experiment with the requested or pre-set dwell time
+    let locked = devices.lock_for_run(run_id, &["cld1015", "osa"])?;
+    let SweepInstruments { cld1015: Some(cld1015), osa: Some(osa), .. } = locked.instruments() else {
+        return Err("CLD1015 or OSA not connected".to_string());
+    };
+    
+    cld1015_osa::run_current_sweep(
+        cld1015,
+        osa,
+        &currents,
+        &settle,
+        &repeats,
+        output_dir,
+    ).map_err(|e| format!("Experiment failed: {}", e))?;
+    let files = with_hysteresis("current_sweep_results.csv and current_sweep_trace_data/", params.order, "current_sweep_hysteresis.csv");
+    Ok(with_samples(&files, &repeats, "current_sweep_samples.csv"))
+}
+
+fn run_wavelength_check(
+    devices: &Devices,
+    run_id: &str,
+    params: WavelengthCheckParams,
+    output_dir: &Path,
+) -> std::result::Result<String, String> {
+    // Validate parameters
+    if !safety::N77_WAVELENGTH_NM.contains(&params.wavelength) {
+        return Err("Invalid wavelength check parameters".to_string());
+    }
+    
+    let calibration = load_calibration(params.optical_path.as_deref(), params.wavelength, params.wavelength)?;
+    let settle = params.settle.resolve("stabilization_time_ms", params.stabilization_time_ms, DEFAULT_STABILIZATION_TIME_MS)?;
+    let repeats = params.repeat.resolve()?;
+    
+    // Run experiment with the requested or pre-set stabilization time
+    let locked = devices.lock_for_run(run_id, &["n77", "power_meter"])?;
+    let SweepInstruments { n77: Some(n77), power_meter: Some(power_meter), .. } = locked.instruments() else {
+        return Err("N77 laser or power meter not connected".to_string());
+    };
+    
+    n77_wavelength_check::run_wavelength_check(
+        n77,
+        power_meter,
+        params.wavelength,
+        &settle,
+        calibration.as_ref(),
+        &repeats,
+        output_dir,
+    ).map_err(|e| format!("Experiment failed: {}", e))?;
+    Ok(with_samples("wavelength_check_result.csv", &repeats, "wavelength_check_samples.csv"))
+}
+
+fn run_wavelength_sweep(
+    devices: &Devices,
+    run_id: &str,
+    params: WavelengthSweepParams,
+    output_dir: &Path,
+) -> std::result::Result<String, String> {
+    // Validate parameters
+    if !safety::N77_WAVELENGTH_NM.contains(&params.start_nm) ||
+       !safety::N77_WAVELENGTH_NM.contains(&params.stop_nm) ||
+       params.stop_nm <= params.start_nm || params.step_nm <= 0.0 {
+        return Err("Invalid wavelength sweep parameters".to_string());
+    }
+    
+    // The run's duration was checked against the limit before it started, swept mode is also limited by the log size
+    let wavelengths = match params.mode {
+        SweepMode::Stepped => {
+            if params.speed_nm_s.is_some() {
+                return Err("speed_nm_s only applies to swept mode".to_string());
+            }
+            ordered_points((params.start_nm, params.stop_nm, params.step_nm), params.order, params.seed)?
+        },
+        SweepMode::Swept => {
+            validate_swept(&params)?;
+            SweepOrder::Up.apply(&[], None)
+        },
+    };
+    let settle = params.settle.resolve(
+        "stabilization_time_ms",
+        params.stabilization_time_ms,
+        DEFAULT_WAVELENGTH_SWEEP_STABILIZATION_MS,
+    )?;
+    let repeats = params.repeat.resolve()?;
+    let calibration = load_calibration(params.optical_path.as_deref(), params.start_nm, params.stop_nm)?;
+    
+    // A sweep either captures a reference or is normalized against one
+    if params.capture_reference.is_some() && params.reference.is_some() {
+        return Err("Use either capture_reference or reference, not both".to_string());
+    }
+    if params.order != SweepOrder::Up && (params.capture_reference.is_some() || params.reference.is_some()) {
+        return Err("References are captured and applied with upward sweeps".to_string());
+    }
+    if let Some(name) = &params.capture_reference
+        && !calibration::is_valid_name(name) {
+        return Err(format!("Invalid reference name: {}", name));
+    }
+    if params.reference_lifetime_min.is_some() && params.capture_reference.is_none() {
+        return Err("reference_lifetime_min needs a capture_reference".to_string());
+    }
+    if let Some(lifetime) = params.reference_lifetime_min
+        && !reference::LIFETIME_MIN.contains(&lifetime) {
+        return Err(format!(
+            "reference_lifetime_min {} min is outside {}..={} min",
+            lifetime, reference::LIFETIME_MIN.start(), reference::LIFETIME_MIN.end(),
+        ));
+    }
+    let normalize_to = match &params.reference {
+        Some(name) => {
+            let stored = Reference::load(name)?;
+            stored.check_usable(params.start_nm, params.stop_nm, params.optical_path.as_deref())?;
+            Some(stored)
+        },
+        None => None,
+    };
+    
+    // Run experiment with the requested or pre-set stabilization time
+    let locked = devices.lock_for_run(run_id, &["n77", "power_meter"])?;
+    let SweepInstruments { n77: Some(n77), power_meter: Some(power_meter), .. } = locked.instruments() else {
+        return Err("N77 laser or power meter not connected".to_string());
+    };
+    
+    let points = match params.mode {
+        SweepMode::Stepped => n77_wavelength_sweep::run_wavelength_sweep(
+            n77,
+            power_meter,
+            &wavelengths,
+            &settle,
+            calibration.as_ref(),
+            &repeats,
+            output_dir,
+        ),
+        SweepMode::Swept => n77_swept_sweep::run_swept_wavelength_sweep(
+            n77,
+            power_meter,
+            (params.start_nm, params.stop_nm, params.step_nm),
+            params.speed_nm_s.unwrap_or(DEFAULT_SWEEP_SPEED_NM_S),
+            settle.wait_ms,
+            calibration.as_ref(),
+            output_dir,
+        ),
+    }.map_err(|e| format!("Experiment failed: {}", e))?;
+    
+    if let Some(name) = &params.capture_reference {
+        let lifetime = params.reference_lifetime_min.unwrap_or(reference::DEFAULT_LIFETIME_MIN);
+        let captured = Reference::new(name, run_id, params.optical_path.clone(), &points, lifetime);
+        // A replay's points come from simulated instruments and would overwrite the lab's reference
+        if audit::is_simulated() {
+            info!(reference = %name, "Replay, reference not saved");
+        } else {
+            let path = captured.save()?;
+            info!(file = %path.display(), lifetime_min = lifetime, "Reference saved");
+        }
+    }
+    let mut files = with_hysteresis("wavelength_sweep_results.csv", params.order, "wavelength_sweep_hysteresis.csv");
+    if let Some(stored) = normalize_to {
+        stored.write_normalized(&output_dir.join("wavelength_sweep_normalized.csv"), &points)
+            .map_err(|e| format!("Failed to write normalized results: {}", e))?;
+        files.push_str(" and wavelength_sweep_normalized.csv");
+    }
+    Ok(with_samples(&files, &repeats, "wavelength_sweep_samples.csv"))
+}
+
+// The laser and meter only accept some continuous sweeps, check before touching them
+fn validate_swept(params: &WavelengthSweepParams) -> std::result::Result<(), String> {
+    if params.settle.is_adaptive() {
+        return Err("Adaptive settling applies to stepped sweeps, a swept sweep doesn't stop at each wavelength".to_string());
+    }
+    if params.repeat.is_repeated() {
+        return Err("Repeats apply to stepped sweeps, a swept sweep logs one reading per wavelength".to_string());
+    }
+    if params.order != SweepOrder::Up || params.seed.is_some() {
+        return Err("Swept mode sweeps upward, use stepped mode for other orders".to_string());
+    }
+    let speed = params.speed_nm_s.unwrap_or(DEFAULT_SWEEP_SPEED_NM_S);
+    if !n77_swept_sweep::SPEED_NM_S.contains(&speed) {
+        return Err(format!(
+            "Sweep speed {} nm/s is outside {}..={} nm/s",
+            speed, n77_swept_sweep::SPEED_NM_S.start(), n77_swept_sweep::SPEED_NM_S.end(),
+        ));
+    }
+    if params.step_nm < n77_swept_sweep::MIN_STEP_NM {
+        return Err(format!("Swept mode needs a step of at least {} nm", n77_swept_sweep::MIN_STEP_NM));
+    }
+    let points = n77_swept_sweep::expected_points(params.start_nm, params.stop_nm, params.step_nm);
+    if points > n77_swept_sweep::MAX_POINTS {
+        return Err(format!("Too many data points: {}. The power meter logs at most {}.", points, n77_swept_sweep::MAX_POINTS));
+    }
+    Ok(())
+}
+
+fn run_wavelength_sweep_osa(
+    devices: &Devices,
+    run_id: &str,
+    params: WavelengthSweepParams,
+    output_dir: &Path,
+) -> std::result::Result<String, String> {
+    // Validate parameters
+    if !safety::N77_WAVELENGTH_NM.contains(&params.start_nm) ||
+       !safety::N77_WAVELENGTH_NM.contains(&params.stop_nm) ||
+       params.stop_nm <= params.start_nm || params.step_nm <= 0.0 {
+        return Err("Invalid wavelength sweep parameters".to_string());
+    }
+    if params.optical_path.is_some() || params.capture_reference.is_some() || params.reference_lifetime_min.is_some()
+       || params.reference.is_some() {
+        return Err("Path calibrations and references apply to power meter sweeps, this sweep measures with the OSA".to_string());
+    }
+    if params.mode != SweepMode::Stepped || params.speed_nm_s.is_some() {
+        return Err("Swept mode needs the power meter's logging, the OSA sweep steps through the wavelengths".to_string());
+    }
+    let settle = params.settle.resolve(
+        "stabilization_time_ms",
+        params.stabilization_time_ms,
+        DEFAULT_WAVELENGTH_SWEEP_STABILIZATION_MS,
+    )?;
+    let repeats = params.repeat.resolve()?;
+    let wavelengths = ordered_points((params.start_nm, params.stop_nm, params.step_nm), params.order, params.seed)?;
+    
+    // Run experiment with pre-set stabilization time
+    let locked = devices.lock_for_run(run_id, &["n77", "osa"])?;
+    let SweepInstruments { n77: Some(n77), osa: Some(osa), .. } = locked.instruments() else {
+        return Err("N77 laser or OSA not connected".to_string());
+    };
+    
+    n77_osa::run_wavelength_sweep_osa(
+        n77,
+        osa,
+        &wavelengths,
+        &settle,
+        &repeats,
+        output_dir,
+    ).map_err(|e| format!("Experiment failed: {}", e))?;
+    let files = with_hysteresis(
+        "wavelength_sweep_trace_results.csv and wavelength_sweep_trace_data/",
+        params.order,
+        "wavelength_sweep_trace_hysteresis.csv",
+    );
+    Ok(with_samples(&files, &repeats, "wavelength_sweep_trace_samples.csv"))
+}
+
+// Start to stop points in the requested order, refusing a seed that wouldn't be used
+fn ordered_points(
+    (start, stop, step): (f64, f64, f64),
+    order: SweepOrder,
+    seed: Option<u64>,
+) -> std::result::Result<OrderedSweep, String> {
+    if seed.is_some() && order != SweepOrder::Random {
+        return Err("seed only applies to the random order".to_string());
+    }
+    Ok(order.apply(&sweep::linear_points(start, stop, step)?, seed))
+}
+
+// Result files of an up-down sweep include the comparison of its branches
+fn with_hysteresis(files: &str, order: SweepOrder, hysteresis_file: &str) -> String {
+    if order == SweepOrder::UpDown {
+        format!("{} and {}", files, hysteresis_file)
+    } else {
+        files.to_string()
+    }
+}
+
+// Result files of a run, with the raw readings when the request kept them
+fn with_samples(files: &str, repeats: &Repeats, samples_file: &str) -> String {
+    if repeats.keep_samples {
+        format!("{} and {}", files, samples_file)
+    } else {
+        files.to_string()
+    }
+}
+
+
+// The table of an optical path, checked to cover the wavelengths before any instrument is touched
+fn load_calibration(
+    optical_path: Option<&str>,
+    start_nm: f64,
+    stop_nm: f64,
+) -> std::result::Result<Option<PathCalibration>, String> {
+    let Some(name) = optical_path else {
+        return Ok(None);
+    };
+    let calibration = PathCalibration::load(name)?;
+    calibration.check_covers(start_nm, stop_nm)?;
+    Ok(Some(calibration))
+}
+
+fn run_path_calibration(
+    devices: &Devices,
+    run_id: &str,
+    params: PathCalibrationParams,
+    output_dir: &Path,
+) -> std::result::Result<String, String> {
+    // Validate parameters
+    if !calibration::is_valid_name(&params.optical_path) {
+        return Err(format!("Invalid optical path name: {}", params.optical_path));
+    }
+    if !safety::N77_WAVELENGTH_NM.contains(&params.start_nm) ||
+       !safety::N77_WAVELENGTH_NM.contains(&params.stop_nm) ||
+       params.stop_nm <= params.start_nm || params.step_nm <= 0.0 {
+        return Err("Invalid path calibration parameters".to_string());
+    }
+    let wavelengths = sweep::linear_points(params.start_nm, params.stop_nm, params.step_nm)?;
+    let settle = params.settle.resolve(
+        "stabilization_time_ms",
+        params.stabilization_time_ms,
+        DEFAULT_WAVELENGTH_SWEEP_STABILIZATION_MS,
+    )?;
+    let repeats = params.repeat.resolve()?;
+    
+    let locked = devices.lock_for_run(run_id, &["n77", "power_meter"])?;
+    let SweepInstruments { n77: Some(n77), power_meter: Some(power_meter), .. } = locked.instruments() else {
+        return Err("N77 laser or power meter not connected".to_string());
+    };
+    
+    let points = n77_path_calibration::run_path_calibration(
+        n77,
+        power_meter,
+        &wavelengths,
+        params.laser_power_dbm,
+        (&settle, &repeats),
+        output_dir,
+    ).map_err(|e| format!("Experiment failed: {}", e))?;
+    
+    // Later runs pick the table up by name
+    let calibration = PathCalibration {
+        description: params.description,
+        measured_at: Some(queue::now_secs()),
+        run_id: Some(run_id.to_string()),
+        points,
+    };
+    // A replay's table comes from simulated instruments and would overwrite the lab's calibration
+    if audit::is_simulated() {
+        info!(optical_path = %params.optical_path, "Replay, calibration not saved");
+    } else {
+        let path = calibration.save(&params.optical_path)?;
+        info!(file = %path.display(), "Calibration saved");
+    }
+    Ok(with_samples("path_calibration_results.csv", &repeats, "path_calibration_samples.csv"))
+}
+
+fn run_stability(
+    devices: &Devices,
+    run_id: &str,
+    params: StabilityParams,
+    output_dir: &Path,
+) -> std::result::Result<String, String> {
+    let schedule = params.schedule()?;
+
+    let locked = devices.lock_for_run(run_id, params.instruments())?;
+    let instruments = locked.instruments();
+    let source = match params.source {
+        StabilitySource::PowerMeter => {
+            let SweepInstruments { n77: Some(laser), power_meter: Some(meter), .. } = instruments else {
+                return Err("N77 laser or power meter not connected".to_string());
+            };
+            Source::PowerMeter { laser, meter, wavelength_nm: params.wavelength.unwrap_or_default() }
+        },
+        StabilitySource::Osa => {
+            let SweepInstruments { cld1015: Some(cld1015), osa: Some(osa), .. } = instruments else {
+                return Err("CLD1015 or OSA not connected".to_string());
+            };
+            Source::Osa { cld1015, osa, current_ma: params.current_ma.unwrap_or_default() }
+        },
+    };
+
+    // The web interface follows the readings while the run goes on
+    let columns = [&["Elapsed (s)"], source.quantities()].concat();
+    let live = devices.live.open(run_id, "stability", &columns);
+    stability::run_stability(source, &schedule, &live, output_dir)
+        .map_err(|e| format!("Experiment failed: {}", e))?;
+    Ok("stability_results.csv and stability_allan.csv and stability_summary.csv".to_string())
+}
+
+fn run_sweep(
+    devices: &Devices,
+    run_id: &str,
+    plan: SweepPlan,
+    output_dir: &Path,
+) -> std::result::Result<usize, String> {
+    plan.validate()?;
+
+    // Lock every instrument the plan uses, this also checks they are connected
+    let locked = devices.lock_for_run(run_id, &plan.required_instruments())?;
+
+    sweep::run_sweep(&locked.instruments(), &plan, &output_dir.join("sweep_results.csv"))
+        .map_err(|e| format!("Experiment failed: {}", e))
+}
+
+fn run_recipe(
+    devices: &Devices,
+    run_id: &str,
+    recipe: Recipe,
+    output_dir: &Path,
+) -> std::result::Result<String, String> {
+    // Lock every declared instrument, this also checks they are connected
+    let names: Vec<&str> = recipe.instruments.iter().map(|s| s.as_str()).collect();
+    let locked = devices.lock_for_run(run_id, &names)?;
+
+    info!(recipe = %recipe.name, "Running recipe");
+    for setup in &recipe.setup {
+        let device = locked.get(&setup.instrument)
+            .ok_or_else(|| format!("{} not connected", setup.instrument))?;
+        drivers::write(device, &setup.command)
+            .map_err(|e| format!("Setup command '{}' failed: {}", setup.command, e))?;
+    }
+
+    let output = recipe.output_file();
+    sweep::run_sweep(&locked.instruments(), &recipe.plan, &output_dir.join(&output))
+        .map_err(|e| format!("Experiment failed: {}", e))?;
+    Ok(output)
+}
+
+/// Expected duration of an experiment from its parameters, without touching any instrument
+pub fn estimate_run(
+    experiment: &str,
+    params_json: &serde_json::Value,
+) -> std::result::Result<RunEstimate, String> {
+    fn parse<T: serde::de::DeserializeOwned>(params_json: &serde_json::Value) -> std::result::Result<T, String> {
+        serde_json::from_value(params_json.clone()).map_err(|err| format!("Invalid parameters: {}", err))
+    }
+    let power_meter_s = estimate::acquisition_s("power_meter");
+    let osa_s = estimate::acquisition_s("osa");
+    // Settling in wavelength sweeps, its first wait also follows switching the laser on
+    let sweep_settle = |wait_ms: Option<u64>, settle: &SettleParams| {
+        settle.resolve("stabilization_time_ms", wait_ms, DEFAULT_WAVELENGTH_SWEEP_STABILIZATION_MS)
+    };
+
+    Ok(match experiment {
+        "current_sweep" => {
+            let params: CurrentSweepParams = parse(params_json)?;
+            let settle = params.settle.resolve("dwell_time_ms", params.dwell_time_ms, DEFAULT_DWELL_TIME_MS)?;
+            let reads = params.repeat.resolve()?.count as f64;
+            let points = params.order.visits(sweep::linear_count(params.start_ma, params.stop_ma, params.step_ma));
+            RunEstimate::new(points, 0.2, settle.min_s(osa_s) + reads * osa_s)
+        },
+        "wavelength_check" => {
+            let params: WavelengthCheckParams = parse(params_json)?;
+            let settle = params.settle.resolve("stabilization_time_ms", params.stabilization_time_ms, DEFAULT_STABILIZATION_TIME_MS)?;
+            let reads = params.repeat.resolve()?.count as f64;
+            RunEstimate::new(1, settle.wait_ms as f64 / 1000.0, settle.min_s(power_meter_s) + reads * power_meter_s)
+        },
+        "wavelength_sweep" => {
+            let params: WavelengthSweepParams = parse(params_json)?;
+            let settle = sweep_settle(params.stabilization_time_ms, &params.settle)?;
+            let reads = params.repeat.resolve()?.count as f64;
+            let setup_s = settle.wait_ms as f64 / 1000.0;
+            match params.mode {
+                SweepMode::Stepped => {
+                    let points = params.order.visits(sweep::linear_count(params.start_nm, params.stop_nm, params.step_nm));
+                    RunEstimate::new(points, setup_s, settle.min_s(power_meter_s) + reads * power_meter_s)
+                },
+                SweepMode::Swept => {
+                    let points = n77_swept_sweep::expected_points(params.start_nm, params.stop_nm, params.step_nm);
+                    let speed = params.speed_nm_s.unwrap_or(DEFAULT_SWEEP_SPEED_NM_S);
+                    let sweep_s = (params.stop_nm - params.start_nm).abs() / speed;
+                    RunEstimate::new(points, setup_s + sweep_s + estimate::log_transfer_s(points), 0.0)
+                },
+            }
+        },
+        "wavelength_sweep_osa" => {
+            let params: WavelengthSweepParams = parse(params_json)?;
+            let settle = sweep_settle(params.stabilization_time_ms, &params.settle)?;
+            let reads = params.repeat.resolve()?.count as f64;
+            let points = params.order.visits(sweep::linear_count(params.start_nm, params.stop_nm, params.step_nm));
+            RunEstimate::new(points, settle.wait_ms as f64 / 1000.0 + osa_s, settle.min_s(osa_s) + reads * osa_s)
+        },
+        "path_calibration" => {
+            let params: PathCalibrationParams = parse(params_json)?;
+            let settle = sweep_settle(params.stabilization_time_ms, &params.settle)?;
+            let reads = params.repeat.resolve()?.count as f64;
+            let points = sweep::linear_count(params.start_nm, params.stop_nm, params.step_nm);
+            RunEstimate::new(points, settle.wait_ms as f64 / 1000.0, settle.min_s(power_meter_s) + reads * power_meter_s)
+        },
+        "stability" => {
+            let params: StabilityParams = parse(params_json)?;
+            let schedule = params.schedule()?;
+            let read_s = match params.source {
+                StabilitySource::PowerMeter => power_meter_s,
+                StabilitySource::Osa => osa_s,
+            };
+            let points = stability::expected_points(schedule.interval_s, schedule.duration_s);
+            // Readings are on a fixed schedule, only the last one adds its own acquisition
+            RunEstimate::new(points, schedule.warmup_ms as f64 / 1000.0 + read_s, schedule.interval_s.max(read_s))
+        },
+        "sweep" => RunEstimate::sweep_plan(&parse::<SweepPlan>(params_json)?)?,
+        _ => RunEstimate::sweep_plan(&recipe::find(experiment)?.plan)?,
+    })
+}
+
+// Instruments an experiment needs, used to reserve them before it runs
+pub fn experiment_instruments(
+    experiment: &str,
+    params_json: &serde_json::Value,
+) -> std::result::Result<Vec<String>, String> {
+    if let Some(builtin) = BUILTIN_EXPERIMENTS.iter().find(|e| e.name == experiment && !e.instruments.is_empty()) {
+        return Ok(builtin.instruments.iter().map(|s| s.to_string()).collect());
+    }
+    if experiment == "stability" {
+        let params = serde_json::from_value::<StabilityParams>(params_json.clone())
+            .map_err(|err| format!("Invalid parameters: {}", err))?;
+        return Ok(params.instruments().iter().map(|s| s.to_string()).collect());
+    }
+    if experiment == "sweep" {
+        let plan = serde_json::from_value::<SweepPlan>(params_json.clone())
+            .map_err(|err| format!("Invalid parameters: {}", err))?;
+        return Ok(plan.required_instruments().iter().map(|s| s.to_string()).collect());
+    }
+    recipe::find(experiment).map(|recipe| recipe.instruments)
+}
+
+// Copies a run's result tables into its run directory, the next run of the experiment overwrites the originals
+fn archive_results(run_id: &str, output_dir: &Path, files: &str) {
+    for file in run_db::output_files(files).iter().filter(|file| file.ends_with(".csv")) {
+        if let Err(err) = fs::copy(output_dir.join(file), transactions::run_dir(run_id).join(file)) {
+            warn!(error = %err, file = %file, "Failed to archive result file");
+        }
+    }
+}
+
+// Dispatches an experiment by name and returns where its results were saved
+pub fn run_experiment(
+    devices: &Devices,
+    run_id: &str,
+    output_dir: &Path,
+    experiment: &str,
+    mut params_json: serde_json::Value,
+    operator: Option<&str>,
+) -> std::result::Result<String, String> {
+    let _span = info_span!("run", run_id, experiment, operator).entered();
+
+    // Recorded with the other parameters, so a replay visits a random order's points in the same order
+    hysteresis::resolve_seed(&mut params_json);
+
+    // Every result belongs to a registered sample
+    devices.runs.require_sample(&params_json)?;
+
+    // Refuse runs that would hold the instruments for too long
+    let estimate = estimate_run(experiment, &params_json)?;
+    estimate.check()?;
+    info!(points = estimate.points, duration = %estimate::format_duration(estimate.duration_s), "Estimated run duration");
+
+    // The instruments' transactions go to the run's own log once they are locked, see Devices::lock_for_run
+    let instruments = experiment_instruments(experiment, &params_json)?;
+    let info = RunInfo {
+        run_id: run_id.to_string(),
+        experiment: experiment.to_string(),
+        params: params_json.clone(),
+        instruments,
+        started_at: queue::now_secs(),
+        operator: operator.map(str::to_string),
+    };
+    info.save().map_err(|err| format!("Failed to save run info: {}", err))?;
+    // The run still goes ahead without its index entry, the run log has everything
+    if let Err(err) = devices.runs.start(&info, output_dir) {
+        warn!(error = %err, "Failed to record run start");
+    }
+    info!("Starting run");
+
+    let result = match experiment {
+        "current_sweep" => {
+            match serde_json::from_value::<CurrentSweepParams>(params_json) {
+                Ok(params) => {
+                    run_current_sweep(devices, run_id, params, output_dir)
+                },
+                Err(err) => Err(format!("Invalid parameters: {}", err)),
+            }
+        },
+        "wavelength_check" => {
+            match serde_json::from_value::<WavelengthCheckParams>(params_json) {
+                Ok(params) => {
+                    run_wavelength_check(devices, run_id, params, output_dir)
+                },
+                Err(err) => Err(format!("Invalid parameters: {}", err)),
+            }
+        },
+        "wavelength_sweep" => {
+            match serde_json::from_value::<WavelengthSweepParams>(params_json) {
+                Ok(params) => {
+                    run_wavelength_sweep(devices, run_id, params, output_dir)
+                },
+                Err(err) => Err(format!("Invalid parameters: {}", err)),
+            }
+        },
+        "wavelength_sweep_osa" => {
+            match serde_json::from_value::<WavelengthSweepParams>(params_json) {
+                Ok(params) => {
+                    run_wavelength_sweep_osa(devices, run_id, params, output_dir)
+                },
+                Err(err) => Err(format!("Invalid parameters: {}", err)),
+            }
+        },
+        "path_calibration" => {
+            match serde_json::from_value::<PathCalibrationParams>(params_json) {
+                Ok(params) => {
+                    run_path_calibration(devices, run_id, params, output_dir)
+                },
+                Err(err) => Err(format!("Invalid parameters: {}", err)),
+            }
+        },
+        "stability" => {
+            match serde_json::from_value::<StabilityParams>(params_json) {
+                Ok(params) => {
+                    run_stability(devices, run_id, params, output_dir)
+                },
+                Err(err) => Err(format!("Invalid parameters: {}", err)),
+            }
+        },
+        "sweep" => {
+            match serde_json::from_value::<SweepPlan>(params_json) {
+                Ok(plan) => {
+                    run_sweep(devices, run_id, plan, output_dir).map(|_| {
+                        "sweep_results.csv".to_string()
+                    })
+                },
+                Err(err) => Err(format!("Invalid parameters: {}", err)),
+            }
+        },
+        // Anything else has to be a recipe file
+        _ => recipe::find(experiment).and_then(|recipe| run_recipe(devices, run_id, recipe, output_dir)),
+    };
+    if let Ok(files) = &result {
+        archive_results(run_id, output_dir, files);
+    }
+    if let Err(err) = devices.runs.finish(run_id, output_dir, &result) {
+        warn!(error = %err, "Failed to record run result");
+    }
+    result
+}
+
+/// Re-runs a recorded run against its own transaction log, without hardware
+pub fn replay_run(source_run_id: &str, transactions: Arc<TransactionLog>, operator: Option<&str>) -> ReplayReport {
+    let run_id = format!("{}-replay-{}", source_run_id, queue::now_secs());
+    let output_dir = config::data_path("replays").join(&run_id);
+
+    // Nothing is switched on in a replay, keep it out of the audit trail
+    let _audit = audit::enter(audit::Context { simulated: true, ..Default::default() });
+
+    let report = replay::prepare(source_run_id, transactions).map(|replay| {
+        let result = run_experiment(
+            &replay.devices,
+            &run_id,
+            &output_dir,
+            &replay.info.experiment,
+            replay.info.params.clone(),
+            operator,
+        );
+        replay.finish(&run_id, &output_dir, result)
+    });
+
+    report.unwrap_or_else(|err| ReplayReport {
+        success: false,
+        source_run_id: source_run_id.to_string(),
+        run_id,
+        output_dir: output_dir.display().to_string(),
+        replayed: 0,
+        unplayed: 0,
+        mismatches: Vec::new(),
+        error: Some(err),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sweep(extra: serde_json::Value) -> WavelengthSweepParams {
+        let mut params = json!({ "start_nm": 1540.0, "stop_nm": 1560.0, "step_nm": 5.0 });
+        params.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(params).unwrap()
+    }
+
+    #[test]
+    fn reference_lifetime_needs_a_capture() {
+        config::init_for_tests();
+        let devices = Devices::new();
+        let output_dir = config::data_path("reference_lifetime_test");
+        let err = run_wavelength_sweep(&devices, "lifetime", sweep(json!({ "reference_lifetime_min": 60 })), &output_dir).unwrap_err();
+        assert_eq!(err, "reference_lifetime_min needs a capture_reference");
+
+        let params = sweep(json!({ "capture_reference": "bypass", "reference_lifetime_min": 0 }));
+        let err = run_wavelength_sweep(&devices, "lifetime", params, &output_dir).unwrap_err();
+        assert!(err.starts_with("reference_lifetime_min 0 min is outside"), "{}", err);
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+use crate::devices::Devices;
+use crate::queue::now_secs;
+
+// How often connected instruments are pinged
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// A healthy instrument answers its identity query well within this
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+// Failed pings in a row before the session is dropped and reopened
+const FAILURES_BEFORE_RECONNECT: u32 = 3;
+// Status changes kept per device
+const HISTORY_LEN: usize = 50;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    /// Never connected since the server started
+    #[default]
+    Unknown,
+    Healthy,
+    /// Recent pings failed, the session is still open
+    Degraded,
+    /// The session was dropped, the monitor keeps trying to reopen it
+    Disconnected,
+    /// Closed on request so another program can use the device, not reopened automatically
+    Released,
+}
+
+/// One change of health state
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthEvent {
+    pub timestamp: u64,
+    pub health: Health,
+    pub detail: String,
+}
+
+/// Current health of a device and how it got there
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HealthRecord {
+    pub health: Health,
+    pub last_checked: Option<u64>,
+    pub latency_ms: Option<u64>,
+    pub consecutive_failures: u32,
+    /// Oldest first, at most HISTORY_LEN entries
+    pub history: VecDeque<HealthEvent>,
+}
+
+impl HealthRecord {
+    /// Changes the state, only transitions are added to the history
+    pub fn set(&mut self, health: Health, detail: &str) {
+        self.last_checked = Some(now_secs());
+        if self.health == health {
+            return;
+        }
+        self.health = health;
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(HealthEvent {
+            timestamp: now_secs(),
+            health,
+            detail: detail.to_string(),
+        });
+    }
+
+    pub fn record_ok(&mut self, latency: Duration, detail: &str) {
+        self.latency_ms = Some(latency.as_millis() as u64);
+        self.consecutive_failures = 0;
+        self.set(Health::Healthy, detail);
+    }
+
+    /// Returns the number of failures in a row, including this one
+    pub fn record_failure(&mut self, detail: &str) -> u32 {
+        self.latency_ms = None;
+        self.consecutive_failures += 1;
+        self.set(Health::Degraded, detail);
+        self.consecutive_failures
+    }
+}
+
+/// Pings every idle, connected instrument and reopens the ones that stopped answering
+pub async fn run_monitor(devices: Arc<Devices>) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        for slot in devices.slots() {
+            check(&devices, slot.info.name).await;
+        }
+    }
+}
+
+async fn check(devices: &Arc<Devices>, name: &'static str) {
+    let Some(slot) = devices.slot(name) else { return };
+
+    if slot.health().health == Health::Disconnected {
+        // Opening can take up to the VISA timeout, keep it off the async workers
+        let reopen = {
+            let devices = devices.clone();
+            tokio::task::spawn_blocking(move || devices.reconnect(name))
+                .await
+                .unwrap_or_else(|err| Err(format!("Reconnect panicked: {}", err)))
+        };
+        match reopen {
+            Ok(idn) => info!(instrument = name, idn = %idn, "Reconnected"),
+            Err(err) => slot.with_health(|record| {
+                record.last_checked = Some(now_secs());
+                warn!(instrument = name, error = %err, "Still disconnected");
+            }),
+        }
+        return;
+    }
+
+    // Devices used by an experiment are skipped, its own commands report any failure. The device stays
+    // locked while it answers, so the ping can't end up in the transaction log of a run starting meanwhile.
+    let ping = {
+        let devices = devices.clone();
+        tokio::task::spawn_blocking(move || {
+            let slot = devices.slot(name)?;
+            slot.with_idle(|handle| {
+                let start = Instant::now();
+                handle.query_timeout(slot.info.idn_command, PING_TIMEOUT).map(|_| start.elapsed())
+            })
+        })
+        .await
+        .unwrap_or_else(|err| Some(Err(io::Error::other(format!("Ping panicked: {}", err)))))
+    };
+    let Some(ping) = ping else { return };
+
+    match ping {
+        Ok(latency) => slot.with_health(|record| record.record_ok(latency, "Answered ping")),
+        Err(err) => {
+            let detail = format!("Ping failed: {}", err);
+            warn!(instrument = name, error = %err, "Ping failed");
+            let failures = slot.with_health(|record| record.record_failure(&detail));
+            if failures >= FAILURES_BEFORE_RECONNECT {
+                devices.disconnect_stale(name, &format!("No answer to {} pings", failures));
+            }
+        },
+    }
+}
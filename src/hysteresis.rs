@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use tracing::info;
+
+/// Order a one-dimensional sweep visits its points in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SweepOrder {
+    /// Start to stop, the only order before hysteresis sweeps
+    #[default]
+    Up,
+    Down,
+    /// Start to stop, then every point again from stop to start
+    UpDown,
+    /// Shuffled, so slow drifts don't line up with the setpoint
+    Random,
+}
+
+/// Which way the setpoint was moving when a point was measured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
+    Random,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SweepPoint {
+    pub value: f64,
+    pub direction: Direction,
+    /// Position among the start to stop points, shared by a setpoint's up and down readings
+    pub step: usize,
+}
+
+/// The points of a sweep in the order they are measured
+#[derive(Debug, Clone)]
+pub struct OrderedSweep {
+    pub order: SweepOrder,
+    pub points: Vec<SweepPoint>,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+            Direction::Random => "random",
+        }
+    }
+}
+
+impl SweepOrder {
+    /// Points measured for `count` start to stop points
+    pub fn visits(self, count: usize) -> usize {
+        match self {
+            SweepOrder::UpDown => count.saturating_mul(2),
+            _ => count,
+        }
+    }
+
+    /// Orders start to stop `values`. A random order is shuffled with `seed`, or a fresh seed that is logged
+    /// so the run can be repeated in the same order. Runs get theirs from `resolve_seed` before they start.
+    pub fn apply(self, values: &[f64], seed: Option<u64>) -> OrderedSweep {
+        let point = |direction| move |(step, &value): (usize, &f64)| SweepPoint { value, direction, step };
+        let up = values.iter().enumerate().map(point(Direction::Up));
+        let down = values.iter().enumerate().rev().map(point(Direction::Down));
+        let points = match self {
+            SweepOrder::Up => up.collect(),
+            SweepOrder::Down => down.collect(),
+            SweepOrder::UpDown => up.chain(down).collect(),
+            SweepOrder::Random => {
+                let seed = seed.unwrap_or_else(|| rand::thread_rng().r#gen());
+                info!(seed, "Randomized sweep order");
+                let mut points: Vec<SweepPoint> = values.iter().enumerate().map(point(Direction::Random)).collect();
+                points.shuffle(&mut StdRng::seed_from_u64(seed));
+                points
+            },
+        };
+        OrderedSweep { order: self, points }
+    }
+}
+
+/// Gives request parameters asking for a random order without a seed a fresh one
+pub fn resolve_seed(params: &mut serde_json::Value) {
+    let Some(params) = params.as_object_mut() else { return };
+    let random = params.get("order").and_then(|order| order.as_str()) == Some("random");
+    if random && params.get("seed").is_none_or(serde_json::Value::is_null) {
+        params.insert("seed".to_string(), rand::thread_rng().r#gen::<u64>().into());
+    }
+}
+
+impl OrderedSweep {
+    /// Lowest and highest setpoint
+    pub fn range(&self) -> (f64, f64) {
+        self.points.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), p| (min.min(p.value), max.max(p.value)))
+    }
+
+    /// Order and direction column headers, left out of plain upward sweeps so their files stay as they were
+    pub fn header(&self) -> &'static str {
+        match self.order {
+            SweepOrder::Up => "",
+            _ => ",Order,Direction",
+        }
+    }
+
+    /// Columns for the `index`th point measured, in the order of `header`
+    pub fn columns(&self, index: usize) -> String {
+        match self.order {
+            SweepOrder::Up => String::new(),
+            _ => format!(",{},{}", index, self.points[index].direction.as_str()),
+        }
+    }
+
+    /// Keeps per point files of the down branch apart from the up branch's
+    pub fn file_suffix(&self, point: &SweepPoint) -> &'static str {
+        match (self.order, point.direction) {
+            (SweepOrder::UpDown, Direction::Down) => "_down",
+            _ => "",
+        }
+    }
+
+    /// Writes each setpoint's up and down readings side by side with their difference (down - up), for up-down
+    /// sweeps only. `columns` names the setpoint and then each quantity.
+    pub fn write_hysteresis(&self, path: &Path, columns: &[&str], readings: &[(SweepPoint, Vec<f64>)]) -> io::Result<()> {
+        if self.order != SweepOrder::UpDown {
+            return Ok(());
+        }
+        let (setpoint, quantities) = columns.split_first().unwrap_or((&"Setpoint", &[]));
+
+        // Both readings of a setpoint share its step
+        let mut branches = BTreeMap::new();
+        for (point, values) in readings {
+            let (_, up, down) = branches.entry(point.step).or_insert((point.value, None, None));
+            match point.direction {
+                Direction::Down => *down = Some(values.as_slice()),
+                _ => *up = Some(values.as_slice()),
+            }
+        }
+
+        let mut file = File::create(path)?;
+        let header: Vec<String> = quantities.iter()
+            .map(|q| format!("{0} Up,{0} Down,{0} Difference", q))
+            .collect();
+        writeln!(file, "{},{}", setpoint, header.join(","))?;
+        let mut largest = vec![0.0f64; quantities.len()];
+        for (value, up, down) in branches.values() {
+            let (Some(up), Some(down)) = (up, down) else {
+                continue; // Only a completed up-down sweep has both, a run that fails returns before this
+            };
+            let row: Vec<String> = up.iter().zip(down.iter()).enumerate()
+                .map(|(q, (u, d))| {
+                    largest[q] = largest[q].max((d - u).abs());
+                    format!("{:.6},{:.6},{:.6}", u, d, d - u)
+                })
+                .collect();
+            writeln!(file, "{},{}", value, row.join(","))?;
+        }
+        for (quantity, largest) in quantities.iter().zip(largest) {
+            info!(quantity, largest_difference = largest, "Hysteresis");
+        }
+        info!(file = %path.display(), "Hysteresis saved");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn values(sweep: &OrderedSweep) -> Vec<f64> {
+        sweep.points.iter().map(|p| p.value).collect()
+    }
+
+    #[test]
+    fn up_down_visits_every_point_twice() {
+        let sweep = SweepOrder::UpDown.apply(&[1.0, 2.0, 3.0], None);
+        assert_eq!(values(&sweep), vec![1.0, 2.0, 3.0, 3.0, 2.0, 1.0]);
+        assert_eq!(SweepOrder::UpDown.visits(3), 6);
+        assert_eq!(sweep.file_suffix(&sweep.points[3]), "_down");
+        assert_eq!(sweep.points[3].step, sweep.points[2].step);
+    }
+
+    #[test]
+    fn random_order_repeats_with_its_seed() {
+        let points: Vec<f64> = (0..20).map(f64::from).collect();
+        let first = SweepOrder::Random.apply(&points, Some(7));
+        let again = SweepOrder::Random.apply(&points, Some(7));
+        assert_eq!(values(&first), values(&again));
+        let mut sorted = values(&first);
+        sorted.sort_by(f64::total_cmp);
+        assert_eq!(sorted, points);
+    }
+
+    #[test]
+    fn resolve_seed_only_fills_a_missing_random_seed() {
+        let mut random = json!({ "order": "random" });
+        resolve_seed(&mut random);
+        assert!(random["seed"].is_u64());
+
+        let mut seeded = json!({ "order": "random", "seed": 3 });
+        resolve_seed(&mut seeded);
+        assert_eq!(seeded["seed"], 3);
+
+        let mut up = json!({ "order": "up_down" });
+        resolve_seed(&mut up);
+        assert!(up.get("seed").is_none());
+    }
+}
@@ -0,0 +1,114 @@
+use serde::Serialize;
+
+/// Static description of an instrument known to the server
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct InstrumentInfo {
+    /// Name used in API routes and recipe files
+    pub name: &'static str,
+    /// Short name used in messages
+    pub label: &'static str,
+    pub model: &'static str,
+    pub resource: &'static str,
+    /// Clears the error queue before identification, if supported
+    #[serde(skip)]
+    pub clear_command: Option<&'static str>,
+    #[serde(skip)]
+    pub idn_command: &'static str,
+    /// Headers a recipe's raw setup commands may use, measurement settings that can't switch a source
+    /// or change its level. Everything else only goes through the drivers so the safety limits apply.
+    #[serde(skip)]
+    pub setup_headers: &'static [&'static str],
+}
+
+pub const REGISTRY: &[InstrumentInfo] = &[
+    InstrumentInfo {
+        name: "cld1015",
+        label: "CLD1015",
+        model: "Thorlabs CLD1015",
+        resource: "USB::4883::32847::M01053290::0::INSTR",
+        clear_command: Some("*CLS"),
+        idn_command: "*IDN?",
+        // Current, temperature and output are all sweep axes
+        setup_headers: &[],
+    },
+    InstrumentInfo {
+        name: "n77",
+        label: "N77",
+        model: "Keysight N7714A",
+        resource: "GPIB0::21::INSTR",
+        clear_command: Some("*CLS"),
+        idn_command: "*IDN?",
+        // Wavelength, power and output are all sweep axes
+        setup_headers: &[],
+    },
+    InstrumentInfo {
+        name: "power_meter",
+        label: "Power Meter",
+        model: "Santec MPM210-H",
+        resource: "GPIB0::16::INSTR",
+        clear_command: None,
+        idn_command: "*IDN?",
+        // Averaging time, unit, gain level and auto range
+        setup_headers: &["AVG", "UNIT", "LEV", "AUTO"],
+    },
+    InstrumentInfo {
+        name: "osa",
+        label: "OSA",
+        model: "HP-70952B",
+        resource: "GPIB0::23::INSTR",
+        clear_command: Some("CLS;"),
+        idn_command: "ID?;",
+        // Resolution and video bandwidth, sweep time, sensitivity, reference level, log scale and amplitude units
+        setup_headers: &["RB", "VB", "ST", "SENS", "RL", "LG", "AUNITS"],
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static InstrumentInfo> {
+    REGISTRY.iter().find(|info| info.name == name)
+}
+
+/// Accepts setup commands only if every command in them has one of the instrument's setup headers.
+/// Compound commands are split on ';', so nothing can ride along behind an allowed header.
+pub fn check_command(name: &str, command: &str) -> Result<(), String> {
+    let info = lookup(name).ok_or_else(|| format!("Unknown instrument: {}", name))?;
+
+    for part in command.split(';').map(str::trim).filter(|part| !part.is_empty()) {
+        // Only the header matters, arguments such as "0.1NM" are ignored
+        let header = part.split_whitespace().next().unwrap_or("").trim_start_matches(':').to_uppercase();
+        if !info.setup_headers.contains(&header.as_str()) {
+            return Err(format!(
+                "Command '{}' for {} is not an allowed setup command ({}), set outputs and levels through the sweep axes",
+                part,
+                name,
+                match info.setup_headers {
+                    [] => "none".to_string(),
+                    headers => headers.join(", "),
+                },
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setup_commands_must_use_allowed_headers() {
+        assert!(check_command("osa", "RB 0.1NM;").is_ok());
+        assert!(check_command("osa", "RB 0.1NM;VB 100HZ;").is_ok());
+        assert!(check_command("power_meter", "AVG 100").is_ok());
+    }
+
+    #[test]
+    fn source_commands_are_refused() {
+        // Levels in any spelling, and commands behind an allowed one
+        assert!(check_command("cld1015", "SOUR:CURR 0.5").is_err());
+        assert!(check_command("cld1015", "OUTP 1").is_err());
+        assert!(check_command("n77", ":SOUR2:WAV 1600NM").is_err());
+        assert!(check_command("n77", "*CLS;:SOUR2:POW:STAT 1").is_err());
+        assert!(check_command("osa", "RB 0.1NM;SNGLS;").is_err());
+        assert!(check_command("laser", "RB 0.1NM").is_err());
+    }
+}
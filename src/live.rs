@@ -0,0 +1,121 @@
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use tokio::sync::watch;
+use crate::lock::lock_or_recover;
+
+// Finished feeds kept for a late viewer, the oldest go first
+const FINISHED_KEPT: usize = 4;
+
+/// Time series of running experiments, for the web interface to follow while they run
+#[derive(Default)]
+pub struct LiveFeeds {
+    feeds: Mutex<Vec<Arc<Feed>>>,
+}
+
+/// Points of one run, each row in the order of `columns`
+pub struct Feed {
+    pub run_id: String,
+    pub experiment: String,
+    pub columns: Vec<String>,
+    state: Mutex<FeedState>,
+    // Bumped on every point and when the run ends
+    changed: watch::Sender<usize>,
+}
+
+#[derive(Default)]
+struct FeedState {
+    rows: Vec<Vec<f64>>,
+    done: bool,
+}
+
+/// A feed as listed to the web interface
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedSummary {
+    pub run_id: String,
+    pub experiment: String,
+    pub columns: Vec<String>,
+    pub points: usize,
+    pub done: bool,
+}
+
+/// Writes a run's points to its feed, the feed is marked done when this is dropped, failed runs included
+pub struct Publisher(Arc<Feed>);
+
+impl LiveFeeds {
+    /// Starts the feed of a run, dropping finished feeds beyond the few kept
+    pub fn open(&self, run_id: &str, experiment: &str, columns: &[&str]) -> Publisher {
+        let feed = Arc::new(Feed {
+            run_id: run_id.to_string(),
+            experiment: experiment.to_string(),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            state: Mutex::new(FeedState::default()),
+            changed: watch::Sender::new(0),
+        });
+        let mut feeds = lock_or_recover(&self.feeds);
+        feeds.retain(|f| f.run_id != run_id);
+        let finished = feeds.iter().filter(|f| f.is_done()).count();
+        let mut excess = (finished + 1).saturating_sub(FINISHED_KEPT);
+        feeds.retain(|f| {
+            let drop = excess > 0 && f.is_done();
+            if drop {
+                excess -= 1;
+            }
+            !drop
+        });
+        feeds.push(feed.clone());
+        Publisher(feed)
+    }
+
+    pub fn get(&self, run_id: &str) -> Option<Arc<Feed>> {
+        lock_or_recover(&self.feeds).iter().find(|f| f.run_id == run_id).cloned()
+    }
+
+    /// Most recent first
+    pub fn list(&self) -> Vec<FeedSummary> {
+        lock_or_recover(&self.feeds).iter().rev().map(|f| f.summary()).collect()
+    }
+}
+
+impl Feed {
+    pub fn is_done(&self) -> bool {
+        lock_or_recover(&self.state).done
+    }
+
+    pub fn summary(&self) -> FeedSummary {
+        let state = lock_or_recover(&self.state);
+        FeedSummary {
+            run_id: self.run_id.clone(),
+            experiment: self.experiment.clone(),
+            columns: self.columns.clone(),
+            points: state.rows.len(),
+            done: state.done,
+        }
+    }
+
+    /// Rows from `index` on and whether the run has ended
+    pub fn since(&self, index: usize) -> (Vec<Vec<f64>>, bool) {
+        let state = lock_or_recover(&self.state);
+        (state.rows.get(index..).unwrap_or_default().to_vec(), state.done)
+    }
+
+    /// Notified on every change, subscribe before reading so nothing is missed in between
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.changed.subscribe()
+    }
+}
+
+impl Publisher {
+    pub fn push(&self, row: Vec<f64>) {
+        let mut state = lock_or_recover(&self.0.state);
+        state.rows.push(row);
+        self.0.changed.send_replace(state.rows.len());
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        let mut state = lock_or_recover(&self.0.state);
+        state.done = true;
+        self.0.changed.send_modify(|_| {});
+    }
+}
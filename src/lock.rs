@@ -0,0 +1,9 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Locks a mutex, carrying on with the data if a thread panicked while holding it
+pub fn lock_or_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(), // Recover from poison
+    }
+}
@@ -0,0 +1,35 @@
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+use crate::config;
+
+// JSON log files below the data root, one per day
+const LOG_DIR: &str = "logs";
+const LOG_FILE_PREFIX: &str = "server";
+const LOG_FILE_SUFFIX: &str = "jsonl";
+// Used when RUST_LOG is not set
+const DEFAULT_LEVEL: &str = "info";
+
+/// Logs readable lines to the console and JSON lines to logs/server.<date>.jsonl in the data root.
+/// The level comes from RUST_LOG, e.g. "debug" or "info,automating_experiments=debug".
+/// The returned guard flushes the file on drop, keep it alive for the whole program.
+pub fn init() -> Result<WorkerGuard, Box<dyn std::error::Error>> {
+    let file_appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix(LOG_FILE_SUFFIX)
+        .build(config::data_path(LOG_DIR))?;
+    let (file_writer, guard) = tracing_appender::non_blocking(file_appender);
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LEVEL));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(fmt::layer().json().with_current_span(true).with_span_list(true).with_writer(file_writer))
+        .try_init()?;
+
+    Ok(guard)
+}
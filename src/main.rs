@@ -1,24 +1,67 @@
 #![allow(unused)]
 
+mod actor;
+mod audit;
+mod auth;
+mod calibration;
+mod cli;
+mod config;
 mod cld1015_osa;
+mod devices;
+mod drivers;
+mod estimate;
+mod experiments;
+mod health;
+mod hysteresis;
+mod instruments;
+mod live;
+mod lock;
+mod logging;
 mod n77_wavelength_sweep;
 mod n77_wavelength_check;
 mod n77_osa;
+mod n77_path_calibration;
+mod n77_swept_sweep;
+mod queue;
+mod recipe;
+mod reference;
+mod replay;
+mod run_db;
+mod safety;
+mod samples;
+mod settle;
+mod stability;
+mod stats;
+mod sweep;
+mod transactions;
+mod transport;
 mod visa_error;
 mod web_server;
 
 use std::ffi::CString;
 use std::io::{self, BufRead, BufReader, Write};
 use std::time::Duration;
+use clap::Parser;
 use visa_rs::prelude::*;
-use visa_error::io_to_vs_err;
 
 #[tokio::main]
 async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    println!("Starting experiment control server...");
-    
-    // Start the web server
-    web_server::start_server().await?;
+    let cli = cli::Cli::parse();
+    config::init(config::Config::load(cli.settings)?);
+
+    // Keep the guard until exit so buffered log lines reach the file
+    let _log_guard = logging::init()?;
+
+    match cli.command {
+        None | Some(cli::Command::Serve) => {
+            tracing::info!("Starting experiment control server...");
+
+            // Start the web server
+            web_server::start_server().await?;
+        },
+        // Instrument I/O blocks, so one-off commands run off the async workers
+        Some(command) => tokio::task::spawn_blocking(move || cli::execute(command)).await??,
+    }
     
     Ok(())
-}
\ No newline at end of file
+}
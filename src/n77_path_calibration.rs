@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use std::thread;
+use tracing::{info, info_span, warn};
+use crate::{drivers, safety};
+use crate::actor::InstrumentHandle;
+use crate::calibration::CalibrationPoint;
+use crate::drivers::power_meter;
+use crate::settle::{Probe, Settle};
+use crate::stats::{Repeats, SampleLog};
+
+/// Measures the loss of the optical path from the N77 to the power meter at each wavelength
+pub fn run_path_calibration(
+    laser: &InstrumentHandle,
+    meter: &InstrumentHandle,
+    wavelengths: &[f64],
+    laser_power_dbm: f64,
+    (settle, repeats): (&Settle, &Repeats),
+    output_dir: &Path,
+) -> io::Result<Vec<CalibrationPoint>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    safety::check("Laser power", "dBm", &safety::N77_POWER_DBM, laser_power_dbm).map_err(invalid)?;
+    for &wavelength in wavelengths {
+        safety::check("Wavelength", "nm", &safety::N77_WAVELENGTH_NM, wavelength).map_err(invalid)?;
+    }
+
+    // Create a CSV file to save results
+    std::fs::create_dir_all(output_dir).unwrap_or_else(|e| {
+        warn!(error = %e, "Failed to create data directory");
+    });
+    let mut file = File::create(output_dir.join("path_calibration_results.csv"))?;
+    writeln!(file, "Wavelength (nm),Laser Power (dBm),Raw Power (dBm),Path Loss (dB){}", repeats.header(&["Raw Power (dBm)"]))?;
+    let mut samples = SampleLog::new(repeats, &output_dir.join("path_calibration_samples.csv"), &["Wavelength (nm)", "Raw Power (dBm)"])?;
+    info!(points = wavelengths.len(), "Starting path calibration");
+
+    // The loss is what the meter misses of a known laser power
+    drivers::n77::configure(laser)?;
+    drivers::n77::set_power_dbm(laser, laser_power_dbm)?;
+    power_meter::configure(meter)?;
+    drivers::n77::set_wavelength_nm(laser, wavelengths.first().copied().unwrap_or_default())?;
+    drivers::n77::set_output(laser, true)?;
+    info!("Laser turned ON");
+    thread::sleep(Duration::from_millis(settle.wait_ms));
+
+    let result = measure_points(laser, meter, wavelengths, laser_power_dbm, (settle, repeats), (&mut file, &mut samples));
+
+    // Always try to leave the laser off, even if the calibration failed
+    drivers::n77::set_output(laser, false)?;
+    info!("Laser turned OFF");
+    let points = result?;
+
+    info!(response = %drivers::n77::read_error(laser)?, "Final error check on laser");
+    info!(response = %power_meter::read_error(meter)?, "Final error check on power meter");
+    info!(file = %output_dir.join("path_calibration_results.csv").display(), "Results saved");
+    Ok(points)
+}
+
+fn measure_points(
+    laser: &InstrumentHandle,
+    meter: &InstrumentHandle,
+    wavelengths: &[f64],
+    laser_power_dbm: f64,
+    (settle, repeats): (&Settle, &Repeats),
+    (file, samples): (&mut File, &mut SampleLog),
+) -> io::Result<Vec<CalibrationPoint>> {
+    let mut points = Vec::with_capacity(wavelengths.len());
+    for &wavelength in wavelengths {
+        let _point = info_span!("point", setpoint = wavelength, unit = "nm").entered();
+
+        drivers::n77::set_wavelength_nm(laser, wavelength)?;
+        power_meter::set_wavelength_nm(meter, wavelength)?;
+        settle.wait(Probe::Power(meter))?;
+
+        // The loss comes from the mean reading when repeated
+        let stats = repeats.measure_one(|| power_meter::read_power_dbm(meter))?;
+        let power = stats.mean;
+        let loss = laser_power_dbm - power;
+        info!(power_dbm = power, loss_db = loss, "Measured path loss");
+        writeln!(file, "{:.3},{:.3},{:.6},{:.4}{}", wavelength, laser_power_dbm, power, loss, repeats.columns(std::slice::from_ref(&stats)))?;
+        samples.write(wavelength, &[stats])?;
+        points.push(CalibrationPoint { wavelength_nm: wavelength, loss_db: loss });
+    }
+    Ok(points)
+}
@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::thread;
+use tracing::{info, warn};
+use crate::{drivers, safety, sweep};
+use crate::actor::InstrumentHandle;
+use crate::calibration::{self, PathCalibration};
+use crate::drivers::power_meter;
+
+/// Continuous sweep speeds the N7714A supports
+pub const SPEED_NM_S: RangeInclusive<f64> = 0.5..=200.0;
+/// Finest trigger step of the N7714A
+pub const MIN_STEP_NM: f64 = 0.0001;
+/// Readings the MPM210-H can hold in one log
+pub const MAX_POINTS: usize = 1_000_001;
+
+// How often the laser and meter are asked whether they're done
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Extra time allowed on top of the sweep itself before giving up
+const COMPLETION_MARGIN: Duration = Duration::from_secs(10);
+
+/// Number of triggers the laser sends for a sweep
+pub fn expected_points(start_nm: f64, stop_nm: f64, step_nm: f64) -> usize {
+    sweep::linear_count(start_nm, stop_nm, step_nm)
+}
+
+/// Sweeps the laser continuously while the power meter logs a reading at every trigger,
+/// returning each wavelength and its power. Wavelengths are rebuilt from the trigger count.
+pub fn run_swept_wavelength_sweep(
+    laser: &InstrumentHandle,
+    meter: &InstrumentHandle,
+    (start_nm, stop_nm, step_nm): (f64, f64, f64),
+    speed_nm_s: f64,
+    stabilization_time_ms: u64,
+    calibration: Option<&PathCalibration>,
+    output_dir: &Path,
+) -> io::Result<Vec<(f64, f64)>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    safety::check("Wavelength", "nm", &safety::N77_WAVELENGTH_NM, start_nm).map_err(invalid)?;
+    safety::check("Wavelength", "nm", &safety::N77_WAVELENGTH_NM, stop_nm).map_err(invalid)?;
+    let expected = expected_points(start_nm, stop_nm, step_nm);
+
+    // Create a CSV file to save results
+    std::fs::create_dir_all(output_dir).unwrap_or_else(|e| {
+        warn!(error = %e, "Failed to create data directory");
+    });
+    let mut file = File::create(output_dir.join("wavelength_sweep_results.csv"))?;
+    // With a path calibration the power is the one at the device, the meter's reading is kept next to it
+    match calibration {
+        Some(_) => writeln!(file, "Wavelength (nm),Power (dBm),Raw Power (dBm),Path Loss (dB)")?,
+        None => writeln!(file, "Wavelength (nm),Power (dBm)")?,
+    }
+    info!(points = expected, speed_nm_s, "Starting swept wavelength sweep");
+
+    // Park the laser at the start so the first trigger comes from a settled source
+    drivers::n77::configure(laser)?;
+    drivers::n77::set_wavelength_nm(laser, start_nm)?;
+    drivers::n77::configure_sweep(laser, start_nm, stop_nm, step_nm, speed_nm_s)?;
+    power_meter::configure_logging(meter, start_nm, stop_nm, step_nm, speed_nm_s)?;
+    drivers::n77::set_output(laser, true)?;
+    info!("Laser turned ON");
+    thread::sleep(Duration::from_millis(stabilization_time_ms));
+
+    let sweep_time = Duration::from_secs_f64((stop_nm - start_nm) / speed_nm_s);
+    let result = acquire(laser, meter, sweep_time);
+
+    // Always try to leave the laser off, even if the acquisition failed
+    if result.is_err() {
+        drivers::n77::stop_sweep(laser).unwrap_or_else(|e| warn!(error = %e, "Failed to stop the sweep"));
+        power_meter::stop_logging(meter).unwrap_or_else(|e| warn!(error = %e, "Failed to stop logging"));
+    }
+    drivers::n77::set_output(laser, false)?;
+    info!("Laser turned OFF");
+    let powers = result?;
+
+    // Every trigger is one step further along, so the count gives the wavelength of each reading
+    if powers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The power meter logged no readings"));
+    }
+    if powers.len() != expected {
+        warn!(logged = powers.len(), expected, "Logged readings don't match the trigger count, extra readings are dropped");
+    }
+    let mut points = Vec::with_capacity(expected);
+    for (index, &raw) in powers.iter().take(expected).enumerate() {
+        let wavelength = start_nm + index as f64 * step_nm;
+        let power = calibration::write_power_row(&mut file, calibration, wavelength, raw, "")?;
+        points.push((wavelength, power));
+    }
+
+    info!(response = %drivers::n77::read_error(laser)?, "Final error check on laser");
+    info!(response = %power_meter::read_error(meter)?, "Final error check on power meter");
+    info!(points = points.len(), file = %output_dir.join("wavelength_sweep_results.csv").display(), "Results saved");
+    Ok(points)
+}
+
+// Arms the meter, runs the sweep and waits for both to finish, returning the logged powers
+fn acquire(laser: &InstrumentHandle, meter: &InstrumentHandle, sweep_time: Duration) -> io::Result<Vec<f64>> {
+    power_meter::start_logging(meter)?;
+    drivers::n77::start_sweep(laser)?;
+    let deadline = Instant::now() + sweep_time + COMPLETION_MARGIN;
+
+    while drivers::n77::sweep_running(laser)? {
+        if Instant::now() > deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "The laser sweep didn't finish in time"));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    info!("Laser sweep finished");
+
+    loop {
+        let (done, logged) = power_meter::logging_status(meter)?;
+        if done {
+            info!(logged, "Power meter logging finished");
+            break;
+        }
+        if Instant::now() > deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("The power meter logged {} readings and didn't finish", logged),
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    power_meter::read_log_dbm(meter, power_meter::PORT)
+}
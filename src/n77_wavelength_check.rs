@@ -1,125 +1,101 @@
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, Write};
+use std::path::Path;
 use std::time::Duration;
 use std::thread;
-use visa_rs::prelude::*;
-use crate::visa_error::io_to_vs_err;
+use tracing::{info, warn};
+use crate::{drivers, safety};
+use crate::actor::InstrumentHandle;
+use crate::calibration::{self, PathCalibration};
+use crate::drivers::power_meter as meter;
+use crate::settle::{Probe, Settle};
+use crate::stats::{Repeats, SampleLog};
 
 
 /// Runs a wavelength and grabs its power
 pub fn run_wavelength_check(
-    laser: &mut Instrument,
-    power_meter: &mut Instrument,
+    laser: &InstrumentHandle,
+    power_meter: &InstrumentHandle,
     wavelength: f64,
-    stabilization_time_ms: u64,
-) -> visa_rs::Result<()> {
+    settle: &Settle,
+    calibration: Option<&PathCalibration>,
+    repeats: &Repeats,
+    output_dir: &Path,
+) -> io::Result<()> {
     // Create a CSV file to save results
-    std::fs::create_dir_all("data").unwrap_or_else(|e| {
-        println!("Warning: Failed to create data directory: {}", e);
+    std::fs::create_dir_all(output_dir).unwrap_or_else(|e| {
+        warn!(error = %e, "Failed to create data directory");
     });
-    let mut file = File::create("data/wavelength_check_result.csv").map_err(io_to_vs_err)?;
-    writeln!(file, "Wavelength (nm),Power (dBm)").map_err(io_to_vs_err)?;
+    let mut file = File::create(output_dir.join("wavelength_check_result.csv"))?;
+    // With a path calibration the power is the one at the device, the meter's reading is kept next to it
+    let stats_header = repeats.header(&[calibration::raw_power_column(calibration)]);
+    match calibration {
+        Some(_) => writeln!(file, "Wavelength (nm),Power (dBm),Raw Power (dBm),Path Loss (dB){}", stats_header)?,
+        None => writeln!(file, "Wavelength (nm),Power (dBm){}", stats_header)?,
+    }
+    let mut samples = SampleLog::new(repeats, &output_dir.join("wavelength_check_samples.csv"), &["Wavelength (nm)", "Power (dBm)"])?;
     
     // Configure the laser for the experiment
-    laser.write_all(b":SOURce2:POWer:STATe 0\n").map_err(io_to_vs_err)?;
-    laser.write_all(b":SOURce2:WAVelength:AUTO 1\n").map_err(io_to_vs_err)?;  // Set power to default value
-    laser.write_all(b":SOURce2:POWer:UNit 0\n").map_err(io_to_vs_err)?;  // Set power unit to dBm
-    laser.write_all(b":SOURce2:POWer:LEVel:IMMediate:AMPLitude DEF\n").map_err(io_to_vs_err)?;  // Set power to default value
+    drivers::n77::configure(laser)?;  // Output off, dBm at the default power
 
     // Configure the power meter for the experiment
-    power_meter.write_all(b"WMOD CONST1\n").map_err(io_to_vs_err)?;  // Constant wavelength mode
-    power_meter.write_all(b"AVG 50\n").map_err(io_to_vs_err)?;        // 50ms averaging time
-    power_meter.write_all(b"UNIT 0\n").map_err(io_to_vs_err)?;        // dBm units
+    power_meter.write("WMOD CONST1")?;  // Constant wavelength mode
+    power_meter.write("AVG 50")?;        // 50ms averaging time
+    power_meter.write("UNIT 0")?;        // dBm units
     
     // Turn laser 2 ON
-    laser.write_all(b":SOURce2:POWer:STATe 1\n").map_err(io_to_vs_err)?;
-    println!("Laser turned ON");
+    drivers::n77::set_output(laser, true)?;
+    info!("Laser turned ON");
     
     // Wait for initial stabilization
-    thread::sleep(Duration::from_millis(stabilization_time_ms));
+    thread::sleep(Duration::from_millis(settle.wait_ms));
     
         
     // Validate wavelength is within safe range
-    if wavelength < 1527.60 || wavelength > 1570.01 {
-        return Err(io_to_vs_err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("Wavelength {:.2} nm is outside safe range (1527.60-1570.01 nm)", wavelength)
-        )));
+    if let Err(msg) = safety::check("Wavelength", "nm", &safety::N77_WAVELENGTH_NM, wavelength) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
     }
         
     // Set the laser wavelength
-    let cmd = format!(":SOURce2:WAVelength:CW {:.3}NM\n", wavelength);
-    laser.write_all(cmd.as_bytes()).map_err(io_to_vs_err)?;
+    let cmd = format!(":SOURce2:WAVelength:CW {:.3}NM", wavelength);
+    laser.write(&cmd)?;
         
     // Update power meter wavelength calibration
-    let cmd = format!("WAV {:.3}\n", wavelength);
-    power_meter.write_all(cmd.as_bytes()).map_err(io_to_vs_err)?;
+    let cmd = format!("WAV {:.3}", wavelength);
+    power_meter.write(&cmd)?;
         
-    println!("Set wavelength to {:.2} nm", wavelength);
+    info!(wavelength_nm = wavelength, "Set wavelength");
         
     // Wait for stabilization
-    thread::sleep(Duration::from_millis(150));
-        
-    // Measure power
-    power_meter.write_all(b"READ? 0\n").map_err(io_to_vs_err)?; // from module 0
-        
-    let mut power_response = String::new();
-    {
-        let mut reader = BufReader::new(&*power_meter);
-        reader.read_line(&mut power_response).map_err(io_to_vs_err)?;
-    }
-        
-    // Parse the power value (format: "power1,power2,power3,power4")
-    let power_values: Vec<&str> = power_response.trim().split(',').collect();
-    if power_values.is_empty() {
-        return Err(io_to_vs_err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "No power readings received"
-        )));
-    }
+    settle.wait(Probe::Power(power_meter))?;
         
-    let power = match power_values[1].parse::<f64>() { // Save power value from the 2nd port (top to bottom)
-        Ok(value) => value,
-        Err(_) => return Err(io_to_vs_err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("Failed to parse power value: {}", power_values[1])
-        ))),
-    };
+    // Measure power, the mean when repeated
+    let stats = repeats.measure_one(|| meter::read_power_dbm(power_meter))?;
+    let power = stats.mean;
         
     // Print measured values
-    println!("  Power: {:.3} dBm", power);
+    info!(power_dbm = power, "Measured power");
         
     // Write to result to file
-    writeln!(file, "{:.3},{:.6}", wavelength, power).map_err(io_to_vs_err)?;
+    calibration::write_power_row(&mut file, calibration, wavelength, power, &repeats.columns(std::slice::from_ref(&stats)))?;
+    samples.write(wavelength, &[stats])?;
     
     // Turn laser OFF
-    laser.write_all(b":SOURce2:POWer:STATe 0\n").map_err(io_to_vs_err)?;
-    println!("Laser turned OFF");
+    drivers::n77::set_output(laser, false)?;
+    info!("Laser turned OFF");
     
     // Check for errors on laser
-    laser.write_all(b"SYST:ERR?\n").map_err(io_to_vs_err)?;
-    
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&*laser);
-        reader.read_line(&mut response).map_err(io_to_vs_err)?;
-    }
+    let response = laser.query("SYST:ERR?")?;
     
-    println!("Final error check on laser: {}", response.trim());
+    info!(response = %response.trim(), "Final error check on laser");
     
     // Check for errors on power meter
-    power_meter.write_all(b"ERR?\n").map_err(io_to_vs_err)?;
-    
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&*power_meter);
-        reader.read_line(&mut response).map_err(io_to_vs_err)?;
-    }
+    let response = power_meter.query("ERR?")?;
     
-    println!("Final error check on power meter: {}", response.trim());
+    info!(response = %response.trim(), "Final error check on power meter");
     
-    println!("Constant wavelength check completed successfully");
-    println!("Results saved to wavelength_check_result.csv");
+    info!("Constant wavelength check completed successfully");
+    info!(file = %output_dir.join("wavelength_check_result.csv").display(), "Results saved");
     
     Ok(())
 }
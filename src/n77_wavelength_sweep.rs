@@ -1,137 +1,117 @@
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, Write};
+use std::path::Path;
 use std::time::Duration;
 use std::thread;
-use visa_rs::prelude::*;
-use crate::visa_error;
-use visa_error::io_to_vs_err;
+use tracing::{info, info_span, warn};
+use crate::{drivers, safety};
+use crate::actor::InstrumentHandle;
+use crate::calibration::{self, PathCalibration};
+use crate::drivers::power_meter as meter;
+use crate::hysteresis::OrderedSweep;
+use crate::settle::{Probe, Settle};
+use crate::stats::{Repeats, SampleLog};
 
-/// Runs a wavelength sweep with the specified parameters
+/// Runs a wavelength sweep with the specified parameters, returning each wavelength and its power in the order measured
 pub fn run_wavelength_sweep(
-    laser: &mut Instrument,
-    power_meter: &mut Instrument,
-    start_nm: f64,
-    stop_nm: f64,
-    step_nm: f64,
-    stabilization_time_ms: u64,
-) -> visa_rs::Result<()> {
+    laser: &InstrumentHandle,
+    power_meter: &InstrumentHandle,
+    sweep: &OrderedSweep,
+    settle: &Settle,
+    calibration: Option<&PathCalibration>,
+    repeats: &Repeats,
+    output_dir: &Path,
+) -> io::Result<Vec<(f64, f64)>> {
     // Create a CSV file to save results
-    std::fs::create_dir_all("data").unwrap_or_else(|e| {
-        println!("Warning: Failed to create data directory: {}", e);
+    std::fs::create_dir_all(output_dir).unwrap_or_else(|e| {
+        warn!(error = %e, "Failed to create data directory");
     });
-    let mut file = File::create("data/wavelength_sweep_results.csv").map_err(io_to_vs_err)?;
-    writeln!(file, "Wavelength (nm),Power (dBm)").map_err(io_to_vs_err)?;
+    let mut file = File::create(output_dir.join("wavelength_sweep_results.csv"))?;
+    // With a path calibration the power is the one at the device, the meter's reading is kept next to it
+    let extra_header = format!("{}{}", repeats.header(&[calibration::raw_power_column(calibration)]), sweep.header());
+    match calibration {
+        Some(_) => writeln!(file, "Wavelength (nm),Power (dBm),Raw Power (dBm),Path Loss (dB){}", extra_header)?,
+        None => writeln!(file, "Wavelength (nm),Power (dBm){}", extra_header)?,
+    }
+    let mut samples = SampleLog::new(repeats, &output_dir.join("wavelength_sweep_samples.csv"), &["Wavelength (nm)", "Power (dBm)"])?;
     
-    // Calculate number of points
-    let num_points = ((stop_nm - start_nm) / step_nm).floor() as usize + 1;
-    println!("Starting wavelength sweep with {} points", num_points);
+    info!(points = sweep.points.len(), order = ?sweep.order, "Starting wavelength sweep");
     
     // Configure the laser for the experiment
-    laser.write_all(b":SOURce2:POWer:STATe 0\n").map_err(io_to_vs_err)?;
-    laser.write_all(b":SOURce2:WAVelength:AUTO 1\n").map_err(io_to_vs_err)?;  // Set power to default value
-    laser.write_all(b":SOURce2:POWer:UNit 0\n").map_err(io_to_vs_err)?;  // Set power unit to dBm
-    laser.write_all(b":SOURce2:POWer:LEVel:IMMediate:AMPLitude DEF\n").map_err(io_to_vs_err)?;  // Set power to default value
+    drivers::n77::configure(laser)?;  // Output off, dBm at the default power
 
     // Configure the power meter for the experiment
-    power_meter.write_all(b"WMOD CONST1\n").map_err(io_to_vs_err)?;  // Constant wavelength mode
-    power_meter.write_all(b"AVG 50\n").map_err(io_to_vs_err)?;        // 50ms averaging time
-    power_meter.write_all(b"UNIT 0\n").map_err(io_to_vs_err)?;        // dBm units
+    power_meter.write("WMOD CONST1")?;  // Constant wavelength mode
+    power_meter.write("AVG 50")?;        // 50ms averaging time
+    power_meter.write("UNIT 0")?;        // dBm units
     
     // Turn laser 2 ON
-    laser.write_all(b":SOURce2:POWer:STATe 1\n").map_err(io_to_vs_err)?;
-    println!("Laser turned ON");
+    drivers::n77::set_output(laser, true)?;
+    info!("Laser turned ON");
     
-    let cmd = format!(":SOURce2:WAVelength:CW {:.3}NM\n", start_nm);
-    laser.write_all(cmd.as_bytes()).map_err(io_to_vs_err)?;
+    let start_nm = sweep.points.first().map(|p| p.value).unwrap_or_default();
+    let cmd = format!(":SOURce2:WAVelength:CW {:.3}NM", start_nm);
+    laser.write(&cmd)?;
     // Wait for initial stabilization
-    thread::sleep(Duration::from_millis(stabilization_time_ms));
+    thread::sleep(Duration::from_millis(settle.wait_ms));
     
     // Perform the sweep
-    for i in 0..num_points {
-        let wavelength = start_nm + (i as f64 * step_nm);
-        
+    let mut points = Vec::with_capacity(sweep.points.len());
+    let mut readings = Vec::with_capacity(sweep.points.len());
+    for (index, point) in sweep.points.iter().enumerate() {
+        let wavelength = point.value;
+        let _point = info_span!("point", setpoint = wavelength, unit = "nm").entered();
+
         // Validate wavelength is within safe range
-        if wavelength < 1527.60 || wavelength > 1570.01 {
-            return Err(io_to_vs_err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("Wavelength {:.2} nm is outside safe range (1527.60-1611.76 nm)", wavelength)
-            )));
+        if let Err(msg) = safety::check("Wavelength", "nm", &safety::N77_WAVELENGTH_NM, wavelength) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
         }
         
         // Set the laser wavelength
-        let cmd = format!(":SOURce2:WAVelength:CW {:.3}NM\n", wavelength);
-        laser.write_all(cmd.as_bytes()).map_err(io_to_vs_err)?;
+        let cmd = format!(":SOURce2:WAVelength:CW {:.3}NM", wavelength);
+        laser.write(&cmd)?;
         
         // Update power meter wavelength calibration
-        let cmd = format!("WAV {:.3}\n", wavelength);
-        power_meter.write_all(cmd.as_bytes()).map_err(io_to_vs_err)?;
+        let cmd = format!("WAV {:.3}", wavelength);
+        power_meter.write(&cmd)?;
         
-        println!("Set wavelength to {:.2} nm", wavelength);
+        info!(wavelength_nm = wavelength, "Set wavelength");
         
         // Wait for stabilization
-        thread::sleep(Duration::from_millis(stabilization_time_ms));
-        
-        // Measure power
-        power_meter.write_all(b"READ? 0\n").map_err(io_to_vs_err)?;
-        
-        let mut power_response = String::new();
-        {
-            let mut reader = BufReader::new(&*power_meter);
-            reader.read_line(&mut power_response).map_err(io_to_vs_err)?;
-        }
+        settle.wait(Probe::Power(power_meter))?;
         
-        // Parse the power value (format: "power1,power2,power3,power4")
-        let power_values: Vec<&str> = power_response.trim().split(',').collect();
-        if power_values.is_empty() {
-            return Err(io_to_vs_err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "No power readings received"
-            )));
-        }
-        
-        let power = match power_values[1].parse::<f64>() { // Save power value from the 2nd port (top to bottom)
-            Ok(value) => value,
-            Err(_) => return Err(io_to_vs_err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to parse power value: {}", power_values[1])
-            ))),
-        };
+        // Measure power, the mean when repeated
+        let stats = repeats.measure_one(|| meter::read_power_dbm(power_meter))?;
+        let power = stats.mean;
         
         // Print measured values
-        println!("  Power: {:.3} dBm", power);
+        info!(power_dbm = power, "Measured power");
         
         // Write to results file
-        writeln!(file, "{:.3},{:.6}", wavelength, power).map_err(io_to_vs_err)?;
+        let extra = format!("{}{}", repeats.columns(std::slice::from_ref(&stats)), sweep.columns(index));
+        let power = calibration::write_power_row(&mut file, calibration, wavelength, power, &extra)?;
+        samples.write(wavelength, &[stats])?;
+        points.push((wavelength, power));
+        readings.push((*point, vec![power]));
     }
     
     // Turn laser OFF
-    laser.write_all(b":SOURce2:POWer:STATe 0\n").map_err(io_to_vs_err)?;
-    println!("Laser turned OFF");
+    drivers::n77::set_output(laser, false)?;
+    info!("Laser turned OFF");
     
     // Check for errors on laser
-    laser.write_all(b"SYST:ERR?\n").map_err(io_to_vs_err)?;
-    
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&*laser);
-        reader.read_line(&mut response).map_err(io_to_vs_err)?;
-    }
+    let response = laser.query("SYST:ERR?")?;
     
-    println!("Final error check on laser: {}", response.trim());
+    info!(response = %response.trim(), "Final error check on laser");
     
     // Check for errors on power meter
-    power_meter.write_all(b"ERR?\n").map_err(io_to_vs_err)?;
-    
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&*power_meter);
-        reader.read_line(&mut response).map_err(io_to_vs_err)?;
-    }
+    let response = power_meter.query("ERR?")?;
     
-    println!("Final error check on power meter: {}", response.trim());
+    info!(response = %response.trim(), "Final error check on power meter");
     
-    println!("Wavelength sweep completed successfully");
-    println!("Results saved to wavelength_sweep_results.csv");
+    info!("Wavelength sweep completed successfully");
+    info!(file = %output_dir.join("wavelength_sweep_results.csv").display(), "Results saved");
+    sweep.write_hysteresis(&output_dir.join("wavelength_sweep_hysteresis.csv"), &["Wavelength (nm)", "Power (dBm)"], &readings)?;
     
-    Ok(())
+    Ok(points)
 }
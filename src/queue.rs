@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{info, info_span, warn};
+use crate::config;
+use crate::lock::lock_or_recover;
+
+// The queue survives server restarts through this file
+const QUEUE_FILE: &str = "queue.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// One experiment request submitted to the queue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRequest {
+    pub experiment: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub experiment: String,
+    pub params: serde_json::Value,
+    pub instruments: Vec<String>,
+    pub status: JobStatus,
+    pub submitted_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub result_path: Option<String>,
+    pub error: Option<String>,
+    /// Set when the job starts, names its transaction log
+    #[serde(default)]
+    pub run_id: Option<String>,
+    /// User who queued the job
+    #[serde(default)]
+    pub submitted_by: Option<String>,
+}
+
+/// Pending, running and finished jobs in submission order
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct JobQueue {
+    jobs: Vec<Job>,
+    next_id: u64,
+}
+
+/// Instruments currently in use, mapped to who is using them
+#[derive(Debug, Default)]
+pub struct Reservations {
+    in_use: Mutex<HashMap<String, String>>,
+}
+
+/// Releases the reserved instruments when dropped
+pub struct ReservationGuard {
+    reservations: Arc<Reservations>,
+    names: Vec<String>,
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl JobQueue {
+    /// Loads the saved queue. Jobs that were running when the server stopped are marked failed.
+    pub fn load() -> JobQueue {
+        let path = config::data_path(QUEUE_FILE);
+        let mut queue = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!(file = %path.display(), error = %e, "Failed to parse queue");
+                JobQueue::default()
+            }),
+            Err(_) => JobQueue::default(),
+        };
+
+        for job in queue.jobs.iter_mut().filter(|j| j.status == JobStatus::Running) {
+            job.status = JobStatus::Failed;
+            job.finished_at = Some(now_secs());
+            job.error = Some("Interrupted by server restart".to_string());
+        }
+        queue
+    }
+
+    pub fn save(&self) {
+        std::fs::create_dir_all(&config::get().data_dir).unwrap_or_else(|e| {
+            warn!(error = %e, "Failed to create data directory");
+        });
+        match serde_json::to_string_pretty(self) {
+            Ok(text) => fs::write(config::data_path(QUEUE_FILE), text).unwrap_or_else(|e| {
+                warn!(file = %config::data_path(QUEUE_FILE).display(), error = %e, "Failed to save queue");
+            }),
+            Err(e) => warn!(error = %e, "Failed to serialize queue"),
+        }
+    }
+
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    /// Appends a job and returns its ID
+    pub fn submit(&mut self, request: JobRequest, instruments: Vec<String>, submitted_by: &str) -> u64 {
+        self.next_id += 1;
+        self.jobs.push(Job {
+            id: self.next_id,
+            experiment: request.experiment,
+            params: request.params,
+            instruments,
+            status: JobStatus::Pending,
+            submitted_at: now_secs(),
+            started_at: None,
+            finished_at: None,
+            result_path: None,
+            error: None,
+            run_id: None,
+            submitted_by: Some(submitted_by.to_string()),
+        });
+        self.next_id
+    }
+
+    pub fn cancel(&mut self, id: u64) -> Result<(), String> {
+        let job = self.jobs.iter_mut().find(|j| j.id == id)
+            .ok_or_else(|| format!("Unknown job: {}", id))?;
+        if job.status != JobStatus::Pending {
+            return Err(format!("Job {} is not pending", id));
+        }
+        job.status = JobStatus::Cancelled;
+        job.finished_at = Some(now_secs());
+        Ok(())
+    }
+
+    /// Moves a pending job to `position` among the pending jobs (0 runs next)
+    pub fn move_to(&mut self, id: u64, position: usize) -> Result<(), String> {
+        let index = self.jobs.iter().position(|j| j.id == id)
+            .ok_or_else(|| format!("Unknown job: {}", id))?;
+        if self.jobs[index].status != JobStatus::Pending {
+            return Err(format!("Job {} is not pending", id));
+        }
+        let job = self.jobs.remove(index);
+
+        // Translate the pending position back to an index in the full list
+        let pending: Vec<usize> = self.jobs.iter().enumerate()
+            .filter(|(_, j)| j.status == JobStatus::Pending)
+            .map(|(i, _)| i)
+            .collect();
+        let target = match pending.get(position) {
+            Some(&i) => i,
+            None => self.jobs.len(),
+        };
+        self.jobs.insert(target, job);
+        Ok(())
+    }
+
+    /// Marks the first pending job as running and returns a copy of it
+    pub fn start_next(&mut self) -> Option<Job> {
+        let job = self.jobs.iter_mut().find(|j| j.status == JobStatus::Pending)?;
+        job.status = JobStatus::Running;
+        job.started_at = Some(now_secs());
+        job.run_id = Some(format!("job-{}", job.id));
+        Some(job.clone())
+    }
+
+    pub fn finish(&mut self, id: u64, result: Result<String, String>) {
+        if let Some(job) = self.jobs.iter_mut().find(|j| j.id == id) {
+            job.finished_at = Some(now_secs());
+            match result {
+                Ok(path) => {
+                    job.status = JobStatus::Completed;
+                    job.result_path = Some(path);
+                },
+                Err(err) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(err);
+                },
+            }
+        }
+    }
+}
+
+impl Reservations {
+    /// Reserves all instruments or none of them
+    pub fn try_reserve(self: &Arc<Self>, names: &[String], owner: &str) -> Result<ReservationGuard, String> {
+        let mut in_use = lock_or_recover(&self.in_use);
+
+        if let Some(busy) = names.iter().find(|n| in_use.contains_key(*n)) {
+            return Err(format!("{} is in use by {}", busy, in_use[busy]));
+        }
+        for name in names {
+            in_use.insert(name.clone(), owner.to_string());
+        }
+
+        Ok(ReservationGuard {
+            reservations: self.clone(),
+            names: names.to_vec(),
+        })
+    }
+
+    /// Reserves the instruments, waiting for running jobs to release them
+    pub async fn reserve(self: &Arc<Self>, names: &[String], owner: &str) -> ReservationGuard {
+        loop {
+            if let Ok(guard) = self.try_reserve(names, owner) {
+                return guard;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+}
+
+impl Drop for ReservationGuard {
+    fn drop(&mut self) {
+        let mut in_use = lock_or_recover(&self.reservations.in_use);
+        for name in &self.names {
+            in_use.remove(name);
+        }
+    }
+}
+
+/// Runs queued jobs one after another until the server stops.
+/// `run` executes a job on a blocking thread and returns its result path.
+pub async fn run_worker<F>(
+    queue: Arc<Mutex<JobQueue>>,
+    reservations: Arc<Reservations>,
+    notify: Arc<Notify>,
+    run: F,
+) where
+    F: Fn(Job) -> Result<String, String> + Send + Sync + Clone + 'static,
+{
+    loop {
+        let next = {
+            let mut queue = lock_or_recover(&queue);
+            let job = queue.start_next();
+            if job.is_some() {
+                queue.save();
+            }
+            job
+        };
+
+        let job = match next {
+            Some(job) => job,
+            None => {
+                notify.notified().await;
+                continue;
+            },
+        };
+
+        let id = job.id;
+        let span = info_span!("job", job_id = id, experiment = %job.experiment);
+        span.in_scope(|| info!("Starting queued job"));
+        let owner = format!("job {}", id);
+        let guard = reservations.reserve(&job.instruments, &owner).await;
+
+        let run = run.clone();
+        let job_span = span.clone();
+        let result = match tokio::task::spawn_blocking(move || job_span.in_scope(|| run(job))).await {
+            Ok(result) => result,
+            Err(err) => Err(format!("Job panicked: {}", err)),
+        };
+        drop(guard);
+
+        span.in_scope(|| match &result {
+            Ok(path) => info!(result = %path, "Job completed"),
+            Err(err) => warn!(error = %err, "Job failed"),
+        });
+
+        let mut queue = lock_or_recover(&queue);
+        queue.finish(id, result);
+        queue.save();
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::config;
+use crate::instruments;
+use crate::sweep::SweepPlan;
+
+/// A raw command sent to an instrument before the sweep starts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetupCommand {
+    pub instrument: String,
+    pub command: String,
+}
+
+/// An experiment declared in a TOML or YAML file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recipe {
+    /// Defaults to the file name without extension
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub instruments: Vec<String>,
+    #[serde(default)]
+    pub setup: Vec<SetupCommand>,
+    #[serde(flatten)]
+    pub plan: SweepPlan,
+    /// Output file name inside data/, defaults to "<name>_results.csv"
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
+/// Summary returned by /api/experiments
+#[derive(Debug, Serialize)]
+pub struct RecipeSummary {
+    pub name: String,
+    pub description: String,
+    pub instruments: Vec<String>,
+    pub file: String,
+    pub error: Option<String>,
+}
+
+impl Recipe {
+    /// Parses a recipe file, picking the format from the extension
+    pub fn load(path: &Path) -> Result<Recipe, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+        let mut recipe: Recipe = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&text)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?,
+            _ => return Err(format!("Unsupported recipe format: {}", path.display())),
+        };
+
+        if recipe.name.is_empty() {
+            recipe.name = path.file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default()
+                .to_string();
+        }
+        Ok(recipe)
+    }
+
+    /// Checks the recipe against the instrument registry and the safety limits
+    pub fn validate(&self) -> Result<(), String> {
+        for name in &self.instruments {
+            if instruments::lookup(name).is_none() {
+                return Err(format!("Unknown instrument: {}", name));
+            }
+        }
+
+        // Every instrument the sweep touches has to be declared
+        for name in self.plan.required_instruments() {
+            if !self.instruments.iter().any(|i| i == name) {
+                return Err(format!("Sweep uses {} but it is not listed in instruments", name));
+            }
+        }
+
+        for setup in &self.setup {
+            if !self.instruments.contains(&setup.instrument) {
+                return Err(format!("Setup command for {} but it is not listed in instruments", setup.instrument));
+            }
+            instruments::check_command(&setup.instrument, &setup.command)?;
+        }
+
+        if let Some(output) = &self.output
+            && (output.is_empty() || output.contains(['/', '\\']) || output.contains("..")) {
+            return Err(format!("Output must be a plain file name: {}", output));
+        }
+
+        self.plan.validate()
+    }
+
+    pub fn output_file(&self) -> String {
+        self.output.clone().unwrap_or_else(|| format!("{}_results.csv", self.name))
+    }
+}
+
+fn recipe_files() -> Vec<PathBuf> {
+    // Recipe files are looked up in the configured directory
+    let entries = match fs::read_dir(&config::get().recipe_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("toml") | Some("yaml") | Some("yml")
+        ))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Lists every recipe file, including ones that fail to load or validate
+pub fn list() -> Vec<RecipeSummary> {
+    recipe_files()
+        .iter()
+        .map(|path| {
+            let file = path.display().to_string();
+            match Recipe::load(path) {
+                Ok(recipe) => RecipeSummary {
+                    error: recipe.validate().err(),
+                    name: recipe.name,
+                    description: recipe.description,
+                    instruments: recipe.instruments,
+                    file,
+                },
+                Err(err) => RecipeSummary {
+                    name: path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string(),
+                    description: String::new(),
+                    instruments: Vec::new(),
+                    file,
+                    error: Some(err),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Finds a recipe by name and validates it
+pub fn find(name: &str) -> Result<Recipe, String> {
+    for path in recipe_files() {
+        // Skip files that don't parse, they can't match a name anyway
+        if let Ok(recipe) = Recipe::load(&path)
+            && recipe.name == name {
+            recipe.validate()?;
+            return Ok(recipe);
+        }
+    }
+    Err(format!("Unknown experiment: {}", name))
+}
@@ -0,0 +1,227 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::calibration::{self, is_valid_name, RANGE_TOLERANCE_NM};
+use crate::config;
+use crate::queue;
+
+// References are measurements, so they live with the results rather than the configuration
+const REFERENCE_DIR: &str = "references";
+
+/// How long a reference stays usable when the capture doesn't say, the source and fibers drift
+pub const DEFAULT_LIFETIME_MIN: u64 = 240;
+
+/// Lifetimes a capture may ask for, a week at most
+pub const LIFETIME_MIN: RangeInclusive<u64> = 1..=7 * 24 * 60;
+
+/// Power through the bypass path at one wavelength
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ReferencePoint {
+    pub wavelength_nm: f64,
+    pub power_dbm: f64,
+}
+
+/// A wavelength sweep through the reference path, which device sweeps are normalized against
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reference {
+    pub name: String,
+    /// Seconds since the Unix epoch
+    pub measured_at: u64,
+    pub expires_at: u64,
+    pub run_id: String,
+    /// Calibrated path the powers were corrected for, device sweeps must use the same one
+    #[serde(default)]
+    pub optical_path: Option<String>,
+    pub points: Vec<ReferencePoint>,
+}
+
+/// Summary returned by /api/references
+#[derive(Debug, Serialize)]
+pub struct ReferenceSummary {
+    pub name: String,
+    pub measured_at: u64,
+    pub expires_at: u64,
+    pub expired: bool,
+    pub run_id: String,
+    pub optical_path: Option<String>,
+    pub points: usize,
+}
+
+fn reference_file(name: &str) -> Result<PathBuf, String> {
+    if !is_valid_name(name) {
+        return Err(format!("Invalid reference name: {}", name));
+    }
+    Ok(config::data_path(REFERENCE_DIR).join(format!("{}.json", name)))
+}
+
+impl Reference {
+    /// A reference from the points of a sweep, usable for `lifetime_min` minutes
+    pub fn new(name: &str, run_id: &str, optical_path: Option<String>, points: &[(f64, f64)], lifetime_min: u64) -> Self {
+        let measured_at = queue::now_secs();
+        let mut points: Vec<ReferencePoint> = points.iter()
+            .map(|&(wavelength_nm, power_dbm)| ReferencePoint { wavelength_nm, power_dbm })
+            .collect();
+        points.sort_by(|a, b| a.wavelength_nm.total_cmp(&b.wavelength_nm));
+        Reference {
+            name: name.to_string(),
+            measured_at,
+            expires_at: measured_at.saturating_add(lifetime_min.saturating_mul(60)),
+            run_id: run_id.to_string(),
+            optical_path,
+            points,
+        }
+    }
+
+    pub fn load(name: &str) -> Result<Reference, String> {
+        let path = reference_file(name)?;
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("No reference named {} ({}): {}", name, path.display(), e))?;
+        let reference: Reference = serde_json::from_str(&text)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        if reference.points.is_empty() {
+            return Err(format!("Reference {} has no points", name));
+        }
+        Ok(reference)
+    }
+
+    /// Stores the reference, replacing any earlier one of the same name
+    pub fn save(&self) -> Result<PathBuf, String> {
+        let path = reference_file(&self.name)?;
+        fs::create_dir_all(config::data_path(REFERENCE_DIR))
+            .map_err(|e| format!("Failed to create reference directory: {}", e))?;
+        let text = serde_json::to_string_pretty(self).map_err(|e| format!("Failed to serialize reference: {}", e))?;
+        fs::write(&path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        queue::now_secs() >= self.expires_at
+    }
+
+    /// Checks a device sweep can be normalized against this reference, before any instrument is touched
+    pub fn check_usable(&self, start_nm: f64, stop_nm: f64, optical_path: Option<&str>) -> Result<(), String> {
+        if self.is_expired() {
+            return Err(format!("Reference {} expired, capture it again", self.name));
+        }
+        if self.optical_path.as_deref() != optical_path {
+            return Err(format!(
+                "Reference {} was measured with optical path {}, the sweep uses {}",
+                self.name,
+                self.optical_path.as_deref().unwrap_or("none"),
+                optical_path.unwrap_or("none"),
+            ));
+        }
+        let (min, max) = (self.points[0].wavelength_nm, self.points[self.points.len() - 1].wavelength_nm);
+        if start_nm.min(stop_nm) < min - RANGE_TOLERANCE_NM || start_nm.max(stop_nm) > max + RANGE_TOLERANCE_NM {
+            return Err(format!(
+                "Reference {} covers {:.3} to {:.3} nm, the sweep needs {:.3} to {:.3} nm",
+                self.name, min, max, start_nm.min(stop_nm), start_nm.max(stop_nm),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reference power at a wavelength, interpolated linearly between the nearest points
+    pub fn power_dbm(&self, wavelength_nm: f64) -> f64 {
+        let table: Vec<(f64, f64)> = self.points.iter().map(|p| (p.wavelength_nm, p.power_dbm)).collect();
+        calibration::interpolate(&table, wavelength_nm)
+    }
+
+    /// Writes the device's power, the reference power and their difference at each wavelength
+    pub fn write_normalized(&self, path: &Path, points: &[(f64, f64)]) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "Wavelength (nm),Power (dBm),Reference Power (dBm),Insertion Loss (dB)")?;
+        for &(wavelength, power) in points {
+            let reference = self.power_dbm(wavelength);
+            writeln!(file, "{:.3},{:.6},{:.6},{:.4}", wavelength, power, reference, power - reference)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every stored reference, including expired ones, newest first
+pub fn list() -> Vec<ReferenceSummary> {
+    let Ok(entries) = fs::read_dir(config::data_path(REFERENCE_DIR)) else {
+        return Vec::new();
+    };
+    let mut references: Vec<ReferenceSummary> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .filter_map(|path| path.file_stem().and_then(|s| s.to_str()).map(str::to_string))
+        .filter_map(|name| Reference::load(&name).ok())
+        .map(|reference| ReferenceSummary {
+            expired: reference.is_expired(),
+            name: reference.name,
+            measured_at: reference.measured_at,
+            expires_at: reference.expires_at,
+            run_id: reference.run_id,
+            optical_path: reference.optical_path,
+            points: reference.points.len(),
+        })
+        .collect();
+    references.sort_by_key(|reference| std::cmp::Reverse(reference.measured_at));
+    references
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(optical_path: Option<&str>) -> Reference {
+        let points = [(1550.0, -10.0), (1530.0, -12.0), (1570.0, -11.0)];
+        Reference::new("bypass", "run-1", optical_path.map(str::to_string), &points, DEFAULT_LIFETIME_MIN)
+    }
+
+    #[test]
+    fn new_reference_is_sorted_and_expires_after_its_lifetime() {
+        let reference = reference(None);
+        let wavelengths: Vec<f64> = reference.points.iter().map(|p| p.wavelength_nm).collect();
+        assert_eq!(wavelengths, vec![1530.0, 1550.0, 1570.0]);
+        assert_eq!(reference.expires_at - reference.measured_at, DEFAULT_LIFETIME_MIN * 60);
+        assert!(!reference.is_expired());
+    }
+
+    #[test]
+    fn huge_lifetime_saturates() {
+        let reference = Reference::new("bypass", "run-1", None, &[(1550.0, -10.0)], u64::MAX);
+        assert_eq!(reference.expires_at, u64::MAX);
+    }
+
+    #[test]
+    fn usable_within_its_range_and_path() {
+        let reference = reference(Some("bench-a"));
+        assert!(reference.check_usable(1530.0, 1570.0, Some("bench-a")).is_ok());
+        assert!(reference.check_usable(1570.0, 1530.0 - RANGE_TOLERANCE_NM / 2.0, Some("bench-a")).is_ok());
+    }
+
+    #[test]
+    fn sweep_beyond_the_reference_is_refused() {
+        let err = reference(None).check_usable(1520.0, 1560.0, None).unwrap_err();
+        assert_eq!(err, "Reference bypass covers 1530.000 to 1570.000 nm, the sweep needs 1520.000 to 1560.000 nm");
+    }
+
+    #[test]
+    fn other_optical_path_is_refused() {
+        let err = reference(Some("bench-a")).check_usable(1530.0, 1570.0, None).unwrap_err();
+        assert_eq!(err, "Reference bypass was measured with optical path bench-a, the sweep uses none");
+    }
+
+    #[test]
+    fn expired_reference_is_refused() {
+        let mut reference = reference(None);
+        reference.expires_at = reference.measured_at;
+        assert!(reference.is_expired());
+        assert_eq!(reference.check_usable(1530.0, 1570.0, None).unwrap_err(), "Reference bypass expired, capture it again");
+    }
+
+    #[test]
+    fn power_is_interpolated_between_points() {
+        let reference = reference(None);
+        assert_eq!(reference.power_dbm(1550.0), -10.0);
+        assert!((reference.power_dbm(1540.0) - -11.0).abs() < 1e-12);
+        assert!((reference.power_dbm(1565.0) - -10.75).abs() < 1e-12);
+        assert_eq!(reference.power_dbm(1500.0), -12.0);
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::Serialize;
+use crate::devices::Devices;
+use crate::transactions::{self, Direction, RunInfo, Transaction, TransactionLog};
+use crate::transport::Transport;
+use crate::lock::lock_or_recover;
+
+/// Recorded transactions still to be replayed per instrument, and everything that didn't match
+#[derive(Default)]
+struct Script {
+    remaining: HashMap<String, VecDeque<Transaction>>,
+    replayed: usize,
+    mismatches: Vec<String>,
+}
+
+/// Transport answering from a recorded run, checking that the same commands are sent in the same order
+pub struct ReplayTransport {
+    name: String,
+    script: Arc<Mutex<Script>>,
+}
+
+/// A recorded run loaded for replay, with simulated devices for its instruments
+pub struct Replay {
+    pub info: RunInfo,
+    pub devices: Devices,
+    script: Arc<Mutex<Script>>,
+}
+
+/// Outcome of replaying a run
+#[derive(Debug, Serialize)]
+pub struct ReplayReport {
+    pub success: bool,
+    pub source_run_id: String,
+    pub run_id: String,
+    pub output_dir: String,
+    pub replayed: usize,
+    /// Recorded transactions the replay never reached
+    pub unplayed: usize,
+    pub mismatches: Vec<String>,
+    pub error: Option<String>,
+}
+
+fn describe(direction: Direction, data: &str) -> String {
+    match direction {
+        Direction::Write => format!("write '{}'", data),
+        Direction::Read => "read".to_string(),
+        Direction::Clear => "clear".to_string(),
+    }
+}
+
+// Recreates a recorded failure, keeping the kind so retries behave as they did
+fn recorded_error(transaction: &Transaction) -> Option<io::Error> {
+    let message = transaction.error.clone()?;
+    let kind = match transaction.error_kind.as_deref() {
+        Some("TimedOut") => io::ErrorKind::TimedOut,
+        Some("BrokenPipe") => io::ErrorKind::BrokenPipe,
+        Some("Interrupted") => io::ErrorKind::Interrupted,
+        Some("InvalidData") => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::Other,
+    };
+    Some(io::Error::new(kind, message))
+}
+
+impl ReplayTransport {
+    fn next(&mut self, direction: Direction, data: &str) -> io::Result<Transaction> {
+        let mut script = lock_or_recover(&self.script);
+        let mismatch = match script.remaining.get_mut(&self.name).and_then(|queue| queue.pop_front()) {
+            None => format!("{}: unexpected {} after the end of the recording", self.name, describe(direction, data)),
+            Some(expected) if expected.direction != direction || (direction == Direction::Write && expected.data != data) => {
+                format!(
+                    "{}: expected {}, got {}",
+                    self.name,
+                    describe(expected.direction, &expected.data),
+                    describe(direction, data)
+                )
+            },
+            Some(expected) => {
+                script.replayed += 1;
+                return Ok(expected);
+            },
+        };
+        script.mismatches.push(mismatch.clone());
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("Replay mismatch: {}", mismatch)))
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let recorded = self.next(Direction::Write, line)?;
+        recorded_error(&recorded).map_or(Ok(()), Err)
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let recorded = self.next(Direction::Read, "")?;
+        match recorded_error(&recorded) {
+            Some(err) => Err(err),
+            None => Ok(recorded.data),
+        }
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        let recorded = self.next(Direction::Clear, "")?;
+        recorded_error(&recorded).map_or(Ok(()), Err)
+    }
+}
+
+/// Loads a recorded run and sets up simulated devices answering from its transaction log
+pub fn prepare(source_run_id: &str, transactions: Arc<TransactionLog>) -> Result<Replay, String> {
+    if !transactions::is_valid_run_id(source_run_id) {
+        return Err(format!("Invalid run id: {}", source_run_id));
+    }
+    let info = RunInfo::load(source_run_id)
+        .map_err(|err| format!("Failed to load run {}: {}", source_run_id, err))?;
+    let recorded = match transactions::read_log(&transactions::run_log_path(source_run_id)) {
+        Ok(recorded) => recorded,
+        // Runs refused before their instruments were locked never sent anything
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(format!("Failed to read transactions of run {}: {}", source_run_id, err)),
+    };
+    Replay::new(info, recorded, transactions)
+}
+
+impl Replay {
+    /// Simulated devices for the run's instruments, answering from `recorded`
+    pub fn new(info: RunInfo, recorded: Vec<Transaction>, transactions: Arc<TransactionLog>) -> Result<Replay, String> {
+        let mut script = Script::default();
+        for transaction in recorded {
+            script.remaining.entry(transaction.instrument.clone()).or_default().push_back(transaction);
+        }
+        let script = Arc::new(Mutex::new(script));
+
+        let transports: Vec<(String, Box<dyn Transport>)> = info.instruments
+            .iter()
+            .map(|name| {
+                let transport = ReplayTransport { name: name.clone(), script: script.clone() };
+                (name.clone(), Box::new(transport) as Box<dyn Transport>)
+            })
+            .collect();
+        let devices = Devices::with_transports(transports, transactions)?;
+
+        Ok(Replay { info, devices, script })
+    }
+
+    /// Compares what the replayed run did with the recording
+    pub fn finish(self, run_id: &str, output_dir: &Path, result: Result<String, String>) -> ReplayReport {
+        // Stops the simulated devices before reading what's left of the script
+        drop(self.devices);
+        let mut script = lock_or_recover(&self.script);
+
+        let unplayed: usize = script.remaining.values().map(|queue| queue.len()).sum();
+        if unplayed > 0 && result.is_ok() {
+            let mut names: Vec<&String> = script.remaining.iter()
+                .filter(|(_, queue)| !queue.is_empty())
+                .map(|(name, _)| name)
+                .collect();
+            names.sort();
+            let first = names.first().and_then(|name| script.remaining[*name].front().map(|t| (name.to_string(), t.clone())));
+            if let Some((name, transaction)) = first {
+                let mismatch = format!(
+                    "{} recorded transactions were never replayed, starting with {}: {}",
+                    unplayed, name, describe(transaction.direction, &transaction.data)
+                );
+                script.mismatches.push(mismatch);
+            }
+        }
+
+        ReplayReport {
+            success: result.is_ok() && script.mismatches.is_empty(),
+            source_run_id: self.info.run_id,
+            run_id: run_id.to_string(),
+            output_dir: output_dir.display().to_string(),
+            replayed: script.replayed,
+            unplayed,
+            mismatches: std::mem::take(&mut script.mismatches),
+            error: result.err(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::experiments;
+
+    // Sessions under tests/fixtures/replay: golden-* were recorded from the experiments against simulated
+    // instruments, manual-* were written by hand from the instrument manuals, so they also catch a command
+    // sequence that drifts from what the instruments expect
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay");
+
+    fn transaction(instrument: &str, direction: Direction, data: &str) -> Transaction {
+        Transaction {
+            timestamp_ms: 0,
+            instrument: instrument.to_string(),
+            direction,
+            data: data.to_string(),
+            latency_ms: 0.0,
+            error: None,
+            error_kind: None,
+            run_id: None,
+        }
+    }
+
+    fn run_info(instruments: &[&str]) -> RunInfo {
+        RunInfo {
+            run_id: "recorded".to_string(),
+            experiment: "current_sweep".to_string(),
+            params: serde_json::Value::Null,
+            instruments: instruments.iter().map(|name| name.to_string()).collect(),
+            started_at: 0,
+            operator: None,
+        }
+    }
+
+    // Copies a recorded session to where replays look for runs and replays it
+    fn replay_fixture(run_id: &str) -> ReplayReport {
+        config::init_for_tests();
+        let dir = transactions::run_dir(run_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in ["run.json", "transactions.jsonl"] {
+            std::fs::copy(Path::new(FIXTURES).join(run_id).join(file), dir.join(file)).unwrap();
+        }
+        experiments::replay_run(run_id, Arc::new(TransactionLog::new()), None)
+    }
+
+    fn assert_golden(report: &ReplayReport) {
+        assert_eq!(report.error, None);
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+        assert_eq!(report.unplayed, 0);
+        assert!(report.replayed > 0);
+        assert!(report.success);
+    }
+
+    #[test]
+    fn current_sweep_replays_its_recording() {
+        let report = replay_fixture("golden-current-sweep");
+        assert_golden(&report);
+        assert!(Path::new(&report.output_dir).join("current_sweep_results.csv").exists());
+    }
+
+    #[test]
+    fn random_order_replays_with_its_recorded_seed() {
+        // Recorded without a seed in the request, run.json holds the one the run was given
+        let report = replay_fixture("golden-current-sweep-random");
+        assert_golden(&report);
+    }
+
+    #[test]
+    fn wavelength_check_sends_the_commands_from_the_manuals() {
+        let report = replay_fixture("manual-wavelength-check");
+        assert_golden(&report);
+        let results = std::fs::read_to_string(Path::new(&report.output_dir).join("wavelength_check_result.csv")).unwrap();
+        assert!(results.ends_with("1550.000,-9.842000\n"), "{}", results);
+    }
+
+    #[test]
+    fn wavelength_sweep_osa_replays_its_recording() {
+        let report = replay_fixture("golden-wavelength-sweep-osa");
+        assert_golden(&report);
+        assert!(Path::new(&report.output_dir).join("wavelength_sweep_trace_results.csv").exists());
+    }
+
+    #[test]
+    fn command_out_of_order_is_a_mismatch() {
+        let mut script = Script::default();
+        script.remaining.insert("osa".to_string(), VecDeque::from([
+            transaction("osa", Direction::Write, "SNGLS;"),
+            transaction("osa", Direction::Write, "MDS?;"),
+        ]));
+        let script = Arc::new(Mutex::new(script));
+        let mut transport = ReplayTransport { name: "osa".to_string(), script: script.clone() };
+
+        let err = transport.write_line("MDS?;").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let script = lock_or_recover(&script);
+        assert_eq!(script.mismatches, vec!["osa: expected write 'SNGLS;', got write 'MDS?;'".to_string()]);
+        assert_eq!(script.replayed, 0);
+    }
+
+    #[test]
+    fn read_instead_of_write_is_a_mismatch() {
+        let mut script = Script::default();
+        script.remaining.insert("osa".to_string(), VecDeque::from([transaction("osa", Direction::Write, "TRA?;")]));
+        let script = Arc::new(Mutex::new(script));
+        let mut transport = ReplayTransport { name: "osa".to_string(), script: script.clone() };
+
+        assert!(transport.read_line().is_err());
+        assert_eq!(lock_or_recover(&script).mismatches, vec!["osa: expected write 'TRA?;', got read".to_string()]);
+    }
+
+    #[test]
+    fn recorded_errors_are_replayed() {
+        let mut failed = transaction("osa", Direction::Read, "");
+        failed.error = Some("timed out".to_string());
+        failed.error_kind = Some("TimedOut".to_string());
+        let mut script = Script::default();
+        script.remaining.insert("osa".to_string(), VecDeque::from([failed]));
+        let mut transport = ReplayTransport { name: "osa".to_string(), script: Arc::new(Mutex::new(script)) };
+
+        assert_eq!(transport.read_line().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn unplayed_transactions_fail_the_replay() {
+        config::init_for_tests();
+        let recorded = vec![
+            transaction("osa", Direction::Write, "SWEEP OFF;"),
+            transaction("osa", Direction::Write, "XERR?;"),
+            transaction("osa", Direction::Read, "0"),
+        ];
+        let replay = Replay::new(run_info(&["osa"]), recorded, Arc::new(TransactionLog::new())).unwrap();
+
+        let report = replay.finish("replayed", Path::new("out"), Ok("results.csv".to_string()));
+        assert!(!report.success);
+        assert_eq!(report.unplayed, 3);
+        assert_eq!(report.mismatches, vec![
+            "3 recorded transactions were never replayed, starting with osa: write 'SWEEP OFF;'".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn failed_run_reports_its_error_rather_than_unplayed_transactions() {
+        config::init_for_tests();
+        let recorded = vec![transaction("osa", Direction::Write, "SNGLS;")];
+        let replay = Replay::new(run_info(&["osa"]), recorded, Arc::new(TransactionLog::new())).unwrap();
+
+        let report = replay.finish("replayed", Path::new("out"), Err("Experiment failed".to_string()));
+        assert!(!report.success);
+        assert_eq!(report.unplayed, 1);
+        assert!(report.mismatches.is_empty());
+        assert_eq!(report.error.as_deref(), Some("Experiment failed"));
+    }
+}
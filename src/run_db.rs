@@ -0,0 +1,434 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use crate::config;
+use crate::queue;
+use crate::transactions::RunInfo;
+use crate::lock::lock_or_recover;
+
+// Lives next to the results it indexes, so moving the data root moves the database with it
+pub(crate) const DB_FILE: &str = "runs.db";
+
+// Each entry brings the schema from its index to the next version, applied in order
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE runs (
+        run_id TEXT PRIMARY KEY,
+        experiment TEXT NOT NULL,
+        params TEXT NOT NULL,
+        sample_id TEXT,
+        operator TEXT,
+        instruments TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER,
+        status TEXT NOT NULL,
+        output_dir TEXT,
+        output_files TEXT NOT NULL,
+        error TEXT,
+        metrics TEXT NOT NULL
+    );
+    CREATE INDEX runs_sample_id ON runs (sample_id);
+    CREATE INDEX runs_experiment ON runs (experiment);
+    CREATE INDEX runs_started_at ON runs (started_at);",
+    "CREATE TABLE samples (
+        sample_id TEXT PRIMARY KEY,
+        wafer TEXT,
+        die TEXT,
+        description TEXT,
+        fields TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        created_by TEXT
+    );",
+];
+
+/// One run as recorded in the database
+#[derive(Debug, Clone, Serialize)]
+pub struct RunRecord {
+    pub run_id: String,
+    pub experiment: String,
+    pub params: serde_json::Value,
+    pub sample_id: Option<String>,
+    pub operator: Option<String>,
+    pub instruments: Vec<String>,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    /// running, completed, failed, or imported for results older than the database
+    pub status: String,
+    pub output_dir: Option<String>,
+    pub output_files: Vec<String>,
+    pub error: Option<String>,
+    /// Figures of merit read back from the result files, e.g. threshold_current_ma
+    pub metrics: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Filters for `RunDb::search`, anything unset matches every run
+#[derive(Debug, Default, Deserialize)]
+pub struct RunFilter {
+    pub sample: Option<String>,
+    pub experiment: Option<String>,
+    pub operator: Option<String>,
+    pub status: Option<String>,
+    /// Runs started at or after this time, in seconds since the Unix epoch
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+/// Searchable index of every run, stored in SQLite below the data root
+pub struct RunDb {
+    /// Opened on first use, None until then or when disabled
+    conn: Mutex<Option<Connection>>,
+    enabled: bool,
+}
+
+pub(crate) fn db_error(err: rusqlite::Error) -> String {
+    format!("Run database error: {}", err)
+}
+
+fn open() -> Result<Connection, String> {
+    let data_dir = &config::get().data_dir;
+    fs::create_dir_all(data_dir).map_err(|err| format!("Failed to create data directory: {}", err))?;
+    let path = config::data_path(DB_FILE);
+    let created = !path.exists();
+    let conn = Connection::open(&path)
+        .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+    // The CLI and the server may write at the same time
+    conn.busy_timeout(Duration::from_secs(5)).map_err(db_error)?;
+    migrate(&conn)?;
+
+    // Results from before the database existed are indexed once, when it is created
+    if created {
+        match import_legacy(&conn) {
+            Ok(count) => info!(count, "Imported existing results into the run database"),
+            Err(err) => warn!(error = %err, "Failed to import existing results"),
+        }
+    }
+    Ok(conn)
+}
+
+fn migrate(conn: &Connection) -> Result<(), String> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).map_err(db_error)?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", migration, index + 1))
+            .map_err(|err| format!("Failed to migrate run database to version {}: {}", index + 1, err))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_else(|_| "null".to_string())
+}
+
+fn from_row(row: &Row) -> rusqlite::Result<RunRecord> {
+    let json = |index: usize| -> rusqlite::Result<serde_json::Value> {
+        let text: String = row.get(index)?;
+        Ok(serde_json::from_str(&text).unwrap_or(serde_json::Value::Null))
+    };
+    Ok(RunRecord {
+        run_id: row.get(0)?,
+        experiment: row.get(1)?,
+        params: json(2)?,
+        sample_id: row.get(3)?,
+        operator: row.get(4)?,
+        instruments: serde_json::from_value(json(5)?).unwrap_or_default(),
+        started_at: row.get(6)?,
+        finished_at: row.get(7)?,
+        status: row.get(8)?,
+        output_dir: row.get(9)?,
+        output_files: serde_json::from_value(json(10)?).unwrap_or_default(),
+        error: row.get(11)?,
+        metrics: serde_json::from_value(json(12)?).unwrap_or_default(),
+    })
+}
+
+const COLUMNS: &str = "run_id, experiment, params, sample_id, operator, instruments, started_at, \
+    finished_at, status, output_dir, output_files, error, metrics";
+
+/// The sample a run measured, as given in its parameters
+pub fn sample_id(params: &serde_json::Value) -> Option<String> {
+    params.get("sample_id").and_then(|value| value.as_str()).map(str::to_string)
+}
+
+/// Result files named in an experiment's result string, e.g. "a.csv and a_trace_data/"
+pub fn output_files(result: &str) -> Vec<String> {
+    result.split(" and ").map(|file| file.trim().to_string()).collect()
+}
+
+pub(crate) fn column(header: &[String], name: &str) -> Option<usize> {
+    header.iter().position(|column| column == name)
+}
+
+/// Column names and numeric rows of a result CSV, values that aren't numbers read as NaN
+pub fn read_csv(path: &Path) -> Option<(Vec<String>, Vec<Vec<f64>>)> {
+    let content = fs::read_to_string(path).ok()?;
+    let mut lines = content.lines();
+    let header: Vec<String> = lines.next()?.split(',').map(|column| column.trim().to_string()).collect();
+    let rows = lines
+        .map(|line| line.split(',').map(|value| value.trim().parse().unwrap_or(f64::NAN)).collect())
+        .filter(|row: &Vec<f64>| row.len() == header.len())
+        .collect();
+    Some((header, rows))
+}
+
+/// Figures of merit of one result CSV, found by its column names
+pub fn csv_metrics(path: &Path) -> serde_json::Map<String, serde_json::Value> {
+    let mut metrics = serde_json::Map::new();
+    let Some((header, rows)) = read_csv(path) else {
+        return metrics;
+    };
+    metrics.insert("points".to_string(), rows.len().into());
+
+    // Row with the largest finite value in a column
+    let max_row = |index: usize| {
+        rows.iter()
+            .filter(|row| row[index].is_finite())
+            .max_by(|a, b| a[index].total_cmp(&b[index]))
+    };
+    let mut insert = |name: &str, value: f64| {
+        if value.is_finite() {
+            metrics.insert(name.to_string(), value.into());
+        }
+    };
+
+    let wavelength = column(&header, "Laser Wavelength (nm)").or_else(|| column(&header, "Wavelength (nm)"));
+    let current = column(&header, "Current (mA)");
+    // OSA results have a peak per row, power meter results a single power
+    let peak_power = column(&header, "Peak Power (dBm)");
+    if let Some(row) = peak_power.and_then(max_row) {
+        insert("peak_power_dbm", row[peak_power.unwrap()]);
+        if let Some(peak_wavelength) = column(&header, "Peak Wavelength (nm)") {
+            insert("peak_wavelength_nm", row[peak_wavelength]);
+        }
+    }
+    let power = column(&header, "Power (dBm)");
+    if let Some(row) = power.filter(|_| peak_power.is_none()).and_then(max_row) {
+        insert("max_power_dbm", row[power.unwrap()]);
+        if let Some(wavelength) = wavelength {
+            insert("max_power_wavelength_nm", row[wavelength]);
+        }
+    }
+
+    // Threshold of an L-I curve is where the slope of linear power jumps the most
+    if let (Some(current), Some(power)) = (current, peak_power.or(power)) {
+        let curve: Vec<(f64, f64)> = rows.iter()
+            .map(|row| (row[current], 10f64.powf(row[power] / 10.0)))
+            .filter(|(i, p)| i.is_finite() && p.is_finite())
+            .collect();
+        let slopes: Vec<f64> = curve.windows(2)
+            .map(|pair| (pair[1].1 - pair[0].1) / (pair[1].0 - pair[0].0))
+            .collect();
+        let threshold = slopes.windows(2)
+            .enumerate()
+            .filter(|(_, pair)| (pair[1] - pair[0]).is_finite())
+            .max_by(|(_, a), (_, b)| (a[1] - a[0]).total_cmp(&(b[1] - b[0])))
+            .map(|(index, _)| curve[index + 1].0);
+        if let Some(threshold) = threshold {
+            insert("threshold_current_ma", threshold);
+        }
+    }
+    metrics
+}
+
+// Metrics of every CSV a run wrote, named by file when there is more than one
+fn run_metrics(output_dir: &Path, files: &[String]) -> serde_json::Map<String, serde_json::Value> {
+    let csvs: Vec<&String> = files.iter().filter(|file| file.ends_with(".csv")).collect();
+    let mut metrics = serde_json::Map::new();
+    for file in &csvs {
+        let file_metrics = csv_metrics(&output_dir.join(file));
+        if csvs.len() == 1 {
+            return file_metrics;
+        }
+        metrics.insert(file.to_string(), file_metrics.into());
+    }
+    metrics
+}
+
+// The built-in experiment that writes a result file, for results older than the database
+fn guess_experiment(stem: &str) -> String {
+    match stem {
+        "current_sweep_results" => "current_sweep",
+        "wavelength_check_result" => "wavelength_check",
+        "wavelength_sweep_results" => "wavelength_sweep",
+        "wavelength_sweep_trace_results" => "wavelength_sweep_osa",
+        "sweep_results" => "sweep",
+        "stability_results" => "stability",
+        other => other,
+    }.to_string()
+}
+
+/// Indexes run logs and result CSVs written before the database existed, skipping any already indexed
+fn import_legacy(conn: &Connection) -> Result<usize, String> {
+    let data_dir = &config::get().data_dir;
+    let mut count = 0;
+
+    // Run logs know everything but their result files
+    if let Ok(entries) = fs::read_dir(config::data_path("runs")) {
+        for entry in entries.flatten() {
+            let Ok(content) = fs::read_to_string(entry.path().join("run.json")) else {
+                continue;
+            };
+            let Ok(info) = serde_json::from_str::<RunInfo>(&content) else {
+                continue;
+            };
+            count += conn.execute(
+                &format!("INSERT OR IGNORE INTO runs ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, NULL, 'imported', NULL, '[]', NULL, '{{}}')", COLUMNS),
+                params![
+                    info.run_id,
+                    info.experiment,
+                    to_json(&info.params),
+                    sample_id(&info.params),
+                    info.operator,
+                    to_json(&info.instruments),
+                    info.started_at,
+                ],
+            ).map_err(db_error)?;
+        }
+    }
+
+    // Result files only know their name and when they were written
+    let entries = fs::read_dir(data_dir).map_err(|err| format!("Failed to read {}: {}", data_dir.display(), err))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "csv") {
+            continue;
+        }
+        let (Some(stem), Some(name)) = (path.file_stem().and_then(|s| s.to_str()), path.file_name().and_then(|s| s.to_str())) else {
+            continue;
+        };
+        let modified = entry.metadata()
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        count += conn.execute(
+            &format!("INSERT OR IGNORE INTO runs ({}) VALUES (?1, ?2, 'null', NULL, NULL, '[]', ?3, ?3, 'imported', ?4, ?5, NULL, ?6)", COLUMNS),
+            params![
+                format!("import-{}", stem),
+                guess_experiment(stem),
+                modified,
+                data_dir.to_string_lossy(),
+                to_json(&vec![name]),
+                to_json(&csv_metrics(&path)),
+            ],
+        ).map_err(db_error)?;
+    }
+    Ok(count)
+}
+
+impl RunDb {
+    pub fn new() -> Self {
+        RunDb { conn: Mutex::new(None), enabled: true }
+    }
+
+    /// Records nothing, for replays whose runs are not new results
+    pub fn disabled() -> Self {
+        RunDb { conn: Mutex::new(None), enabled: false }
+    }
+
+    /// False for the database of replays, which records nothing
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn with_conn<T>(&self, f: impl FnOnce(&Connection) -> Result<T, String>) -> Result<Option<T>, String> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let mut conn = lock_or_recover(&self.conn);
+        if conn.is_none() {
+            *conn = Some(open()?);
+        }
+        f(conn.as_ref().unwrap()).map(Some)
+    }
+
+    /// Records a run as it starts
+    pub fn start(&self, info: &RunInfo, output_dir: &Path) -> Result<(), String> {
+        self.with_conn(|conn| {
+            conn.execute(
+                &format!("INSERT OR REPLACE INTO runs ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, NULL, 'running', ?8, '[]', NULL, '{{}}')", COLUMNS),
+                params![
+                    info.run_id,
+                    info.experiment,
+                    to_json(&info.params),
+                    sample_id(&info.params),
+                    info.operator,
+                    to_json(&info.instruments),
+                    info.started_at,
+                    output_dir.to_string_lossy(),
+                ],
+            ).map_err(db_error)
+        }).map(|_| ())
+    }
+
+    /// Records how a run ended, reading its metrics from the result files it wrote
+    pub fn finish(&self, run_id: &str, output_dir: &Path, result: &Result<String, String>) -> Result<(), String> {
+        let (status, files, error) = match result {
+            Ok(files) => ("completed", output_files(files), None),
+            Err(err) => ("failed", Vec::new(), Some(err.as_str())),
+        };
+        self.with_conn(|conn| {
+            conn.execute(
+                "UPDATE runs SET finished_at = ?2, status = ?3, output_files = ?4, error = ?5, metrics = ?6 WHERE run_id = ?1",
+                params![
+                    run_id,
+                    queue::now_secs(),
+                    status,
+                    to_json(&files),
+                    error,
+                    to_json(&run_metrics(output_dir, &files)),
+                ],
+            ).map_err(db_error)
+        }).map(|_| ())
+    }
+
+    /// Runs matching every filter given, newest first
+    pub fn search(&self, filter: &RunFilter) -> Result<Vec<RunRecord>, String> {
+        let records = self.with_conn(|conn| {
+            let mut statement = conn.prepare(&format!(
+                "SELECT {} FROM runs
+                 WHERE (?1 IS NULL OR sample_id = ?1)
+                   AND (?2 IS NULL OR experiment = ?2)
+                   AND (?3 IS NULL OR operator = ?3)
+                   AND (?4 IS NULL OR status = ?4)
+                   AND (?5 IS NULL OR started_at >= ?5)
+                   AND (?6 IS NULL OR started_at <= ?6)
+                 ORDER BY started_at DESC
+                 LIMIT ?7",
+                COLUMNS,
+            )).map_err(db_error)?;
+            let rows = statement.query_map(
+                params![
+                    filter.sample,
+                    filter.experiment,
+                    filter.operator,
+                    filter.status,
+                    filter.since,
+                    filter.until,
+                    filter.limit.unwrap_or(100),
+                ],
+                from_row,
+            ).map_err(db_error)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+        })?;
+        Ok(records.unwrap_or_default())
+    }
+
+    pub fn get(&self, run_id: &str) -> Result<Option<RunRecord>, String> {
+        let record = self.with_conn(|conn| {
+            conn.query_row(&format!("SELECT {} FROM runs WHERE run_id = ?1", COLUMNS), [run_id], from_row)
+                .optional()
+                .map_err(db_error)
+        })?;
+        Ok(record.flatten())
+    }
+
+    /// Indexes results written before the database existed, returning how many were added
+    pub fn import_legacy(&self) -> Result<usize, String> {
+        self.with_conn(import_legacy).map(|count| count.unwrap_or(0))
+    }
+}
@@ -0,0 +1,20 @@
+use std::ops::RangeInclusive;
+
+// Safe operating ranges for the lab setup
+pub const CLD1015_CURRENT_MA: RangeInclusive<f64> = 0.0..=100.0;
+pub const CLD1015_TEMPERATURE_C: RangeInclusive<f64> = 15.0..=35.0;
+pub const N77_WAVELENGTH_NM: RangeInclusive<f64> = 1527.60..=1570.01;
+pub const N77_POWER_DBM: RangeInclusive<f64> = -10.0..=10.0;
+pub const OSA_WAVELENGTH_NM: RangeInclusive<f64> = 600.0..=1700.0;
+
+/// Checks a value against a safe range, naming the quantity in the error
+pub fn check(name: &str, unit: &str, range: &RangeInclusive<f64>, value: f64) -> Result<(), String> {
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} {:.2} {} is outside safe range ({:.2}-{:.2} {})",
+            name, value, unit, range.start(), range.end(), unit
+        ))
+    }
+}
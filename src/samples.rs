@@ -0,0 +1,220 @@
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::{Deserialize, Serialize};
+use crate::queue;
+use crate::run_db::{self, RunDb, RunFilter, RunRecord};
+use crate::transactions;
+
+/// A chip or device under test that runs are recorded against
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    pub sample_id: String,
+    pub wafer: Option<String>,
+    pub die: Option<String>,
+    pub description: Option<String>,
+    /// Anything else worth knowing about the sample, e.g. cavity length or coating
+    pub fields: serde_json::Map<String, serde_json::Value>,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    pub created_by: Option<String>,
+}
+
+/// What can be set when registering or editing a sample
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SampleDetails {
+    pub wafer: Option<String>,
+    pub die: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+/// One past run of a sample with the data of its main result file
+#[derive(Debug, Clone, Serialize)]
+pub struct SampleRun {
+    pub run: RunRecord,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<f64>>,
+}
+
+const COLUMNS: &str = "sample_id, wafer, die, description, fields, created_at, created_by";
+
+fn from_row(row: &Row) -> rusqlite::Result<Sample> {
+    let fields: String = row.get(4)?;
+    Ok(Sample {
+        sample_id: row.get(0)?,
+        wafer: row.get(1)?,
+        die: row.get(2)?,
+        description: row.get(3)?,
+        fields: serde_json::from_str(&fields).unwrap_or_default(),
+        created_at: row.get(5)?,
+        created_by: row.get(6)?,
+    })
+}
+
+fn find(conn: &Connection, sample_id: &str) -> Result<Option<Sample>, String> {
+    conn.query_row(&format!("SELECT {} FROM samples WHERE sample_id = ?1", COLUMNS), [sample_id], from_row)
+        .optional()
+        .map_err(run_db::db_error)
+}
+
+/// Sample ids appear in file names and URLs, so only allow plain names
+pub fn is_valid_sample_id(sample_id: &str) -> bool {
+    !sample_id.is_empty()
+        && sample_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+impl RunDb {
+    /// Registers a sample, the id must not be taken
+    pub fn add_sample(&self, sample_id: &str, details: SampleDetails, created_by: Option<&str>) -> Result<Sample, String> {
+        if !is_valid_sample_id(sample_id) {
+            return Err(format!("Invalid sample id: {}", sample_id));
+        }
+        self.with_conn(|conn| {
+            if find(conn, sample_id)?.is_some() {
+                return Err(format!("Sample {} already exists", sample_id));
+            }
+            conn.execute(
+                &format!("INSERT INTO samples ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)", COLUMNS),
+                params![
+                    sample_id,
+                    details.wafer,
+                    details.die,
+                    details.description,
+                    run_db::to_json(&details.fields),
+                    queue::now_secs(),
+                    created_by,
+                ],
+            ).map_err(run_db::db_error)?;
+            find(conn, sample_id)?.ok_or_else(|| format!("Sample {} was not saved", sample_id))
+        })?.ok_or_else(|| "The run database is disabled".to_string())
+    }
+
+    /// Replaces a sample's details, its id and creation stay as they are
+    pub fn update_sample(&self, sample_id: &str, details: SampleDetails) -> Result<Sample, String> {
+        self.with_conn(|conn| {
+            let changed = conn.execute(
+                "UPDATE samples SET wafer = ?2, die = ?3, description = ?4, fields = ?5 WHERE sample_id = ?1",
+                params![
+                    sample_id,
+                    details.wafer,
+                    details.die,
+                    details.description,
+                    run_db::to_json(&details.fields),
+                ],
+            ).map_err(run_db::db_error)?;
+            if changed == 0 {
+                return Err(format!("Unknown sample: {}", sample_id));
+            }
+            find(conn, sample_id)?.ok_or_else(|| format!("Unknown sample: {}", sample_id))
+        })?.ok_or_else(|| "The run database is disabled".to_string())
+    }
+
+    pub fn get_sample(&self, sample_id: &str) -> Result<Option<Sample>, String> {
+        Ok(self.with_conn(|conn| find(conn, sample_id))?.flatten())
+    }
+
+    /// Every registered sample, by id
+    pub fn list_samples(&self) -> Result<Vec<Sample>, String> {
+        let samples = self.with_conn(|conn| {
+            let mut statement = conn.prepare(&format!("SELECT {} FROM samples ORDER BY sample_id", COLUMNS))
+                .map_err(run_db::db_error)?;
+            let rows = statement.query_map([], from_row).map_err(run_db::db_error)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>().map_err(run_db::db_error)
+        })?;
+        Ok(samples.unwrap_or_default())
+    }
+
+    /// Checks that experiment parameters name a registered sample, returning its id
+    pub fn require_sample(&self, params: &serde_json::Value) -> Result<Option<String>, String> {
+        // Replays re-run old results and may predate samples
+        if !self.enabled() {
+            return Ok(None);
+        }
+        let sample_id = run_db::sample_id(params)
+            .ok_or_else(|| "Select a sample: the parameters need a sample_id".to_string())?;
+        match self.get_sample(&sample_id)? {
+            Some(_) => Ok(Some(sample_id)),
+            None => Err(format!("Unknown sample: {}, register it first", sample_id)),
+        }
+    }
+
+    /// A sample's runs oldest first, each with the rows of its first result CSV, for comparing them over time
+    pub fn sample_history(&self, sample_id: &str, experiment: Option<String>) -> Result<Vec<SampleRun>, String> {
+        let mut runs = self.search(&RunFilter {
+            sample: Some(sample_id.to_string()),
+            experiment,
+            limit: Some(u32::MAX),
+            ..Default::default()
+        })?;
+        runs.reverse();
+
+        Ok(runs.into_iter().map(|run| {
+            // Results in the data root are overwritten by the next run, the run directory keeps a copy
+            let table = run.output_files.iter()
+                .find(|file| file.ends_with(".csv"))
+                .and_then(|file| {
+                    let archived = transactions::run_dir(&run.run_id).join(file);
+                    let path = match (&run.output_dir, archived.exists()) {
+                        (Some(dir), false) => std::path::Path::new(dir).join(file),
+                        _ => archived,
+                    };
+                    run_db::read_csv(&path)
+                });
+            let (columns, rows) = table.unwrap_or_default();
+            SampleRun { run, columns, rows }
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::LazyLock;
+    use serde_json::json;
+
+    // One database for every test, so they don't race to create it, and none left by an earlier process
+    static DB: LazyLock<RunDb> = LazyLock::new(|| {
+        crate::config::init_for_tests();
+        let _ = std::fs::remove_file(crate::config::data_path(run_db::DB_FILE));
+        RunDb::new()
+    });
+
+    #[test]
+    fn only_plain_sample_ids_are_valid() {
+        assert!(is_valid_sample_id("W12-D3_a.1"));
+        for sample_id in ["", "../chip", "chip 1", "chip/1", "chip?"] {
+            assert!(!is_valid_sample_id(sample_id), "{}", sample_id);
+        }
+        assert_eq!(DB.add_sample("chip/1", SampleDetails::default(), None).unwrap_err(), "Invalid sample id: chip/1");
+    }
+
+    #[test]
+    fn sample_is_registered_once() {
+        let details = SampleDetails { wafer: Some("W12".to_string()), ..Default::default() };
+        let sample = DB.add_sample("registered-once", details.clone(), Some("alice")).unwrap();
+        assert_eq!((sample.wafer.as_deref(), sample.created_by.as_deref()), (Some("W12"), Some("alice")));
+        assert_eq!(DB.add_sample("registered-once", details, None).unwrap_err(), "Sample registered-once already exists");
+    }
+
+    #[test]
+    fn unknown_sample_cannot_be_updated() {
+        let err = DB.update_sample("never-registered", SampleDetails::default()).unwrap_err();
+        assert_eq!(err, "Unknown sample: never-registered");
+    }
+
+    #[test]
+    fn runs_need_a_registered_sample() {
+        DB.add_sample("required", SampleDetails::default(), None).unwrap();
+        assert_eq!(DB.require_sample(&json!({ "sample_id": "required" })).unwrap(), Some("required".to_string()));
+        assert!(DB.require_sample(&json!({})).unwrap_err().starts_with("Select a sample"));
+        assert_eq!(
+            DB.require_sample(&json!({ "sample_id": "unregistered" })).unwrap_err(),
+            "Unknown sample: unregistered, register it first",
+        );
+    }
+
+    #[test]
+    fn replays_need_no_sample() {
+        assert_eq!(RunDb::disabled().require_sample(&json!({})).unwrap(), None);
+    }
+}
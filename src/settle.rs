@@ -0,0 +1,188 @@
+use std::io;
+use std::ops::RangeInclusive;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use tracing::{info, warn};
+use crate::actor::InstrumentHandle;
+use crate::drivers::{osa, power_meter};
+
+/// Dwell and stabilization times a request may ask for
+pub const WAIT_MS: RangeInclusive<u64> = 0..=60_000;
+/// Time between readings while settling adaptively
+pub const INTERVAL_MS: RangeInclusive<u64> = 50..=10_000;
+/// Longest adaptive settling before the point is measured anyway
+pub const TIMEOUT_MS: RangeInclusive<u64> = 100..=600_000;
+
+const DEFAULT_INTERVAL_MS: u64 = 500;
+const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+
+/// Adaptive settling as a request asks for it, next to its dwell or stabilization time
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct SettleParams {
+    /// Settles adaptively until consecutive readings differ by at most this, in dB or nm
+    #[serde(default)]
+    pub settle_tolerance: Option<f64>,
+    #[serde(default)]
+    pub settle_interval_ms: Option<u64>,
+    #[serde(default)]
+    pub settle_timeout_ms: Option<u64>,
+}
+
+/// Settling between readings until they agree
+#[derive(Debug, Clone, Copy)]
+pub struct Adaptive {
+    pub tolerance: f64,
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+/// How long to wait after changing a setpoint before measuring
+#[derive(Debug, Clone, Copy)]
+pub struct Settle {
+    /// Fixed wait, the minimum before polling when adaptive
+    pub wait_ms: u64,
+    pub adaptive: Option<Adaptive>,
+}
+
+/// What is polled while settling adaptively
+#[derive(Clone, Copy)]
+pub enum Probe<'a> {
+    /// Power meter reading in dBm
+    Power(&'a InstrumentHandle),
+    /// OSA peak wavelength in nm, one sweep per reading
+    PeakWavelength(&'a InstrumentHandle),
+}
+
+fn check_range(name: &str, range: &RangeInclusive<u64>, value: u64) -> Result<u64, String> {
+    if !range.contains(&value) {
+        return Err(format!("{} {} ms is outside {}..={} ms", name, value, range.start(), range.end()));
+    }
+    Ok(value)
+}
+
+impl SettleParams {
+    /// Checks the request's timing against the bounds. `wait_ms` is the request's dwell or stabilization time,
+    /// `default_ms` applies without one, unless settling adaptively where no fixed wait is needed.
+    pub fn resolve(&self, name: &str, wait_ms: Option<u64>, default_ms: u64) -> Result<Settle, String> {
+        let adaptive = match self.settle_tolerance {
+            Some(tolerance) => {
+                if !(tolerance > 0.0 && tolerance.is_finite()) {
+                    return Err(format!("settle_tolerance must be positive, got {}", tolerance));
+                }
+                Some(Adaptive {
+                    tolerance,
+                    interval_ms: check_range("settle_interval_ms", &INTERVAL_MS, self.settle_interval_ms.unwrap_or(DEFAULT_INTERVAL_MS))?,
+                    timeout_ms: check_range("settle_timeout_ms", &TIMEOUT_MS, self.settle_timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS))?,
+                })
+            },
+            None if self.settle_interval_ms.is_some() || self.settle_timeout_ms.is_some() => {
+                return Err("settle_interval_ms and settle_timeout_ms need a settle_tolerance".to_string());
+            },
+            None => None,
+        };
+        let default_ms = if adaptive.is_some() { 0 } else { default_ms };
+        Ok(Settle {
+            wait_ms: check_range(name, &WAIT_MS, wait_ms.unwrap_or(default_ms))?,
+            adaptive,
+        })
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.settle_tolerance.is_some() || self.settle_interval_ms.is_some() || self.settle_timeout_ms.is_some()
+    }
+}
+
+impl Settle {
+    /// Shortest time one settling takes when each probe reading takes `read_s`, for run estimates
+    pub fn min_s(&self, read_s: f64) -> f64 {
+        let polls_s = self.adaptive.map_or(0.0, |adaptive| adaptive.interval_ms as f64 / 1000.0 + 2.0 * read_s);
+        self.wait_ms as f64 / 1000.0 + polls_s
+    }
+
+    /// Waits the fixed time, then when adaptive reads the probe until two consecutive readings agree
+    /// within the tolerance. Returns whether the readings settled, a timeout only logs a warning.
+    pub fn wait(&self, probe: Probe) -> io::Result<bool> {
+        thread::sleep(Duration::from_millis(self.wait_ms));
+        let Some(adaptive) = self.adaptive else {
+            return Ok(true);
+        };
+
+        let started = Instant::now();
+        let timeout = Duration::from_millis(adaptive.timeout_ms);
+        let mut previous = probe.read()?;
+        loop {
+            thread::sleep(Duration::from_millis(adaptive.interval_ms));
+            let reading = probe.read()?;
+            if (reading - previous).abs() <= adaptive.tolerance {
+                info!(reading, elapsed_ms = started.elapsed().as_millis() as u64, "Settled");
+                return Ok(true);
+            }
+            if started.elapsed() >= timeout {
+                warn!(reading, change = reading - previous, timeout_ms = adaptive.timeout_ms, "Did not settle, measuring anyway");
+                return Ok(false);
+            }
+            previous = reading;
+        }
+    }
+}
+
+impl Probe<'_> {
+    fn read(self) -> io::Result<f64> {
+        match self {
+            Probe::Power(meter) => power_meter::read_power_dbm(meter),
+            Probe::PeakWavelength(analyzer) => {
+                osa::take_sweep(analyzer)?;
+                Ok(osa::read_peak(analyzer)?.0)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adaptive(tolerance: f64) -> SettleParams {
+        SettleParams { settle_tolerance: Some(tolerance), ..Default::default() }
+    }
+
+    #[test]
+    fn fixed_wait_uses_the_request_or_the_default() {
+        let settle = SettleParams::default().resolve("dwell_ms", None, 200).unwrap();
+        assert_eq!(settle.wait_ms, 200);
+        assert!(settle.adaptive.is_none());
+        assert_eq!(SettleParams::default().resolve("dwell_ms", Some(50), 200).unwrap().wait_ms, 50);
+    }
+
+    #[test]
+    fn adaptive_settling_needs_no_fixed_wait() {
+        let settle = adaptive(0.01).resolve("dwell_ms", None, 200).unwrap();
+        assert_eq!(settle.wait_ms, 0);
+        let adaptive = settle.adaptive.unwrap();
+        assert_eq!((adaptive.tolerance, adaptive.interval_ms, adaptive.timeout_ms), (0.01, DEFAULT_INTERVAL_MS, DEFAULT_TIMEOUT_MS));
+    }
+
+    #[test]
+    fn out_of_range_timing_is_refused() {
+        let err = SettleParams::default().resolve("dwell_ms", Some(60_001), 200).unwrap_err();
+        assert_eq!(err, "dwell_ms 60001 ms is outside 0..=60000 ms");
+        let fast = SettleParams { settle_interval_ms: Some(10), ..adaptive(0.01) };
+        assert!(fast.resolve("dwell_ms", None, 200).unwrap_err().starts_with("settle_interval_ms"));
+        let long = SettleParams { settle_timeout_ms: Some(600_001), ..adaptive(0.01) };
+        assert!(long.resolve("dwell_ms", None, 200).unwrap_err().starts_with("settle_timeout_ms"));
+    }
+
+    #[test]
+    fn tolerance_must_be_positive() {
+        for tolerance in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(adaptive(tolerance).resolve("dwell_ms", None, 200).is_err(), "{}", tolerance);
+        }
+    }
+
+    #[test]
+    fn interval_and_timeout_need_a_tolerance() {
+        let params = SettleParams { settle_timeout_ms: Some(1000), ..Default::default() };
+        assert_eq!(params.resolve("dwell_ms", None, 200).unwrap_err(), "settle_interval_ms and settle_timeout_ms need a settle_tolerance");
+    }
+}
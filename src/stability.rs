@@ -0,0 +1,322 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use crate::{drivers, safety};
+use crate::actor::InstrumentHandle;
+use crate::drivers::power_meter;
+use crate::live::Publisher;
+use crate::stats::Stats;
+
+/// Time between readings a request may ask for
+pub const INTERVAL_S: RangeInclusive<f64> = 0.5..=3600.0;
+
+// Rows are flushed to disk after this many readings or this long, whichever comes first,
+// so a crash or power cut loses at most one chunk of an hours long run
+const CHUNK_POINTS: usize = 20;
+const CHUNK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Same OSA window as the current sweep, around the CLD1015's laser diode
+const OSA_CENTER_NM: f64 = 974.7;
+const OSA_SPAN_NM: f64 = 2.0;
+
+/// What is held constant and what is read
+#[derive(Clone, Copy)]
+pub enum Source<'a> {
+    /// N77 at a fixed wavelength into the power meter
+    PowerMeter { laser: &'a InstrumentHandle, meter: &'a InstrumentHandle, wavelength_nm: f64 },
+    /// CLD1015 at a fixed current, peak from the OSA
+    Osa { cld1015: &'a InstrumentHandle, osa: &'a InstrumentHandle, current_ma: f64 },
+}
+
+/// When readings are taken
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    pub interval_s: f64,
+    pub duration_s: f64,
+    /// Wait after switching the laser on, before the first reading
+    pub warmup_ms: u64,
+}
+
+impl Source<'_> {
+    /// Result columns after the time columns
+    pub fn quantities(&self) -> &'static [&'static str] {
+        match self {
+            Source::PowerMeter { .. } => &["Power (dBm)"],
+            Source::Osa { .. } => &["Peak Wavelength (nm)", "Peak Power (dBm)"],
+        }
+    }
+
+    fn start(&self) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        match *self {
+            Source::PowerMeter { laser, meter, wavelength_nm } => {
+                safety::check("Wavelength", "nm", &safety::N77_WAVELENGTH_NM, wavelength_nm).map_err(invalid)?;
+                drivers::n77::configure(laser)?;
+                power_meter::configure(meter)?;
+                drivers::n77::set_wavelength_nm(laser, wavelength_nm)?;
+                power_meter::set_wavelength_nm(meter, wavelength_nm)?;
+                drivers::n77::set_output(laser, true)?;
+            },
+            Source::Osa { cld1015, osa, current_ma } => {
+                safety::check("Current", "mA", &safety::CLD1015_CURRENT_MA, current_ma).map_err(invalid)?;
+                drivers::cld1015::configure(cld1015)?;
+                drivers::osa::configure(osa, OSA_CENTER_NM, OSA_SPAN_NM)?;
+                // TEC first so the diode never runs unregulated
+                drivers::cld1015::set_tec_output(cld1015, true)?;
+                thread::sleep(Duration::from_millis(100));
+                drivers::cld1015::set_current_ma(cld1015, current_ma)?;
+                drivers::cld1015::set_laser_output(cld1015, true)?;
+            },
+        }
+        info!("Laser turned ON");
+        Ok(())
+    }
+
+    fn read(&self) -> io::Result<Vec<f64>> {
+        match *self {
+            Source::PowerMeter { meter, .. } => Ok(vec![power_meter::read_power_dbm(meter)?]),
+            Source::Osa { osa, .. } => {
+                drivers::osa::take_sweep(osa)?;
+                let (wavelength_nm, power_dbm) = drivers::osa::read_peak(osa)?;
+                Ok(vec![wavelength_nm, power_dbm])
+            },
+        }
+    }
+
+    fn stop(&self) -> io::Result<()> {
+        match *self {
+            Source::PowerMeter { laser, meter, .. } => {
+                drivers::n77::set_output(laser, false)?;
+                info!("Laser turned OFF");
+                info!(response = %drivers::n77::read_error(laser)?, "Final error check on laser");
+                info!(response = %power_meter::read_error(meter)?, "Final error check on power meter");
+            },
+            Source::Osa { cld1015, osa, .. } => {
+                drivers::cld1015::set_laser_output(cld1015, false)?;
+                info!("Laser turned OFF");
+                drivers::osa::sweep_off(osa)?;
+                info!(response = %drivers::cld1015::read_error(cld1015)?, "Final error check on CLD1015");
+                info!(response = %drivers::osa::read_error(osa)?, "Final error check on OSA");
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Number of readings a schedule takes, the first at time 0
+pub fn expected_points(interval_s: f64, duration_s: f64) -> usize {
+    if !(interval_s > 0.0 && duration_s >= 0.0) {
+        return 0;
+    }
+    (duration_s / interval_s).floor() as usize + 1
+}
+
+/// Holds the source constant and reads it every interval for the duration, streaming each reading to `live`.
+/// Writes the time series, then its Allan deviation and drift.
+pub fn run_stability(
+    source: Source,
+    schedule: &Schedule,
+    live: &Publisher,
+    output_dir: &Path,
+) -> io::Result<()> {
+    // Create a CSV file to save results
+    std::fs::create_dir_all(output_dir).unwrap_or_else(|e| {
+        warn!(error = %e, "Failed to create data directory");
+    });
+    let mut file = BufWriter::new(File::create(output_dir.join("stability_results.csv"))?);
+    writeln!(file, "Elapsed (s),Time (unix s),{}", source.quantities().join(","))?;
+    sync(&mut file)?;
+    info!(
+        points = expected_points(schedule.interval_s, schedule.duration_s),
+        interval_s = schedule.interval_s,
+        "Starting stability monitoring",
+    );
+
+    let result = source.start().and_then(|_| {
+        thread::sleep(Duration::from_millis(schedule.warmup_ms));
+        monitor(source, schedule, live, &mut file)
+    });
+
+    // Always try to leave the laser off, even if the monitoring failed
+    let stopped = source.stop();
+    sync(&mut file)?;
+    let rows = result?;
+    stopped?;
+    info!(points = rows.len(), file = %output_dir.join("stability_results.csv").display(), "Results saved");
+
+    write_analysis(&rows, source.quantities(), schedule.interval_s, output_dir)
+}
+
+// Reads on schedule until the duration is up, returning every (elapsed, values) row
+fn monitor(
+    source: Source,
+    schedule: &Schedule,
+    live: &Publisher,
+    file: &mut BufWriter<File>,
+) -> io::Result<Vec<(f64, Vec<f64>)>> {
+    let interval = Duration::from_secs_f64(schedule.interval_s);
+    let started = Instant::now();
+    let end = started + Duration::from_secs_f64(schedule.duration_s);
+    let mut rows = Vec::new();
+    let mut unsynced = 0;
+    let mut last_sync = Instant::now();
+    let mut next = started;
+
+    while next <= end {
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        }
+
+        let values = source.read()?;
+        let elapsed_s = started.elapsed().as_secs_f64();
+        let unix_s = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+        let columns: Vec<String> = values.iter().map(|v| format!("{:.6}", v)).collect();
+        writeln!(file, "{:.3},{:.3},{}", elapsed_s, unix_s, columns.join(","))?;
+        live.push([vec![elapsed_s], values.clone()].concat());
+        rows.push((elapsed_s, values));
+
+        unsynced += 1;
+        if unsynced >= CHUNK_POINTS || last_sync.elapsed() >= CHUNK_INTERVAL {
+            sync(file)?;
+            unsynced = 0;
+            last_sync = Instant::now();
+        }
+
+        // A reading that overran its slot skips the slots it missed rather than bunching readings up
+        next += interval;
+        let now = Instant::now();
+        if next < now {
+            let missed = ((now - next).as_secs_f64() / schedule.interval_s).ceil() as u32;
+            warn!(missed, "Reading took longer than the interval, skipping readings");
+            next += interval * missed;
+        }
+    }
+    Ok(rows)
+}
+
+// Flushes written rows all the way to the disk
+fn sync(file: &mut BufWriter<File>) -> io::Result<()> {
+    file.flush()?;
+    file.get_ref().sync_data()
+}
+
+// Allan deviation at octave spaced averaging times and a summary with the drift of each quantity
+fn write_analysis(rows: &[(f64, Vec<f64>)], quantities: &[&str], interval_s: f64, output_dir: &Path) -> io::Result<()> {
+    let times: Vec<f64> = rows.iter().map(|(t, _)| *t).collect();
+    let series: Vec<Vec<f64>> = (0..quantities.len())
+        .map(|q| rows.iter().map(|(_, values)| values[q]).collect())
+        .collect();
+
+    let mut file = File::create(output_dir.join("stability_allan.csv"))?;
+    let header: Vec<String> = quantities.iter().map(|q| format!("{} Allan Deviation", q)).collect();
+    writeln!(file, "Tau (s),{}", header.join(","))?;
+    let deviations: Vec<Vec<(f64, f64)>> = series.iter().map(|values| allan_deviation(values, interval_s)).collect();
+    for (i, &(tau_s, _)) in deviations.first().map(Vec::as_slice).unwrap_or_default().iter().enumerate() {
+        let columns: Vec<String> = deviations.iter().map(|d| format!("{:.6e}", d[i].1)).collect();
+        writeln!(file, "{:.3},{}", tau_s, columns.join(","))?;
+    }
+
+    let mut file = File::create(output_dir.join("stability_summary.csv"))?;
+    writeln!(file, "Quantity,Mean,Std Dev,Min,Max,Drift (per h)")?;
+    for (quantity, values) in quantities.iter().zip(&series) {
+        if values.is_empty() {
+            continue;
+        }
+        // An infinite threshold leaves the outlier flags out, a slow drift isn't an outlier
+        let stats = Stats::new(values.clone(), f64::INFINITY);
+        let drift = drift_per_hour(&times, values).unwrap_or(f64::NAN);
+        info!(quantity, mean = stats.mean, std_dev = stats.std_dev, drift_per_h = drift, "Stability");
+        writeln!(
+            file,
+            "{},{:.6},{:.6},{:.6},{:.6},{:.6}",
+            quantity, stats.mean, stats.std_dev, stats.min, stats.max, drift,
+        )?;
+    }
+    info!(file = %output_dir.join("stability_allan.csv").display(), "Analysis saved");
+    Ok(())
+}
+
+/// Overlapping Allan deviation at averaging times of 1, 2, 4... intervals, as (tau s, deviation).
+/// Readings are taken as evenly spaced, a skipped reading shortens the series rather than leaving a gap.
+pub fn allan_deviation(values: &[f64], interval_s: f64) -> Vec<(f64, f64)> {
+    let n = values.len();
+    // Sums of the first k readings, so every average over m readings is one subtraction
+    let mut sums = Vec::with_capacity(n + 1);
+    sums.push(0.0);
+    for value in values {
+        sums.push(sums.last().copied().unwrap_or(0.0) + value);
+    }
+
+    let mut deviations = Vec::new();
+    let mut m = 1;
+    while 2 * m <= n {
+        let average = |start: usize| (sums[start + m] - sums[start]) / m as f64;
+        let pairs = n - 2 * m + 1;
+        let variance = (0..pairs).map(|k| (average(k + m) - average(k)).powi(2)).sum::<f64>() / (2.0 * pairs as f64);
+        deviations.push((m as f64 * interval_s, variance.sqrt()));
+        m *= 2;
+    }
+    deviations
+}
+
+/// Least squares slope of the values over time, per hour
+pub fn drift_per_hour(times_s: &[f64], values: &[f64]) -> Option<f64> {
+    if times_s.len() < 2 {
+        return None;
+    }
+    let n = times_s.len() as f64;
+    let mean_t = times_s.iter().sum::<f64>() / n;
+    let mean_v = values.iter().sum::<f64>() / n;
+    let covariance: f64 = times_s.iter().zip(values).map(|(t, v)| (t - mean_t) * (v - mean_v)).sum();
+    let variance: f64 = times_s.iter().map(|t| (t - mean_t).powi(2)).sum();
+    (variance > 0.0).then(|| covariance / variance * 3600.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule_counts_the_reading_at_time_zero() {
+        assert_eq!(expected_points(1.0, 10.0), 11);
+        assert_eq!(expected_points(3.0, 10.0), 4);
+        assert_eq!(expected_points(1.0, 0.0), 1);
+        assert_eq!(expected_points(0.0, 10.0), 0);
+        assert_eq!(expected_points(1.0, -1.0), 0);
+        assert_eq!(expected_points(f64::NAN, 10.0), 0);
+    }
+
+    #[test]
+    fn allan_deviation_doubles_the_averaging_time() {
+        let deviations = allan_deviation(&[1.0, -1.0, 1.0, -1.0], 0.5);
+        assert_eq!(deviations.len(), 2);
+        assert_eq!(deviations[0].0, 0.5);
+        assert!((deviations[0].1 - 2f64.sqrt()).abs() < 1e-12);
+        assert_eq!(deviations[1], (1.0, 0.0));
+    }
+
+    #[test]
+    fn constant_or_short_series_has_no_deviation() {
+        assert!(allan_deviation(&[3.0; 8], 1.0).iter().all(|&(_, deviation)| deviation == 0.0));
+        assert!(allan_deviation(&[3.0], 1.0).is_empty());
+        assert!(allan_deviation(&[], 1.0).is_empty());
+    }
+
+    #[test]
+    fn drift_is_the_slope_per_hour() {
+        let times = [0.0, 60.0, 120.0, 180.0];
+        let values: Vec<f64> = times.iter().map(|t| -10.0 + 0.001 * t).collect();
+        assert!((drift_per_hour(&times, &values).unwrap() - 3.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn drift_needs_readings_at_different_times() {
+        assert_eq!(drift_per_hour(&[0.0], &[1.0]), None);
+        assert_eq!(drift_per_hour(&[5.0, 5.0], &[1.0, 2.0]), None);
+    }
+}
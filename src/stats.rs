@@ -0,0 +1,262 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+/// Readings a request may ask for at each setpoint
+pub const REPEATS: RangeInclusive<u32> = 1..=100;
+
+// Modified z-score above which a reading is flagged, after Iglewicz and Hoaglin
+const DEFAULT_OUTLIER_THRESHOLD: f64 = 3.5;
+// Fewer readings than this can't tell an outlier from the rest
+const MIN_READINGS_FOR_OUTLIERS: usize = 3;
+
+/// Repeated readings as a request asks for them
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct RepeatParams {
+    /// Readings per setpoint, the result columns hold their mean
+    #[serde(default)]
+    pub repeats: Option<u32>,
+    /// Writes every reading to a separate samples file
+    #[serde(default)]
+    pub keep_samples: bool,
+    #[serde(default)]
+    pub outlier_threshold: Option<f64>,
+}
+
+/// How many readings to take at each setpoint and what to do with them
+#[derive(Debug, Clone, Copy)]
+pub struct Repeats {
+    pub count: u32,
+    pub keep_samples: bool,
+    pub outlier_threshold: f64,
+}
+
+/// Summary of the readings of one quantity at one setpoint
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub samples: Vec<f64>,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Same order as the samples
+    pub outliers: Vec<bool>,
+}
+
+impl RepeatParams {
+    /// Checks the request's repeats against the bounds
+    pub fn resolve(&self) -> Result<Repeats, String> {
+        let count = self.repeats.unwrap_or(1);
+        if !REPEATS.contains(&count) {
+            return Err(format!("repeats {} is outside {}..={}", count, REPEATS.start(), REPEATS.end()));
+        }
+        let outlier_threshold = self.outlier_threshold.unwrap_or(DEFAULT_OUTLIER_THRESHOLD);
+        if !(outlier_threshold > 0.0 && outlier_threshold.is_finite()) {
+            return Err(format!("outlier_threshold must be positive, got {}", outlier_threshold));
+        }
+        if count == 1 && (self.keep_samples || self.outlier_threshold.is_some()) {
+            return Err("keep_samples and outlier_threshold need repeats above 1".to_string());
+        }
+        Ok(Repeats { count, keep_samples: self.keep_samples, outlier_threshold })
+    }
+
+    pub fn is_repeated(&self) -> bool {
+        self.repeats.is_some_and(|count| count > 1) || self.keep_samples || self.outlier_threshold.is_some()
+    }
+}
+
+impl Repeats {
+    /// More than one reading, so the results get statistics columns
+    pub fn is_repeated(&self) -> bool {
+        self.count > 1
+    }
+
+    /// Statistics column headers following a result column, e.g. "Power (dBm) Std Dev"
+    pub fn header(&self, columns: &[&str]) -> String {
+        if !self.is_repeated() {
+            return String::new();
+        }
+        let mut header: String = columns.iter()
+            .map(|column| format!(",{0} Std Dev,{0} Min,{0} Max", column))
+            .collect();
+        header.push_str(",Outliers");
+        header
+    }
+
+    /// Statistics columns for a row, in the order of `header`
+    pub fn columns(&self, stats: &[Stats]) -> String {
+        if !self.is_repeated() {
+            return String::new();
+        }
+        let mut row: String = stats.iter()
+            .map(|s| format!(",{:.6},{:.6},{:.6}", s.std_dev, s.min, s.max))
+            .collect();
+        row.push_str(&format!(",{}", outlier_count(stats)));
+        row
+    }
+
+    /// Takes `count` readings of one or more quantities and summarizes each quantity
+    pub fn measure(&self, mut read: impl FnMut() -> io::Result<Vec<f64>>) -> io::Result<Vec<Stats>> {
+        let mut readings: Vec<Vec<f64>> = Vec::new();
+        for _ in 0..self.count {
+            let values = read()?;
+            readings.resize_with(values.len(), Vec::new);
+            for (quantity, value) in readings.iter_mut().zip(values) {
+                quantity.push(value);
+            }
+        }
+        let stats: Vec<Stats> = readings.into_iter().map(|samples| Stats::new(samples, self.outlier_threshold)).collect();
+        if self.is_repeated() {
+            let outliers = outlier_count(&stats);
+            if outliers > 0 {
+                warn!(outliers, readings = self.count, "Outlying readings");
+            } else {
+                info!(readings = self.count, "Repeated readings");
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Takes `count` readings of a single quantity
+    pub fn measure_one(&self, mut read: impl FnMut() -> io::Result<f64>) -> io::Result<Stats> {
+        let mut stats = self.measure(|| Ok(vec![read()?]))?;
+        Ok(stats.remove(0))
+    }
+}
+
+// Readings flagged in any quantity, a reading counts once
+fn outlier_count(stats: &[Stats]) -> usize {
+    let readings = stats.iter().map(|s| s.outliers.len()).max().unwrap_or(0);
+    (0..readings).filter(|&i| stats.iter().any(|s| s.outliers.get(i).copied().unwrap_or(false))).count()
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+impl Stats {
+    /// Mean, sample standard deviation and range, with outliers flagged by their modified z-score
+    pub fn new(samples: Vec<f64>, outlier_threshold: f64) -> Self {
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let std_dev = if samples.len() > 1 {
+            (samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        let min = samples.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = samples.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let outliers = flag_outliers(&samples, outlier_threshold);
+        Stats { samples, mean, std_dev, min, max, outliers }
+    }
+}
+
+// The median and its absolute deviation aren't pulled by the outliers themselves, unlike mean and std dev
+fn flag_outliers(samples: &[f64], threshold: f64) -> Vec<bool> {
+    if samples.len() < MIN_READINGS_FOR_OUTLIERS {
+        return vec![false; samples.len()];
+    }
+    let center = median(samples);
+    let deviations: Vec<f64> = samples.iter().map(|x| (x - center).abs()).collect();
+
+    // With more than half the readings identical the median deviation is 0, the mean deviation stands in
+    let mad = median(&deviations);
+    let scale = if mad > 0.0 {
+        mad / 0.6745
+    } else {
+        1.253314 * deviations.iter().sum::<f64>() / samples.len() as f64
+    };
+    if scale == 0.0 {
+        return vec![false; samples.len()];
+    }
+    deviations.iter().map(|d| d / scale > threshold).collect()
+}
+
+/// Every reading of a repeated experiment, one row per reading, when the request keeps samples
+pub struct SampleLog {
+    file: Option<File>,
+}
+
+impl SampleLog {
+    /// Creates the samples file if the request keeps samples. `columns` names the setpoint and then each quantity.
+    pub fn new(repeats: &Repeats, path: &Path, columns: &[&str]) -> io::Result<Self> {
+        if !repeats.keep_samples {
+            return Ok(SampleLog { file: None });
+        }
+        let (setpoint, quantities) = columns.split_first().unwrap_or((&"Setpoint", &[]));
+        let mut file = File::create(path)?;
+        writeln!(file, "{},Reading,{},Outlier", setpoint, quantities.join(","))?;
+        Ok(SampleLog { file: Some(file) })
+    }
+
+    pub fn write(&mut self, setpoint: f64, stats: &[Stats]) -> io::Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let readings = stats.iter().map(|s| s.samples.len()).max().unwrap_or(0);
+        for i in 0..readings {
+            let values: Vec<String> = stats.iter().map(|s| format!("{:.6}", s.samples[i])).collect();
+            let outlier = stats.iter().any(|s| s.outliers[i]);
+            writeln!(file, "{},{},{},{}", setpoint, i + 1, values.join(","), outlier as u8)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_summarize_the_readings() {
+        let stats = Stats::new(vec![1.0, 2.0, 3.0, 4.0], DEFAULT_OUTLIER_THRESHOLD);
+        assert_eq!(stats.mean, 2.5);
+        assert!((stats.std_dev - (5.0f64 / 3.0).sqrt()).abs() < 1e-12);
+        assert_eq!((stats.min, stats.max), (1.0, 4.0));
+        assert_eq!(stats.outliers, vec![false; 4]);
+    }
+
+    #[test]
+    fn single_reading_has_no_spread() {
+        let stats = Stats::new(vec![-12.5], DEFAULT_OUTLIER_THRESHOLD);
+        assert_eq!((stats.mean, stats.std_dev, stats.min, stats.max), (-12.5, 0.0, -12.5, -12.5));
+        assert_eq!(stats.outliers, vec![false]);
+    }
+
+    #[test]
+    fn outlier_is_flagged() {
+        let stats = Stats::new(vec![10.0, 10.1, 9.9, 10.05, 25.0], DEFAULT_OUTLIER_THRESHOLD);
+        assert_eq!(stats.outliers, vec![false, false, false, false, true]);
+    }
+
+    #[test]
+    fn outlier_among_identical_readings_is_flagged() {
+        let stats = Stats::new(vec![5.0, 5.0, 5.0, 5.0, 8.0], DEFAULT_OUTLIER_THRESHOLD);
+        assert_eq!(stats.outliers, vec![false, false, false, false, true]);
+        let flat = Stats::new(vec![5.0; 4], DEFAULT_OUTLIER_THRESHOLD);
+        assert_eq!(flat.outliers, vec![false; 4]);
+    }
+
+    #[test]
+    fn too_few_readings_are_never_flagged() {
+        let stats = Stats::new(vec![1.0, 100.0], DEFAULT_OUTLIER_THRESHOLD);
+        assert_eq!(stats.outliers, vec![false, false]);
+    }
+
+    #[test]
+    fn outliers_count_once_per_reading() {
+        let power = Stats::new(vec![1.0, 1.0, 1.0, 1.0, 9.0], DEFAULT_OUTLIER_THRESHOLD);
+        let voltage = Stats::new(vec![2.0, 2.0, 2.0, 2.0, 7.0], DEFAULT_OUTLIER_THRESHOLD);
+        assert_eq!(outlier_count(&[power, voltage]), 1);
+    }
+}
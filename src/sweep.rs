@@ -0,0 +1,720 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tracing::{info, info_span, warn};
+use crate::actor::InstrumentHandle;
+use crate::calibration::PathCalibration;
+use crate::drivers::{cld1015, n77, osa, power_meter};
+use crate::safety;
+
+// Relative tolerance used when counting sweep points
+const POINT_TOLERANCE: f64 = 1e-9;
+// Generated setpoints are rounded to 1e-9 of their unit
+const POINT_SCALE: f64 = 1e9;
+
+/// Most points an axis or a whole sweep may have, far more than any run within the duration limit.
+/// Checked before the points are generated, so a tiny step can't exhaust the memory.
+pub const MAX_POINTS: usize = 1_000_000;
+
+/// A settable quantity that can be used as a sweep axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Parameter {
+    Cld1015CurrentMa,
+    TecTemperatureC,
+    N77WavelengthNm,
+    N77PowerDbm,
+    OsaCenterNm,
+}
+
+/// A readable quantity recorded at every sweep point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Measurement {
+    PowerMeterDbm,
+    OsaPeakWavelengthNm,
+    OsaPeakPowerDbm,
+    Cld1015CurrentMa,
+    TecTemperatureC,
+}
+
+/// How the values of an axis are generated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AxisPoints {
+    Linear { start: f64, stop: f64, step: f64 },
+    Log { start: f64, stop: f64, points: usize },
+    List { values: Vec<f64> },
+    /// Linear from start to stop and back again
+    Bidirectional { start: f64, stop: f64, step: f64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Axis {
+    pub parameter: Parameter,
+    #[serde(flatten)]
+    pub points: AxisPoints,
+    /// Extra settling time after this axis changes, e.g. for TEC temperature
+    #[serde(default)]
+    pub settle_ms: u64,
+}
+
+/// A declared sweep: axes are nested with the first axis outermost
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SweepPlan {
+    pub axes: Vec<Axis>,
+    pub measurements: Vec<Measurement>,
+    pub dwell_ms: u64,
+    #[serde(default)]
+    pub osa_center_nm: Option<f64>,
+    #[serde(default)]
+    pub osa_span_nm: Option<f64>,
+    /// Calibrated optical path to the power meter, its loss is added to power meter readings
+    #[serde(default)]
+    pub optical_path: Option<String>,
+}
+
+/// Instruments available to the sweep engine, only the ones the plan needs must be set
+#[derive(Default)]
+pub struct SweepInstruments<'a> {
+    pub cld1015: Option<&'a InstrumentHandle>,
+    pub n77: Option<&'a InstrumentHandle>,
+    pub power_meter: Option<&'a InstrumentHandle>,
+    pub osa: Option<&'a InstrumentHandle>,
+}
+
+impl Parameter {
+    pub fn instrument(self) -> &'static str {
+        match self {
+            Parameter::Cld1015CurrentMa | Parameter::TecTemperatureC => "cld1015",
+            Parameter::N77WavelengthNm | Parameter::N77PowerDbm => "n77",
+            Parameter::OsaCenterNm => "osa",
+        }
+    }
+
+    /// CSV column header
+    pub fn label(self) -> &'static str {
+        match self {
+            Parameter::Cld1015CurrentMa => "Current (mA)",
+            Parameter::TecTemperatureC => "TEC Temperature (C)",
+            Parameter::N77WavelengthNm => "Laser Wavelength (nm)",
+            Parameter::N77PowerDbm => "Laser Power (dBm)",
+            Parameter::OsaCenterNm => "OSA Center (nm)",
+        }
+    }
+
+    pub fn check(self, value: f64) -> std::result::Result<(), String> {
+        match self {
+            Parameter::Cld1015CurrentMa => safety::check("Current", "mA", &safety::CLD1015_CURRENT_MA, value),
+            Parameter::TecTemperatureC => safety::check("TEC temperature", "C", &safety::CLD1015_TEMPERATURE_C, value),
+            Parameter::N77WavelengthNm => safety::check("Wavelength", "nm", &safety::N77_WAVELENGTH_NM, value),
+            Parameter::N77PowerDbm => safety::check("Laser power", "dBm", &safety::N77_POWER_DBM, value),
+            Parameter::OsaCenterNm => safety::check("OSA center", "nm", &safety::OSA_WAVELENGTH_NM, value),
+        }
+    }
+}
+
+impl Measurement {
+    pub fn instrument(self) -> &'static str {
+        match self {
+            Measurement::PowerMeterDbm => "power_meter",
+            Measurement::OsaPeakWavelengthNm | Measurement::OsaPeakPowerDbm => "osa",
+            Measurement::Cld1015CurrentMa | Measurement::TecTemperatureC => "cld1015",
+        }
+    }
+
+    /// CSV column header
+    pub fn label(self) -> &'static str {
+        match self {
+            Measurement::PowerMeterDbm => "Power (dBm)",
+            Measurement::OsaPeakWavelengthNm => "Peak Wavelength (nm)",
+            Measurement::OsaPeakPowerDbm => "Peak Power (dBm)",
+            Measurement::Cld1015CurrentMa => "Measured Current (mA)",
+            Measurement::TecTemperatureC => "Measured Temperature (C)",
+        }
+    }
+}
+
+/// Number of points `linear_points` gives, without generating them
+pub fn linear_count(start: f64, stop: f64, step: f64) -> usize {
+    if step <= 0.0 || !step.is_finite() {
+        return 0;
+    }
+    (((stop - start).abs() / step + POINT_TOLERANCE).floor() as usize).saturating_add(1)
+}
+
+/// Refuses a number of points above MAX_POINTS
+pub fn check_count(points: usize) -> std::result::Result<(), String> {
+    if points > MAX_POINTS {
+        return Err(format!("Too many points: {}, a sweep may have at most {}. Use a larger step.", points, MAX_POINTS));
+    }
+    Ok(())
+}
+
+/// Points from start to stop (inclusive) in steps of `step`, in either direction.
+/// Each value is computed from its index, and the stop value is kept even when
+/// (stop - start) / step lands just below an integer.
+pub fn linear_points(start: f64, stop: f64, step: f64) -> std::result::Result<Vec<f64>, String> {
+    if step <= 0.0 || !step.is_finite() {
+        return Ok(Vec::new());
+    }
+    check_count(linear_count(start, stop, step))?;
+    let direction = if stop >= start { 1.0 } else { -1.0 };
+    let intervals = ((stop - start).abs() / step + POINT_TOLERANCE).floor() as usize;
+
+    Ok((0..=intervals)
+        .map(|i| {
+            let value = start + direction * i as f64 * step;
+            if (value - stop).abs() < step * POINT_TOLERANCE * 1e3 {
+                stop
+            } else {
+                round_point(value)
+            }
+        })
+        .collect())
+}
+
+/// Logarithmically spaced points from start to stop (inclusive)
+pub fn log_points(start: f64, stop: f64, points: usize) -> std::result::Result<Vec<f64>, String> {
+    if points < 2 || start <= 0.0 || stop <= 0.0 {
+        return Ok(Vec::new());
+    }
+    check_count(points)?;
+    let ratio = (stop / start).ln() / (points - 1) as f64;
+    Ok((0..points)
+        .map(|i| {
+            if i == points - 1 {
+                stop
+            } else {
+                round_point(start * (ratio * i as f64).exp())
+            }
+        })
+        .collect())
+}
+
+/// Linear points from start to stop and back, without repeating the turning point
+pub fn bidirectional_points(start: f64, stop: f64, step: f64) -> std::result::Result<Vec<f64>, String> {
+    check_count(bidirectional_count(start, stop, step))?;
+    let mut values = linear_points(start, stop, step)?;
+    let back: Vec<f64> = values.iter().rev().skip(1).copied().collect();
+    values.extend(back);
+    Ok(values)
+}
+
+fn bidirectional_count(start: f64, stop: f64, step: f64) -> usize {
+    linear_count(start, stop, step).saturating_mul(2).saturating_sub(1)
+}
+
+// Drop floating point noise such as 0.30000000000000004
+fn round_point(value: f64) -> f64 {
+    (value * POINT_SCALE).round() / POINT_SCALE
+}
+
+impl Axis {
+    /// Number of values the axis has, without generating them
+    pub fn count(&self) -> usize {
+        match &self.points {
+            AxisPoints::Linear { start, stop, step } => linear_count(*start, *stop, *step),
+            AxisPoints::Log { points, .. } => *points,
+            AxisPoints::List { values } => values.len(),
+            AxisPoints::Bidirectional { start, stop, step } => bidirectional_count(*start, *stop, *step),
+        }
+    }
+
+    /// Generates the axis values and checks them against the safety limits
+    pub fn values(&self) -> std::result::Result<Vec<f64>, String> {
+        check_count(self.count()).map_err(|err| format!("{}: {}", self.parameter.label(), err))?;
+        let values = match &self.points {
+            AxisPoints::Linear { start, stop, step } => {
+                if *step <= 0.0 {
+                    return Err(format!("{}: step must be positive", self.parameter.label()));
+                }
+                linear_points(*start, *stop, *step)?
+            },
+            AxisPoints::Log { start, stop, points } => {
+                if *start <= 0.0 || *stop <= 0.0 || *points < 2 {
+                    return Err(format!(
+                        "{}: log axis needs positive start/stop and at least 2 points",
+                        self.parameter.label()
+                    ));
+                }
+                log_points(*start, *stop, *points)?
+            },
+            AxisPoints::List { values } => values.clone(),
+            AxisPoints::Bidirectional { start, stop, step } => {
+                if *step <= 0.0 {
+                    return Err(format!("{}: step must be positive", self.parameter.label()));
+                }
+                bidirectional_points(*start, *stop, *step)?
+            },
+        };
+
+        if values.is_empty() {
+            return Err(format!("{}: axis has no points", self.parameter.label()));
+        }
+        for value in &values {
+            self.parameter.check(*value)?;
+        }
+        Ok(values)
+    }
+}
+
+impl SweepPlan {
+    /// Names of the instruments the plan sets or reads, without duplicates
+    pub fn required_instruments(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = Vec::new();
+        let used = self.axes.iter().map(|a| a.parameter.instrument())
+            .chain(self.measurements.iter().map(|m| m.instrument()));
+        for name in used {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Number of setpoint combinations, without generating them
+    pub fn point_count(&self) -> usize {
+        self.axes.iter().fold(1, |count, axis| count.saturating_mul(axis.count()))
+    }
+
+    /// All setpoint combinations, one value per axis, with the last axis varying fastest
+    pub fn setpoints(&self) -> std::result::Result<Vec<Vec<f64>>, String> {
+        check_count(self.point_count())?;
+        let mut combinations: Vec<Vec<f64>> = vec![Vec::new()];
+        for axis in &self.axes {
+            let values = axis.values()?;
+            combinations = combinations
+                .into_iter()
+                .flat_map(|prefix| {
+                    values.iter().map(move |value| {
+                        let mut combination = prefix.clone();
+                        combination.push(*value);
+                        combination
+                    })
+                })
+                .collect();
+        }
+        Ok(combinations)
+    }
+
+    /// Checks the plan is well formed and every setpoint is within the safety limits
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.axes.is_empty() {
+            return Err("Sweep needs at least one axis".to_string());
+        }
+        if self.measurements.is_empty() {
+            return Err("Sweep needs at least one measurement".to_string());
+        }
+        for (i, axis) in self.axes.iter().enumerate() {
+            if self.axes[..i].iter().any(|a| a.parameter == axis.parameter) {
+                return Err(format!("{} is used by more than one axis", axis.parameter.label()));
+            }
+            axis.values()?;
+        }
+        if self.uses_osa_measurement() && self.osa_center_nm.is_none() && self.axis_index(Parameter::OsaCenterNm).is_none() {
+            return Err("OSA measurements need osa_center_nm or an OSA center axis".to_string());
+        }
+        if let Some(center) = self.osa_center_nm {
+            Parameter::OsaCenterNm.check(center)?;
+        }
+        if self.optical_path.is_some() {
+            if !self.measurements.contains(&Measurement::PowerMeterDbm) {
+                return Err("optical_path needs a power_meter_dbm measurement".to_string());
+            }
+            // The loss depends on the wavelength, which only an N77 axis pins down
+            if self.axis_index(Parameter::N77WavelengthNm).is_none() {
+                return Err("optical_path needs an n77_wavelength_nm axis".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// The optical path's loss table, checked to cover the wavelength axis
+    fn calibration(&self) -> std::result::Result<Option<PathCalibration>, String> {
+        let Some(name) = &self.optical_path else {
+            return Ok(None);
+        };
+        let calibration = PathCalibration::load(name)?;
+        if let Some(index) = self.axis_index(Parameter::N77WavelengthNm) {
+            let values = self.axes[index].values()?;
+            let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            calibration.check_covers(min, max)?;
+        }
+        Ok(Some(calibration))
+    }
+
+    fn axis_index(&self, parameter: Parameter) -> Option<usize> {
+        self.axes.iter().position(|a| a.parameter == parameter)
+    }
+
+    fn uses_osa_measurement(&self) -> bool {
+        self.measurements.iter().any(|m| m.instrument() == "osa")
+    }
+
+    fn uses_instrument(&self, name: &str) -> bool {
+        self.required_instruments().contains(&name)
+    }
+}
+
+fn require<'b>(slot: Option<&'b InstrumentHandle>, name: &str) -> io::Result<&'b InstrumentHandle> {
+    slot.ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotConnected,
+        format!("Sweep needs {} but it is not connected", name)
+    ))
+}
+
+/// Runs a declared sweep and writes one CSV row per setpoint combination.
+/// Returns the number of points measured.
+pub fn run_sweep(
+    instruments: &SweepInstruments,
+    plan: &SweepPlan,
+    output_path: &Path,
+) -> io::Result<usize> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    plan.validate().map_err(invalid)?;
+    let setpoints = plan.setpoints().map_err(invalid)?;
+    let calibration = plan.calibration().map_err(invalid)?;
+
+    // Create a CSV file to save results
+    if let Some(dir) = output_path.parent() {
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| {
+            warn!(error = %e, "Failed to create data directory");
+        });
+    }
+    let mut file = File::create(output_path)?;
+    let mut header: Vec<&str> = plan.axes.iter().map(|a| a.parameter.label())
+        .chain(plan.measurements.iter().map(|m| m.label()))
+        .collect();
+    // Power is then the power at the device, the meter's reading is kept at the end
+    if calibration.is_some() {
+        header.extend(["Raw Power (dBm)", "Path Loss (dB)"]);
+    }
+    writeln!(file, "{}", header.join(","))?;
+
+    info!(points = setpoints.len(), axes = plan.axes.len(), "Starting sweep");
+
+    let result = setup(instruments, plan)
+        .and_then(|_| sweep_points(instruments, plan, &setpoints, calibration.as_ref(), &mut file));
+    // Always try to leave the sources off, even if the setup or the sweep failed
+    let teardown_result = teardown(instruments, plan);
+    result?;
+    teardown_result?;
+
+    info!("Sweep completed successfully");
+    info!(file = %output_path.display(), "Results saved");
+    Ok(setpoints.len())
+}
+
+fn setup(instruments: &SweepInstruments, plan: &SweepPlan) -> io::Result<()> {
+    if plan.uses_instrument("cld1015") {
+        let cld = require(instruments.cld1015, "cld1015")?;
+        cld1015::configure(cld)?;
+        // Turn TEC on before laser activation
+        cld1015::set_tec_output(cld, true)?;
+        thread::sleep(Duration::from_millis(100));
+        if plan.axis_index(Parameter::Cld1015CurrentMa).is_some() {
+            cld1015::set_laser_output(cld, true)?;
+            info!("Laser turned ON");
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    if plan.uses_instrument("n77") {
+        let laser = require(instruments.n77, "n77")?;
+        n77::configure(laser)?;
+        n77::set_output(laser, true)?;
+        info!("Laser turned ON");
+    }
+
+    if plan.uses_instrument("power_meter") {
+        power_meter::configure(require(instruments.power_meter, "power_meter")?)?;
+    }
+
+    if plan.uses_instrument("osa") {
+        let center = plan.osa_center_nm.unwrap_or_else(|| {
+            // validate() guarantees an OSA center axis in this case
+            let index = plan.axis_index(Parameter::OsaCenterNm).unwrap();
+            plan.axes[index].values().unwrap()[0]
+        });
+        osa::configure(require(instruments.osa, "osa")?, center, plan.osa_span_nm.unwrap_or(2.0))?;
+    }
+
+    Ok(())
+}
+
+fn sweep_points(
+    instruments: &SweepInstruments,
+    plan: &SweepPlan,
+    setpoints: &[Vec<f64>],
+    calibration: Option<&PathCalibration>,
+    file: &mut File,
+) -> io::Result<()> {
+    let mut previous: Option<&Vec<f64>> = None;
+
+    for (index, point) in setpoints.iter().enumerate() {
+        let _point = info_span!("point", index, setpoint = ?point).entered();
+
+        // Only touch the axes whose value changed since the last point
+        let mut settle_ms = 0;
+        for (i, axis) in plan.axes.iter().enumerate() {
+            if previous.is_some_and(|p| p[i] == point[i]) {
+                continue;
+            }
+            set_parameter(instruments, axis.parameter, point[i])?;
+            settle_ms = settle_ms.max(axis.settle_ms);
+        }
+        previous = Some(point);
+
+        // Wait for stabilization
+        thread::sleep(Duration::from_millis(plan.dwell_ms.max(settle_ms)));
+
+        let mut values = measure(instruments, plan)?;
+        if let Some(calibration) = calibration {
+            correct_power(plan, calibration, point, &mut values)?;
+        }
+
+        let row: Vec<String> = point.iter().map(|v| format!("{:.4}", v))
+            .chain(values.iter().map(|v| format!("{:.4}", v)))
+            .collect();
+        info!(values = ?values, "Measured point");
+        writeln!(file, "{}", row.join(","))?;
+    }
+
+    Ok(())
+}
+
+fn set_parameter(instruments: &SweepInstruments, parameter: Parameter, value: f64) -> io::Result<()> {
+    match parameter {
+        Parameter::Cld1015CurrentMa => cld1015::set_current_ma(require(instruments.cld1015, "cld1015")?, value),
+        Parameter::TecTemperatureC => cld1015::set_temperature_c(require(instruments.cld1015, "cld1015")?, value),
+        Parameter::N77WavelengthNm => {
+            n77::set_wavelength_nm(require(instruments.n77, "n77")?, value)?;
+            // Keep the power meter calibration wavelength in step with the laser
+            if let Some(meter) = instruments.power_meter {
+                power_meter::set_wavelength_nm(meter, value)?;
+            }
+            Ok(())
+        },
+        Parameter::N77PowerDbm => n77::set_power_dbm(require(instruments.n77, "n77")?, value),
+        Parameter::OsaCenterNm => osa::set_center_nm(require(instruments.osa, "osa")?, value),
+    }
+}
+
+fn measure(instruments: &SweepInstruments, plan: &SweepPlan) -> io::Result<Vec<f64>> {
+    // One OSA sweep serves every OSA measurement at this point
+    let osa_peak = if plan.uses_osa_measurement() {
+        let analyzer = require(instruments.osa, "osa")?;
+        osa::take_sweep(analyzer)?;
+        let (peak_nm, peak_dbm) = osa::read_peak(analyzer)?;
+        info!(peak_nm, peak_dbm, "Measured peak");
+        Some((peak_nm, peak_dbm))
+    } else {
+        None
+    };
+
+    let mut values = Vec::with_capacity(plan.measurements.len());
+    for measurement in &plan.measurements {
+        let value = match measurement {
+            Measurement::PowerMeterDbm => power_meter::read_power_dbm(require(instruments.power_meter, "power_meter")?)?,
+            Measurement::OsaPeakWavelengthNm => osa_peak.unwrap().0,
+            Measurement::OsaPeakPowerDbm => osa_peak.unwrap().1,
+            Measurement::Cld1015CurrentMa => cld1015::read_current_ma(require(instruments.cld1015, "cld1015")?)?,
+            Measurement::TecTemperatureC => cld1015::read_temperature_c(require(instruments.cld1015, "cld1015")?)?,
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+// Adds the path loss to the power meter reading, appending the reading and the loss
+fn correct_power(plan: &SweepPlan, calibration: &PathCalibration, point: &[f64], values: &mut Vec<f64>) -> io::Result<()> {
+    // validate() guarantees both exist when there is a calibration
+    let power = plan.measurements.iter().position(|m| *m == Measurement::PowerMeterDbm).unwrap();
+    let wavelength = point[plan.axis_index(Parameter::N77WavelengthNm).unwrap()];
+    let loss = calibration.loss_db(wavelength)
+        .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+    let raw = values[power];
+    values[power] = raw + loss;
+    values.extend([raw, loss]);
+    Ok(())
+}
+
+// Every instrument is switched off and checked even if another one fails, their errors are reported together
+fn teardown(instruments: &SweepInstruments, plan: &SweepPlan) -> io::Result<()> {
+    let mut errors: Vec<io::Error> = Vec::new();
+
+    if plan.uses_instrument("cld1015") {
+        let result = require(instruments.cld1015, "cld1015").and_then(|cld| {
+            cld1015::set_laser_output(cld, false)?;
+            info!("Laser turned OFF");
+            info!(response = %cld1015::read_error(cld)?, "Final error check on CLD1015");
+            Ok(())
+        });
+        errors.extend(result.err());
+    }
+
+    if plan.uses_instrument("n77") {
+        let result = require(instruments.n77, "n77").and_then(|laser| {
+            n77::set_output(laser, false)?;
+            info!("Laser turned OFF");
+            info!(response = %n77::read_error(laser)?, "Final error check on laser");
+            Ok(())
+        });
+        errors.extend(result.err());
+    }
+
+    if plan.uses_instrument("power_meter") {
+        let result = require(instruments.power_meter, "power_meter").and_then(|meter| {
+            info!(response = %power_meter::read_error(meter)?, "Final error check on power meter");
+            Ok(())
+        });
+        errors.extend(result.err());
+    }
+
+    if plan.uses_instrument("osa") {
+        let result = require(instruments.osa, "osa").and_then(|analyzer| {
+            osa::sweep_off(analyzer)?;
+            info!(response = %osa::read_error(analyzer)?, "Final error check on OSA");
+            Ok(())
+        });
+        errors.extend(result.err());
+    }
+
+    let mut errors = errors.into_iter();
+    let Some(first) = errors.next() else {
+        return Ok(());
+    };
+    let message = errors.fold(first.to_string(), |message, err| format!("{}; {}", message, err));
+    warn!(error = %message, "Teardown failed");
+    Err(io::Error::new(first.kind(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use crate::actor::RetryPolicy;
+    use crate::config;
+    use crate::transport::Transport;
+
+    // Records every command and refuses one of them
+    struct Refusing {
+        written: Arc<Mutex<Vec<String>>>,
+        refuse: &'static str,
+    }
+
+    impl Transport for Refusing {
+        fn write_line(&mut self, line: &str) -> io::Result<()> {
+            self.written.lock().unwrap().push(line.to_string());
+            if line == self.refuse {
+                return Err(io::Error::other(format!("{} refused", line)));
+            }
+            Ok(())
+        }
+
+        fn read_line(&mut self) -> io::Result<String> {
+            Ok("0".to_string())
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn refusing(name: &'static str, refuse: &'static str) -> (InstrumentHandle, Arc<Mutex<Vec<String>>>) {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let transport = Refusing { written: written.clone(), refuse };
+        let policy = RetryPolicy { attempts: 1, ..RetryPolicy::default() };
+        (InstrumentHandle::spawn(name, Box::new(transport), policy).unwrap(), written)
+    }
+
+    fn current_sweep_with_osa() -> SweepPlan {
+        SweepPlan {
+            axes: vec![axis(Parameter::Cld1015CurrentMa, AxisPoints::List { values: vec![10.0] })],
+            measurements: vec![Measurement::OsaPeakPowerDbm],
+            dwell_ms: 0,
+            osa_center_nm: Some(974.7),
+            osa_span_nm: None,
+            optical_path: None,
+        }
+    }
+
+    fn axis(parameter: Parameter, points: AxisPoints) -> Axis {
+        Axis { parameter, points, settle_ms: 0 }
+    }
+
+    #[test]
+    fn linear_points_keep_the_stop_value() {
+        assert_eq!(linear_points(0.0, 0.3, 0.1).unwrap(), vec![0.0, 0.1, 0.2, 0.3]);
+        assert_eq!(linear_points(3.0, 1.0, 1.0).unwrap(), vec![3.0, 2.0, 1.0]);
+        assert_eq!(linear_count(0.0, 0.3, 0.1), 4);
+    }
+
+    #[test]
+    fn too_many_points_are_refused_before_generating_them() {
+        assert!(linear_points(1530.0, 1570.0, 1e-12).is_err());
+        assert!(log_points(1.0, 10.0, usize::MAX).is_err());
+        assert!(bidirectional_points(0.0, 100.0, 1e-9).is_err());
+
+        let current = axis(Parameter::Cld1015CurrentMa, AxisPoints::Linear { start: 10.0, stop: 50.0, step: 1e-12 });
+        assert!(current.values().unwrap_err().contains("Too many points"));
+    }
+
+    #[test]
+    fn plan_counts_its_points_without_generating_them() {
+        let plan = SweepPlan {
+            axes: vec![
+                axis(Parameter::TecTemperatureC, AxisPoints::List { values: vec![20.0, 25.0] }),
+                axis(Parameter::Cld1015CurrentMa, AxisPoints::Bidirectional { start: 10.0, stop: 20.0, step: 5.0 }),
+            ],
+            measurements: vec![Measurement::Cld1015CurrentMa],
+            dwell_ms: 0,
+            osa_center_nm: None,
+            osa_span_nm: None,
+            optical_path: None,
+        };
+        assert_eq!(plan.point_count(), 10);
+        assert_eq!(plan.setpoints().unwrap().len(), 10);
+
+        // Each axis is within the limit, their combinations are not
+        let wide = |parameter, start, stop| axis(parameter, AxisPoints::Log { start, stop, points: 10_000 });
+        let plan = SweepPlan {
+            axes: vec![wide(Parameter::Cld1015CurrentMa, 1.0, 50.0), wide(Parameter::N77WavelengthNm, 1530.0, 1570.0)],
+            ..plan
+        };
+        assert_eq!(plan.point_count(), 100_000_000);
+        assert!(plan.setpoints().is_err());
+    }
+
+    #[test]
+    fn failed_setup_still_switches_the_laser_off() {
+        config::init_for_tests();
+        // The OSA is configured last, after the laser went on
+        let (cld, cld_written) = refusing("cld1015", "");
+        let (analyzer, _) = refusing("osa", "SNGLS;");
+        let instruments = SweepInstruments { cld1015: Some(&cld), osa: Some(&analyzer), ..Default::default() };
+        let output = config::data_path("sweep_tests").join("failed_setup.csv");
+
+        assert!(run_sweep(&instruments, &current_sweep_with_osa(), &output).unwrap_err().to_string().contains("SNGLS;"));
+        let written = cld_written.lock().unwrap();
+        assert!(written.contains(&"OUTPut:STATe 1".to_string()));
+        assert_eq!(written.iter().filter(|c| *c == "OUTPut:STATe 0").count(), 1);
+    }
+
+    #[test]
+    fn teardown_goes_on_after_an_instrument_fails() {
+        config::init_for_tests();
+        let (cld, _) = refusing("cld1015", "OUTPut:STATe 0");
+        let (analyzer, osa_written) = refusing("osa", "");
+        let instruments = SweepInstruments { cld1015: Some(&cld), osa: Some(&analyzer), ..Default::default() };
+
+        let err = teardown(&instruments, &current_sweep_with_osa()).unwrap_err();
+        assert!(err.to_string().contains("OUTPut:STATe 0 refused"));
+        assert!(osa_written.lock().unwrap().contains(&"SWEEP OFF;".to_string()));
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use crate::config;
+use crate::transport::Transport;
+use crate::lock::lock_or_recover;
+
+// Every transaction from every instrument, rotated by size, below the data root
+const SERVER_LOG_DIR: &str = "logs";
+const SERVER_LOG_NAME: &str = "transactions";
+const SERVER_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+// Rotated files kept next to the current one
+const SERVER_LOG_KEEP: usize = 5;
+// One directory per run below the data root, holding its transaction log
+const RUNS_DIR: &str = "runs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Write,
+    Read,
+    /// Device clear before a retry
+    Clear,
+}
+
+/// One command sent to or response read from an instrument
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub instrument: String,
+    pub direction: Direction,
+    pub data: String,
+    pub latency_ms: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// io::ErrorKind name of the error, so a replay can fail the same way
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+}
+
+/// What a run was started with, saved next to its transaction log so it can be replayed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunInfo {
+    pub run_id: String,
+    pub experiment: String,
+    pub params: serde_json::Value,
+    pub instruments: Vec<String>,
+    pub started_at: u64,
+    /// User who started the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator: Option<String>,
+}
+
+struct RunLog {
+    run_id: String,
+    file: Arc<Mutex<File>>,
+}
+
+struct ServerLog {
+    file: Option<File>,
+    size: u64,
+}
+
+/// Records instrument transactions to the server log and to the log of the run using the instrument
+pub struct TransactionLog {
+    server: Mutex<ServerLog>,
+    /// Instrument name to the run currently holding it
+    runs: Mutex<HashMap<String, RunLog>>,
+}
+
+/// Stops logging to the run's file when dropped
+pub struct RunLogGuard {
+    log: Arc<TransactionLog>,
+    instruments: Vec<String>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn server_log_path(index: usize) -> PathBuf {
+    match index {
+        0 => config::data_path(SERVER_LOG_DIR).join(format!("{}.jsonl", SERVER_LOG_NAME)),
+        i => config::data_path(SERVER_LOG_DIR).join(format!("{}.{}.jsonl", SERVER_LOG_NAME, i)),
+    }
+}
+
+/// Directory holding everything recorded about one run
+pub fn run_dir(run_id: &str) -> PathBuf {
+    config::data_path(RUNS_DIR).join(run_id)
+}
+
+/// Path of a run's transaction log
+pub fn run_log_path(run_id: &str) -> PathBuf {
+    run_dir(run_id).join("transactions.jsonl")
+}
+
+fn run_info_path(run_id: &str) -> PathBuf {
+    run_dir(run_id).join("run.json")
+}
+
+impl RunInfo {
+    pub fn save(&self) -> io::Result<()> {
+        let path = run_info_path(&self.run_id);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn load(run_id: &str) -> io::Result<Self> {
+        let content = fs::read_to_string(run_info_path(run_id))?;
+        serde_json::from_str(&content).map_err(io::Error::from)
+    }
+}
+
+/// Run ids become directory names, so only allow plain names
+pub fn is_valid_run_id(run_id: &str) -> bool {
+    !run_id.is_empty() && run_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl ServerLog {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size + line.len() as u64 > SERVER_LOG_MAX_BYTES {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            let file = open_append(&server_log_path(0))?;
+            self.size = file.metadata().map(|m| m.len()).unwrap_or(0);
+            self.file = Some(file);
+        }
+        writeln!(self.file.as_ref().unwrap(), "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // transactions.jsonl becomes transactions.1.jsonl, the oldest file is dropped
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        for index in (1..SERVER_LOG_KEEP).rev() {
+            let from = server_log_path(index);
+            if from.exists() {
+                fs::rename(&from, server_log_path(index + 1))?;
+            }
+        }
+        let current = server_log_path(0);
+        if current.exists() {
+            fs::rename(&current, server_log_path(1))?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl TransactionLog {
+    pub fn new() -> Self {
+        TransactionLog {
+            server: Mutex::new(ServerLog { file: None, size: 0 }),
+            runs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends the transactions of the given instruments to the run's log until the guard is dropped
+    pub fn begin_run(self: &Arc<Self>, run_id: &str, instruments: &[String]) -> io::Result<RunLogGuard> {
+        let file = Arc::new(Mutex::new(open_append(&run_log_path(run_id))?));
+        let mut runs = lock_or_recover(&self.runs);
+        for name in instruments {
+            runs.insert(name.clone(), RunLog { run_id: run_id.to_string(), file: file.clone() });
+        }
+        Ok(RunLogGuard {
+            log: self.clone(),
+            instruments: instruments.to_vec(),
+        })
+    }
+
+    fn record(&self, instrument: &str, direction: Direction, data: &str, latency: Duration, error: Option<&io::Error>) {
+        let (run_id, run_file) = match lock_or_recover(&self.runs).get(instrument) {
+            Some(run) => (Some(run.run_id.clone()), Some(run.file.clone())),
+            None => (None, None),
+        };
+        let transaction = Transaction {
+            timestamp_ms: now_ms(),
+            instrument: instrument.to_string(),
+            direction,
+            data: data.to_string(),
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            error: error.map(|e| e.to_string()),
+            error_kind: error.map(|e| format!("{:?}", e.kind())),
+            run_id,
+        };
+        let line = match serde_json::to_string(&transaction) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        // Logging must never break instrument I/O, so failures are only reported
+        if let Err(err) = lock_or_recover(&self.server).write_line(&line) {
+            warn!(error = %err, "Failed to write transaction log");
+        }
+        if let Some(file) = run_file
+            && let Err(err) = writeln!(lock_or_recover(&file), "{}", line) {
+            warn!(error = %err, "Failed to write run transaction log");
+        }
+    }
+}
+
+impl Drop for RunLogGuard {
+    fn drop(&mut self) {
+        let mut runs = lock_or_recover(&self.log.runs);
+        for name in &self.instruments {
+            runs.remove(name);
+        }
+    }
+}
+
+/// Reads a transaction log, skipping lines that can't be parsed
+pub fn read_log(path: &Path) -> io::Result<Vec<Transaction>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut transactions = Vec::new();
+    for line in reader.lines() {
+        if let Ok(transaction) = serde_json::from_str(&line?) {
+            transactions.push(transaction);
+        }
+    }
+    Ok(transactions)
+}
+
+/// The last `limit` transactions of the current server log, optionally for one instrument
+pub fn recent(instrument: Option<&str>, limit: usize) -> io::Result<Vec<Transaction>> {
+    let path = server_log_path(0);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut transactions = read_log(&path)?;
+    if let Some(name) = instrument {
+        transactions.retain(|t| t.instrument == name);
+    }
+    let skip = transactions.len().saturating_sub(limit);
+    Ok(transactions.split_off(skip))
+}
+
+/// Transport wrapper recording every line written and read
+pub struct LoggingTransport {
+    name: &'static str,
+    inner: Box<dyn Transport>,
+    log: Arc<TransactionLog>,
+}
+
+impl LoggingTransport {
+    pub fn new(name: &'static str, inner: Box<dyn Transport>, log: Arc<TransactionLog>) -> Self {
+        LoggingTransport { name, inner, log }
+    }
+}
+
+impl Transport for LoggingTransport {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let start = Instant::now();
+        let result = self.inner.write_line(line);
+        self.log.record(self.name, Direction::Write, line, start.elapsed(), result.as_ref().err());
+        result
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let start = Instant::now();
+        let result = self.inner.read_line();
+        let data = result.as_deref().unwrap_or("");
+        self.log.record(self.name, Direction::Read, data, start.elapsed(), result.as_ref().err());
+        result
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        let result = self.inner.clear();
+        self.log.record(self.name, Direction::Clear, "", start.elapsed(), result.as_ref().err());
+        result
+    }
+
+    fn lock_exclusive(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.lock_exclusive(timeout)
+    }
+
+    fn unlock(&mut self) -> io::Result<()> {
+        self.inner.unlock()
+    }
+}
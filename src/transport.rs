@@ -0,0 +1,76 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::time::Duration;
+use visa_rs::enums::attribute::{AttrTmoValue, HasAttribute};
+use visa_rs::prelude::*;
+use crate::visa_error::vs_to_io_err;
+
+/// Line based I/O with one instrument
+pub trait Transport: Send {
+    fn write_line(&mut self, line: &str) -> io::Result<()>;
+    fn read_line(&mut self) -> io::Result<String>;
+    /// Sets how long a single read or write may take
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+    /// Device clear, used to recover before a retry
+    fn clear(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Keeps other programs off the device until `unlock`
+    fn lock_exclusive(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+    fn unlock(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Transport over a VISA session
+pub struct VisaTransport {
+    instrument: Instrument,
+    timeout: Option<Duration>,
+}
+
+impl VisaTransport {
+    pub fn new(instrument: Instrument) -> Self {
+        VisaTransport { instrument, timeout: None }
+    }
+}
+
+impl Transport for VisaTransport {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.instrument.write_all(format!("{}\n", line).as_bytes())
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut response = String::new();
+        {
+            let mut reader = BufReader::new(&self.instrument);
+            reader.read_line(&mut response)?;
+        }
+        Ok(response.trim().to_string())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        // Only touch the session attribute when the timeout actually changes
+        if self.timeout == Some(timeout) {
+            return Ok(());
+        }
+        let millis = timeout.as_millis().min(u32::MAX as u128 - 1);
+        let attr = AttrTmoValue::new_checked(millis as _)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid timeout: {} ms", millis)))?;
+        self.instrument.set_attr(attr).map_err(vs_to_io_err)?;
+        self.timeout = Some(timeout);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.instrument.clear().map_err(vs_to_io_err)
+    }
+
+    fn lock_exclusive(&mut self, timeout: Duration) -> io::Result<()> {
+        self.instrument.lock_exclusive(timeout).map_err(vs_to_io_err)
+    }
+
+    fn unlock(&mut self) -> io::Result<()> {
+        self.instrument.unlock().map_err(vs_to_io_err)
+    }
+}
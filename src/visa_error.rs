@@ -1,6 +1,8 @@
-use std::io::{self};
-use visa_rs::prelude::*;
+use std::io;
 
-pub fn io_to_vs_err(err: std::io::Error) -> visa_rs::Error {
-    visa_rs::io_to_vs_err(err)
-}
\ No newline at end of file
+/// Converts a VISA status error into an io::Error, keeping the VISA message.
+/// Instrument I/O is reported as io::Error everywhere, since not every failure
+/// (parse errors, safety limits, actor timeouts) comes from VISA.
+pub fn vs_to_io_err(err: visa_rs::Error) -> io::Error {
+    io::Error::other(err)
+}
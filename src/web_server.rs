@@ -1,477 +1,357 @@
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::ffi::CString;
-use std::io::{self, BufRead, BufReader, Write};
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::io;
 use std::path::Path;
+use std::time::Duration;
+use tokio::sync::Notify;
 use warp::{Filter, Rejection, Reply};
 use serde::{Deserialize, Serialize};
 use visa_rs::prelude::*;
-use crate::cld1015_osa;
-use crate::n77_wavelength_check;
-use crate::n77_wavelength_sweep;
-use crate::n77_osa;
-use crate::visa_error::io_to_vs_err;
-
-// Pre-set timing values
-const DEFAULT_DWELL_TIME_MS: u64 = 100;        // For current sweep
-const DEFAULT_STABILIZATION_TIME_MS: u64 = 200; // For wavelength check
-const DEFAULT_WAVELENGTH_SWEEP_STABILIZATION_MS: u64 = 10000; // For wavelength sweeps
-
-// Configure CORS to allow all origins
-fn with_cors() -> warp::cors::Builder {
-    warp::cors()
-        .allow_any_origin()
-        .allow_methods(vec!["GET", "POST", "OPTIONS"])
-        .allow_headers(vec!["Content-Type"])
-}
-
-// State shared across handlers
-struct AppState {
-    rm: Option<DefaultRM>,
-    devices: DeviceState,
-}
-
-struct DeviceState {
-    cld1015: Option<Instrument>,
-    n77: Option<Instrument>,
-    power_meter: Option<Instrument>,
-    osa: Option<Instrument>,
-    cld1015_info: String,
-    n77_info: String,
-    power_meter_info: String,
-    osa_info: String,
-}
-
-impl AppState {
-    fn new() -> Self {
-        AppState {
-            rm: None,
-            devices: DeviceState {
-                cld1015: None,
-                n77: None,
-                power_meter: None,
-                osa: None,
-                cld1015_info: String::new(),
-                n77_info: String::new(),
-                power_meter_info: String::new(),
-                osa_info: String::new(),
-            },
-        }
-    }
+use tracing::{info, info_span, warn};
+use crate::actor::{self, InstrumentHandle};
+use crate::audit::{self, AuditEvent};
+use crate::auth::{self, Auth, Identity, Role};
+use crate::calibration::{self, PathCalibration};
+use crate::config;
+use crate::devices::Devices;
+use crate::drivers;
+use crate::estimate::RunEstimate;
+use crate::experiments::{self, BuiltinExperiment, BUILTIN_EXPERIMENTS, estimate_run, experiment_instruments, run_experiment};
+use crate::health::{self, HealthRecord};
+use crate::live::{FeedSummary, LiveFeeds};
+use crate::lock::lock_or_recover;
+use crate::queue::{self, Job, JobQueue, JobRequest, Reservations};
+use crate::recipe::{self, RecipeSummary};
+use crate::reference::{self, Reference};
+use crate::replay::ReplayReport;
+use crate::run_db::{RunFilter, RunRecord};
+use crate::safety;
+use crate::samples::{Sample, SampleDetails, SampleRun};
+use crate::transactions::{self, Transaction};
+
+// Numbers direct runs started within the same second
+static DIRECT_RUNS: AtomicU64 = AtomicU64::new(0);
+
+// Transactions returned when a request doesn't ask for a limit
+const DEFAULT_TRANSACTION_LIMIT: usize = 200;
+
+// Wait between switching the CLD1015's TEC on and its laser
+const TEC_SETTLE: Duration = Duration::from_millis(100);
+
+// A comment line on an idle live stream, so proxies keep it open and a closed viewer is noticed
+const LIVE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+
+#[derive(Deserialize)]
+struct LoginParams {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct LoginResult {
+    success: bool,
+    /// Sent back as `Authorization: Bearer <token>`
+    token: Option<String>,
+    user: Option<Identity>,
+    error: Option<String>,
 }
 
 #[derive(Serialize)]
 struct ConnectionStatus {
     connected: bool,
+    /// An experiment is using the device, info is the last known identity
+    busy: bool,
     info: Option<String>,
     error: Option<String>,
 }
 
+/// Result of a manual control request, the reading's fields are inlined on success
+#[derive(Serialize)]
+struct ControlResult<T> {
+    success: bool,
+    #[serde(flatten)]
+    reading: Option<T>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CurrentReading {
+    current_ma: f64,
+    output: bool,
+}
+
+#[derive(Serialize)]
+struct WavelengthReading {
+    channel: u8,
+    wavelength_nm: f64,
+    output: bool,
+}
+
+#[derive(Serialize)]
+struct PowerReading {
+    port: u8,
+    power_dbm: f64,
+}
+
+#[derive(Serialize)]
+struct PeakReading {
+    wavelength_nm: f64,
+    power_dbm: f64,
+}
+
+#[derive(Serialize)]
+struct ScpiResponse {
+    response: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SetCurrentParams {
+    current_ma: f64,
+    /// Switches the laser output, left as is when missing
+    output: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct SetWavelengthParams {
+    wavelength_nm: f64,
+    /// Switches the channel output, left as is when missing
+    output: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ScpiParams {
+    /// Commands containing '?' are sent as queries
+    command: String,
+}
+
+#[derive(Serialize)]
+struct DisconnectResult {
+    success: bool,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct InstrumentStatus {
+    name: &'static str,
+    label: &'static str,
+    resource: &'static str,
+    connected: bool,
+    busy: bool,
+    idn: String,
+    #[serde(flatten)]
+    health: HealthRecord,
+}
+
 #[derive(Serialize)]
 struct ExperimentResult {
     success: bool,
     result_path: Option<String>,
     error: Option<String>,
+    /// Names the run's transaction log
+    run_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EstimateResult {
+    /// False when the parameters are invalid or the run would take too long
+    success: bool,
+    estimate: Option<RunEstimate>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LiveFeedsResult {
+    success: bool,
+    feeds: Vec<FeedSummary>,
+}
+
+#[derive(Serialize)]
+struct TransactionsResult {
+    success: bool,
+    transactions: Vec<Transaction>,
+    error: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct CurrentSweepParams {
-    start_ma: f64,
-    stop_ma: f64,
-    step_ma: f64,
+struct TransactionsQuery {
+    instrument: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct AuditResult {
+    success: bool,
+    events: Vec<AuditEvent>,
+    error: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct WavelengthCheckParams {
-    wavelength: f64,
+struct AuditQuery {
+    instrument: Option<String>,
+    /// Milliseconds since the Unix epoch
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct RunsResult {
+    success: bool,
+    runs: Vec<RunRecord>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RunResult {
+    success: bool,
+    run: Option<RunRecord>,
+    error: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct WavelengthSweepParams {
-    start_nm: f64,
-    stop_nm: f64,
-    step_nm: f64,
+struct NewSampleParams {
+    sample_id: String,
+    #[serde(flatten)]
+    details: SampleDetails,
 }
 
-// Filter to inject state
-fn with_state(
-    state: Arc<Mutex<AppState>>,
-) -> impl Filter<Extract = (Arc<Mutex<AppState>>,), Error = Infallible> + Clone {
-    warp::any().map(move || state.clone())
+#[derive(Serialize)]
+struct SampleResult {
+    success: bool,
+    sample: Option<Sample>,
+    error: Option<String>,
 }
 
-// Device connection helper functions
-fn check_cld1015(state: &mut AppState) -> std::result::Result<String, String> {
-    let resource = CString::new("USB::4883::32847::M01053290::0::INSTR").unwrap();
-    
-    // Check if resource manager is initialized
-    if state.rm.is_none() {
-        state.rm = match DefaultRM::new() {
-            Ok(rm) => Some(rm),
-            Err(err) => return Err(format!("Failed to init VISA resource manager: {}", err)),
-        };
-    }
-    
-    let rm = state.rm.as_ref().unwrap();
-    
-    // Open device if not already open
-    if state.devices.cld1015.is_none() {
-        state.devices.cld1015 = match rm.open(
-            &resource.into(),
-            AccessMode::NO_LOCK,
-            Duration::from_secs(1),
-        ) {
-            Ok(inst) => Some(inst),
-            Err(err) => return Err(format!("Failed to open CLD1015: {}", err)),
-        };
-    }
-    
-    // Get the device
-    let device = state.devices.cld1015.as_mut().unwrap();
-    
-    // Clear any errors
-    if let Err(err) = device.write_all(b"*CLS\n") {
-        return Err(format!("Failed to clear CLD1015: {}", err));
-    }
-    
-    // Query device identity
-    if let Err(err) = device.write_all(b"*IDN?\n") {
-        return Err(format!("Failed to query CLD1015: {}", err));
-    }
-    
-    // Read the response
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&mut *device);
-        if let Err(err) = reader.read_line(&mut response) {
-            return Err(format!("Failed to read CLD1015 response: {}", err));
-        }
-    }
-    
-    // Save info
-    state.devices.cld1015_info = response.trim().to_string();
-    Ok(response.trim().to_string())
+#[derive(Serialize)]
+struct SamplesResult {
+    success: bool,
+    samples: Vec<Sample>,
+    error: Option<String>,
 }
 
-fn check_n77(state: &mut AppState) -> std::result::Result<String, String> {
-    let resource = CString::new("GPIB0::21::INSTR").unwrap();
-    
-    // Check if resource manager is initialized
-    if state.rm.is_none() {
-        state.rm = match DefaultRM::new() {
-            Ok(rm) => Some(rm),
-            Err(err) => return Err(format!("Failed to init VISA resource manager: {}", err)),
-        };
-    }
-    
-    let rm = state.rm.as_ref().unwrap();
-    
-    // Open device if not already open
-    if state.devices.n77.is_none() {
-        state.devices.n77 = match rm.open(
-            &resource.into(),
-            AccessMode::NO_LOCK,
-            Duration::from_secs(1),
-        ) {
-            Ok(inst) => Some(inst),
-            Err(err) => return Err(format!("Failed to open N77: {}", err)),
-        };
-    }
-    
-    // Get the device
-    let device = state.devices.n77.as_mut().unwrap();
-    
-    // Clear any errors
-    if let Err(err) = device.write_all(b"*CLS\n") {
-        return Err(format!("Failed to clear N77: {}", err));
-    }
-    
-    // Query device identity
-    if let Err(err) = device.write_all(b"*IDN?\n") {
-        return Err(format!("Failed to query N77: {}", err));
-    }
-    
-    // Read the response
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&mut *device);
-        if let Err(err) = reader.read_line(&mut response) {
-            return Err(format!("Failed to read N77 response: {}", err));
-        }
-    }
-    
-    // Save info
-    state.devices.n77_info = response.trim().to_string();
-    Ok(response.trim().to_string())
+#[derive(Deserialize)]
+struct SampleHistoryQuery {
+    experiment: Option<String>,
 }
 
-fn check_power_meter(state: &mut AppState) -> std::result::Result<String, String> {
-    let resource = CString::new("GPIB0::16::INSTR").unwrap();
-    
-    // Check if resource manager is initialized
-    if state.rm.is_none() {
-        state.rm = match DefaultRM::new() {
-            Ok(rm) => Some(rm),
-            Err(err) => return Err(format!("Failed to init VISA resource manager: {}", err)),
-        };
-    }
-    
-    let rm = state.rm.as_ref().unwrap();
-    
-    // Open device if not already open
-    if state.devices.power_meter.is_none() {
-        state.devices.power_meter = match rm.open(
-            &resource.into(),
-            AccessMode::NO_LOCK,
-            Duration::from_secs(1),
-        ) {
-            Ok(inst) => Some(inst),
-            Err(err) => return Err(format!("Failed to open Power Meter: {}", err)),
-        };
-    }
-    
-    // Get the device
-    let device = state.devices.power_meter.as_mut().unwrap();
-    
-    // Query device identity
-    if let Err(err) = device.write_all(b"*IDN?\n") {
-        return Err(format!("Failed to query Power Meter: {}", err));
-    }
-    
-    // Read the response
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&mut *device);
-        if let Err(err) = reader.read_line(&mut response) {
-            return Err(format!("Failed to read Power Meter response: {}", err));
-        }
-    }
-    
-    // Save info
-    state.devices.power_meter_info = response.trim().to_string();
-    Ok(response.trim().to_string())
+#[derive(Serialize)]
+struct SampleHistoryResult {
+    success: bool,
+    runs: Vec<SampleRun>,
+    error: Option<String>,
 }
 
-fn check_osa(state: &mut AppState) -> std::result::Result<String, String> {
-    let resource = CString::new("GPIB0::23::INSTR").unwrap();
-    
-    // Check if resource manager is initialized
-    if state.rm.is_none() {
-        state.rm = match DefaultRM::new() {
-            Ok(rm) => Some(rm),
-            Err(err) => return Err(format!("Failed to init VISA resource manager: {}", err)),
-        };
-    }
-    
-    let rm = state.rm.as_ref().unwrap();
-    
-    // Open device if not already open
-    if state.devices.osa.is_none() {
-        state.devices.osa = match rm.open(
-            &resource.into(),
-            AccessMode::NO_LOCK,
-            Duration::from_secs(1),
-        ) {
-            Ok(inst) => Some(inst),
-            Err(err) => return Err(format!("Failed to open OSA: {}", err)),
-        };
-    }
-    
-    // Get the device
-    let device = state.devices.osa.as_mut().unwrap();
-    
-    // Clear any errors
-    if let Err(err) = device.write_all(b"CLS;\n") {
-        return Err(format!("Failed to clear OSA: {}", err));
-    }
-    
-    // Query device identity
-    if let Err(err) = device.write_all(b"ID?;\n") {
-        return Err(format!("Failed to query OSA: {}", err));
+#[derive(Serialize)]
+struct CalibrationResult {
+    success: bool,
+    calibration: Option<PathCalibration>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReferenceResult {
+    success: bool,
+    reference: Option<Reference>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct QueueResult {
+    success: bool,
+    job_ids: Vec<u64>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MoveJobParams {
+    /// New position among the pending jobs, 0 runs next
+    position: usize,
+}
+
+#[derive(Serialize)]
+struct ExperimentList {
+    builtin: &'static [BuiltinExperiment],
+    recipes: Vec<RecipeSummary>,
+}
+
+
+// Filter to inject any other shared handle
+fn with_shared<T: Send + Sync + 'static>(
+    shared: Arc<T>,
+) -> impl Filter<Extract = (Arc<T>,), Error = Infallible> + Clone {
+    warp::any().map(move || shared.clone())
+}
+
+// Filter admitting callers with at least `role`, for handlers that don't need to know who they are
+fn with_role(auth: Arc<Auth>, role: Role) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    auth::require(auth, role).map(|_| ()).untuple_one()
+}
+
+// Handler for logging in with a user name and password
+async fn login_handler(params: LoginParams, auth: Arc<Auth>) -> std::result::Result<impl Reply, Rejection> {
+    // Password hashing is deliberately slow, keep it off the async workers
+    let result = tokio::task::spawn_blocking(move || auth.login(&params.username, &params.password))
+        .await
+        .unwrap_or_else(|err| Err(format!("Login panicked: {}", err)));
+
+    match result {
+        Ok((token, user)) => {
+            info!(user = %user.name, "Logged in");
+            Ok(warp::reply::json(&LoginResult { success: true, token: Some(token), user: Some(user), error: None }))
+        },
+        Err(err) => Ok(warp::reply::json(&LoginResult { success: false, token: None, user: None, error: Some(err) })),
     }
-    
-    // Read the response
-    let mut response = String::new();
-    {
-        let mut reader = BufReader::new(&mut *device);
-        if let Err(err) = reader.read_line(&mut response) {
-            return Err(format!("Failed to read OSA response: {}", err));
-        }
+}
+
+// Handler for ending the caller's session
+async fn logout_handler(header: Option<String>, auth: Arc<Auth>) -> std::result::Result<impl Reply, Rejection> {
+    if let Some(token) = auth::bearer(header.as_deref()) {
+        auth.logout(token);
     }
-    
-    // Save info
-    state.devices.osa_info = response.trim().to_string();
-    Ok(response.trim().to_string())
+    Ok(warp::reply::json(&DisconnectResult { success: true, error: None }))
 }
 
 // Handler for checking device connection
 async fn check_connection_handler(
     device: String,
-    state: Arc<Mutex<AppState>>,
+    devices: Arc<Devices>,
 ) -> std::result::Result<impl Reply, Rejection> {
-    let mut state_guard = match state.lock() {
-        Ok(guard) => guard,
-        Err(poisoned) => poisoned.into_inner(), // Recover from poison
-    };
-    
-    let result = match device.as_str() {
-        "cld1015" => check_cld1015(&mut state_guard),
-        "n77" => check_n77(&mut state_guard),
-        "power_meter" => check_power_meter(&mut state_guard),
-        "osa" => check_osa(&mut state_guard),
-        _ => Err(format!("Unknown device: {}", device)),
+    // Opening a device can take up to the VISA timeout, keep it off the async workers
+    let result = {
+        let devices = devices.clone();
+        let device = device.clone();
+        tokio::task::spawn_blocking(move || devices.connect(&device))
+            .await
+            .unwrap_or_else(|err| Err(format!("Connection check panicked: {}", err)))
     };
+    let busy = devices.slot(&device).is_some_and(|slot| slot.status().busy);
     
     match result {
         Ok(info) => Ok(warp::reply::json(&ConnectionStatus {
             connected: true,
+            busy,
             info: Some(info),
             error: None,
         })),
         Err(err) => Ok(warp::reply::json(&ConnectionStatus {
             connected: false,
+            busy,
             info: None,
             error: Some(err),
         })),
     }
 }
 
-// Run experiment functions
-fn run_current_sweep(
-    state: &mut AppState,
-    params: CurrentSweepParams,
-) -> std::result::Result<(), String> {
-    // Check if devices are connected
-    if state.devices.cld1015.is_none() || state.devices.osa.is_none() {
-        return Err("CLD1015 or OSA not connected".to_string());
-    }
-    
-    // Validate parameters
-    if params.start_ma < 0.0 || params.start_ma > 100.0 ||
-       params.stop_ma < 0.0 || params.stop_ma > 100.0 || 
-       params.stop_ma <= params.start_ma || params.step_ma <= 0.0 {
-        return Err("Invalid current sweep parameters".to_string());
-    }
-    
-    // Run experiment with pre-set dwell time
-    let cld1015 = state.devices.cld1015.as_mut().unwrap();
-    let osa = state.devices.osa.as_mut().unwrap();
-    
-    cld1015_osa::run_current_sweep(
-        cld1015,
-        osa,
-        params.start_ma,
-        params.stop_ma,
-        params.step_ma,
-        DEFAULT_DWELL_TIME_MS, 
-    ).map_err(|e| format!("Experiment failed: {}", e))
-}
-
-fn run_wavelength_check(
-    state: &mut AppState,
-    params: WavelengthCheckParams,
-) -> std::result::Result<(), String> {
-    // Check if devices are connected
-    if state.devices.n77.is_none() || state.devices.power_meter.is_none() {
-        return Err("N77 laser or power meter not connected".to_string());
-    }
-    
-    // Validate parameters
-    if params.wavelength < 1527.60 || params.wavelength > 1570.01 {
-        return Err("Invalid wavelength check parameters".to_string());
-    }
-    
-    // Run experiment with pre-set stabilization time
-    let n77 = state.devices.n77.as_mut().unwrap();
-    let power_meter = state.devices.power_meter.as_mut().unwrap();
-    
-    n77_wavelength_check::run_wavelength_check(
-        n77,
-        power_meter,
-        params.wavelength,
-        DEFAULT_STABILIZATION_TIME_MS, 
-    ).map_err(|e| format!("Experiment failed: {}", e))
-}
-
-fn run_wavelength_sweep(
-    state: &mut AppState,
-    params: WavelengthSweepParams,
-) -> std::result::Result<(), String> {
-    // Check if devices are connected
-    if state.devices.n77.is_none() || state.devices.power_meter.is_none() {
-        return Err("N77 laser or power meter not connected".to_string());
-    }
-    
-    // Validate parameters
-    if params.start_nm < 1527.60 || params.start_nm > 1570.01 ||
-       params.stop_nm < 1527.60 || params.stop_nm > 1570.01 || 
-       params.stop_nm <= params.start_nm || params.step_nm <= 0.0 {
-        return Err("Invalid wavelength sweep parameters".to_string());
-    }
-    
-    // Check number of points (max 9)
-    let num_points = ((params.stop_nm - params.start_nm) / params.step_nm).floor() as usize + 1;
-    if num_points > 9 {
-        return Err(format!("Too many data points: {}. Maximum allowed is 9.", num_points));
-    }
-    
-    // Run experiment with pre-set stabilization time
-    let n77 = state.devices.n77.as_mut().unwrap();
-    let power_meter = state.devices.power_meter.as_mut().unwrap();
-    
-    n77_wavelength_sweep::run_wavelength_sweep(
-        n77,
-        power_meter,
-        params.start_nm,
-        params.stop_nm,
-        params.step_nm,
-        DEFAULT_WAVELENGTH_SWEEP_STABILIZATION_MS, 
-    ).map_err(|e| format!("Experiment failed: {}", e))
-}
-
-fn run_wavelength_sweep_osa(
-    state: &mut AppState,
-    params: WavelengthSweepParams,
-) -> std::result::Result<(), String> {
-    // Check if devices are connected
-    if state.devices.n77.is_none() || state.devices.osa.is_none() {
-        return Err("N77 laser or OSA not connected".to_string());
-    }
-    
-    // Validate parameters
-    if params.start_nm < 1527.60 || params.start_nm > 1570.01 ||
-       params.stop_nm < 1527.60 || params.stop_nm > 1570.01 || 
-       params.stop_nm <= params.start_nm || params.step_nm <= 0.0 {
-        return Err("Invalid wavelength sweep parameters".to_string());
-    }
-    
-    // Check number of points (max 9)
-    let num_points = ((params.stop_nm - params.start_nm) / params.step_nm).floor() as usize + 1;
-    if num_points > 9 {
-        return Err(format!("Too many data points: {}. Maximum allowed is 9.", num_points));
-    }
-    
-    // Run experiment with pre-set stabilization time
-    let n77 = state.devices.n77.as_mut().unwrap();
-    let osa = state.devices.osa.as_mut().unwrap();
-    
-    n77_osa::run_wavelength_sweep_osa(
-        n77,
-        osa,
-        params.start_nm,
-        params.stop_nm,
-        params.step_nm,
-        DEFAULT_WAVELENGTH_SWEEP_STABILIZATION_MS, 
-    ).map_err(|e| format!("Experiment failed: {}", e))
-}
 
 // Handler for running experiments
 async fn run_experiment_handler(
     experiment: String, 
+    identity: Identity,
     body: warp::hyper::body::Bytes, 
-    state: Arc<Mutex<AppState>>,
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
 ) -> std::result::Result<impl Reply, Rejection> {
     // Decode request body
     let params_json = match serde_json::from_slice(&body) {
@@ -481,58 +361,41 @@ async fn run_experiment_handler(
                 success: false,
                 result_path: None,
                 error: Some(format!("Invalid request body: {}", err)),
+                run_id: None,
             }));
         }
     };
     
-    // Run the experiment
-    let mut state_guard = match state.lock() {
-        Ok(guard) => guard,
-        Err(poisoned) => poisoned.into_inner(), // Recover from poison
-    };
+    let run_id = format!("direct-{}-{}", queue::now_secs(), DIRECT_RUNS.fetch_add(1, Ordering::Relaxed));
     
-    let result = match experiment.as_str() {
-        "current_sweep" => {
-            match serde_json::from_value::<CurrentSweepParams>(params_json) {
-                Ok(params) => {
-                    run_current_sweep(&mut state_guard, params).map(|_| {
-                        "current_sweep_results.csv and current_sweep_trace_data/".to_string()
-                    })
-                },
-                Err(err) => Err(format!("Invalid parameters: {}", err)),
-            }
-        },
-        "wavelength_check" => {
-            match serde_json::from_value::<WavelengthCheckParams>(params_json) {
-                Ok(params) => {
-                    run_wavelength_check(&mut state_guard, params).map(|_| {
-                        "wavelength_check_result.csv".to_string()
-                    })
-                },
-                Err(err) => Err(format!("Invalid parameters: {}", err)),
-            }
-        },
-        "wavelength_sweep" => {
-            match serde_json::from_value::<WavelengthSweepParams>(params_json) {
-                Ok(params) => {
-                    run_wavelength_sweep(&mut state_guard, params).map(|_| {
-                        "wavelength_sweep_results.csv".to_string()
-                    })
-                },
-                Err(err) => Err(format!("Invalid parameters: {}", err)),
-            }
-        },
-        "wavelength_sweep_osa" => {
-            match serde_json::from_value::<WavelengthSweepParams>(params_json) {
-                Ok(params) => {
-                    run_wavelength_sweep_osa(&mut state_guard, params).map(|_| {
-                        "wavelength_sweep_trace_results.csv and wavelength_sweep_trace_data/".to_string()
-                    })
-                },
-                Err(err) => Err(format!("Invalid parameters: {}", err)),
-            }
+    // Reserve the instruments so a queued job can't use them at the same time
+    let result = match experiment_instruments(&experiment, &params_json)
+        .and_then(|names| reservations.try_reserve(&names, "a direct request"))
+    {
+        // Run the experiment on a blocking thread so other requests keep being served
+        Ok(guard) => {
+            let run_id = run_id.clone();
+            tokio::task::spawn_blocking(move || {
+                let _audit = audit::enter(audit::Context {
+                    user: Some(identity.name.clone()),
+                    run_id: Some(run_id.clone()),
+                    ..Default::default()
+                });
+                let result = run_experiment(
+                    &devices,
+                    &run_id,
+                    &config::get().data_dir,
+                    &experiment,
+                    params_json,
+                    Some(&identity.name),
+                );
+                drop(guard);
+                result
+            })
+            .await
+            .unwrap_or_else(|err| Err(format!("Experiment panicked: {}", err)))
         },
-        _ => Err(format!("Unknown experiment: {}", experiment)),
+        Err(err) => Err(err),
     };
     
     match result {
@@ -540,15 +403,582 @@ async fn run_experiment_handler(
             success: true,
             result_path: Some(path),
             error: None,
+            run_id: Some(run_id),
         })),
         Err(err) => Ok(warp::reply::json(&ExperimentResult {
             success: false,
             result_path: None,
             error: Some(err),
+            run_id: Some(run_id),
         })),
     }
 }
 
+// Handler for estimating a run's duration before it is started
+async fn estimate_handler(experiment: String, params_json: serde_json::Value) -> std::result::Result<impl Reply, Rejection> {
+    let result = match estimate_run(&experiment, &params_json) {
+        Ok(estimate) => match estimate.check() {
+            Ok(()) => EstimateResult { success: true, estimate: Some(estimate), error: None },
+            Err(err) => EstimateResult { success: false, estimate: Some(estimate), error: Some(err) },
+        },
+        Err(err) => EstimateResult { success: false, estimate: None, error: Some(err) },
+    };
+    Ok(warp::reply::json(&result))
+}
+
+// Handler for listing the live feeds of running and recent time series
+async fn list_live_handler(live: Arc<LiveFeeds>) -> std::result::Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&LiveFeedsResult { success: true, feeds: live.list() }))
+}
+
+// Handler streaming a run's readings as server-sent events, every reading so far and then each new one.
+// Each event's data is a JSON array in the order of the feed's columns, an `end` event follows the last.
+async fn live_stream_handler(
+    run_id: String,
+    live: Arc<LiveFeeds>,
+) -> std::result::Result<warp::reply::Response, Rejection> {
+    let Some(feed) = live.get(&run_id) else {
+        return Ok(warp::reply::json(&DisconnectResult {
+            success: false,
+            error: Some(format!("No live feed for run {}", run_id)),
+        }).into_response());
+    };
+
+    let (mut sender, body) = warp::hyper::Body::channel();
+    tokio::spawn(async move {
+        let mut changes = feed.subscribe();
+        let mut sent = 0;
+        loop {
+            let (rows, done) = feed.since(sent);
+            for row in rows {
+                let event = format!("data: {}\n\n", serde_json::Value::from(row));
+                if sender.send_data(event.into()).await.is_err() {
+                    return; // Viewer went away
+                }
+                sent += 1;
+            }
+            if done {
+                let _ = sender.send_data("event: end\ndata: {}\n\n".into()).await;
+                return;
+            }
+            match tokio::time::timeout(LIVE_KEEPALIVE, changes.changed()).await {
+                Ok(Ok(())) => {},
+                Ok(Err(_)) => return,
+                Err(_) => {
+                    if sender.send_data(": keepalive\n\n".into()).await.is_err() {
+                        return;
+                    }
+                },
+            }
+        }
+    });
+
+    let mut response = warp::reply::Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(warp::http::header::CONTENT_TYPE, warp::http::HeaderValue::from_static("text/event-stream"));
+    headers.insert(warp::http::header::CACHE_CONTROL, warp::http::HeaderValue::from_static("no-cache"));
+    Ok(response)
+}
+
+// Handler for listing the queue
+async fn list_queue_handler(
+    queue: Arc<Mutex<JobQueue>>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let queue = lock_or_recover(&queue);
+    Ok(warp::reply::json(&queue.jobs()))
+}
+
+// Handler for submitting one or more jobs to the queue
+async fn submit_jobs_handler(
+    identity: Identity,
+    requests: Vec<JobRequest>,
+    queue: Arc<Mutex<JobQueue>>,
+    notify: Arc<Notify>,
+    devices: Arc<Devices>,
+) -> std::result::Result<impl Reply, Rejection> {
+    // Validate the whole batch before queueing any of it
+    let mut batch = Vec::new();
+    for request in requests {
+        let checked = devices.runs.require_sample(&request.params)
+            .and_then(|_| estimate_run(&request.experiment, &request.params)?.check())
+            .and_then(|_| experiment_instruments(&request.experiment, &request.params));
+        match checked {
+            Ok(instruments) => batch.push((request, instruments)),
+            Err(err) => {
+                return Ok(warp::reply::json(&QueueResult {
+                    success: false,
+                    job_ids: Vec::new(),
+                    error: Some(err),
+                }));
+            }
+        }
+    }
+
+    let mut queue = lock_or_recover(&queue);
+    let job_ids = batch.into_iter()
+        .map(|(request, instruments)| queue.submit(request, instruments, &identity.name))
+        .collect();
+    queue.save();
+    notify.notify_one();
+
+    Ok(warp::reply::json(&QueueResult {
+        success: true,
+        job_ids,
+        error: None,
+    }))
+}
+
+// Handler for cancelling a pending job
+async fn cancel_job_handler(
+    id: u64,
+    queue: Arc<Mutex<JobQueue>>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let mut queue = lock_or_recover(&queue);
+    let result = queue.cancel(id);
+    if result.is_ok() {
+        queue.save();
+    }
+
+    Ok(warp::reply::json(&QueueResult {
+        success: result.is_ok(),
+        job_ids: vec![id],
+        error: result.err(),
+    }))
+}
+
+// Handler for moving a pending job to another place in the queue
+async fn move_job_handler(
+    id: u64,
+    params: MoveJobParams,
+    queue: Arc<Mutex<JobQueue>>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let mut queue = lock_or_recover(&queue);
+    let result = queue.move_to(id, params.position);
+    if result.is_ok() {
+        queue.save();
+    }
+
+    Ok(warp::reply::json(&QueueResult {
+        success: result.is_ok(),
+        job_ids: vec![id],
+        error: result.err(),
+    }))
+}
+
+// Runs a manual command on one device, refused while an experiment has it reserved
+async fn control<T, F>(
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
+    name: String,
+    user: Option<String>,
+    command: F,
+) -> std::result::Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&InstrumentHandle) -> io::Result<T> + Send + 'static,
+{
+    let guard = reservations.try_reserve(std::slice::from_ref(&name), "manual control")?;
+    tokio::task::spawn_blocking(move || {
+        let _audit = audit::enter(audit::Context { user, ..Default::default() });
+        let locked = devices.lock(&[name.as_str()])?;
+        let device = locked.get(&name).ok_or_else(|| format!("{} not connected", name))?;
+        let result = command(device).map_err(|e| format!("{} command failed: {}", name, e));
+        drop(locked);
+        drop(guard);
+        result
+    })
+    .await
+    .unwrap_or_else(|err| Err(format!("Manual command panicked: {}", err)))
+}
+
+fn control_reply<T: Serialize>(result: std::result::Result<T, String>) -> warp::reply::Json {
+    match result {
+        Ok(reading) => warp::reply::json(&ControlResult { success: true, reading: Some(reading), error: None }),
+        Err(err) => warp::reply::json(&ControlResult::<T> { success: false, reading: None, error: Some(err) }),
+    }
+}
+
+fn read_current(cld: &InstrumentHandle) -> io::Result<CurrentReading> {
+    Ok(CurrentReading {
+        current_ma: drivers::cld1015::read_current_ma(cld)?,
+        output: drivers::cld1015::read_laser_output(cld)?,
+    })
+}
+
+fn read_wavelength(laser: &InstrumentHandle, channel: u8) -> io::Result<WavelengthReading> {
+    Ok(WavelengthReading {
+        channel,
+        wavelength_nm: drivers::n77::read_channel_wavelength_nm(laser, channel)?,
+        output: drivers::n77::read_channel_output(laser, channel)?,
+    })
+}
+
+fn check_channel(name: &str, channel: u8, range: &std::ops::RangeInclusive<u8>) -> std::result::Result<(), String> {
+    if range.contains(&channel) {
+        Ok(())
+    } else {
+        Err(format!("{} {} does not exist ({}-{})", name, channel, range.start(), range.end()))
+    }
+}
+
+// Handler for reading the CLD1015 current
+async fn get_current_handler(
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
+) -> std::result::Result<impl Reply, Rejection> {
+    Ok(control_reply(control(devices, reservations, "cld1015".to_string(), None, read_current).await))
+}
+
+// Handler for setting the CLD1015 current
+async fn set_current_handler(
+    identity: Identity,
+    params: SetCurrentParams,
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
+) -> std::result::Result<impl Reply, Rejection> {
+    if let Err(err) = safety::check("Current", "mA", &safety::CLD1015_CURRENT_MA, params.current_ma) {
+        return Ok(control_reply::<CurrentReading>(Err(err)));
+    }
+
+    let result = control(devices, reservations, "cld1015".to_string(), Some(identity.name), move |cld| {
+        drivers::cld1015::configure(cld)?;
+        drivers::cld1015::set_current_ma(cld, params.current_ma)?;
+        if let Some(on) = params.output {
+            // TEC first so the diode never runs unregulated, as the experiments do
+            if on {
+                drivers::cld1015::set_tec_output(cld, true)?;
+                std::thread::sleep(TEC_SETTLE);
+            }
+            drivers::cld1015::set_laser_output(cld, on)?;
+        }
+        read_current(cld)
+    })
+    .await;
+    Ok(control_reply(result))
+}
+
+// Handler for reading an N77 channel wavelength
+async fn get_wavelength_handler(
+    channel: u8,
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
+) -> std::result::Result<impl Reply, Rejection> {
+    if let Err(err) = check_channel("N77 channel", channel, &drivers::n77::CHANNELS) {
+        return Ok(control_reply::<WavelengthReading>(Err(err)));
+    }
+    let result = control(devices, reservations, "n77".to_string(), None, move |laser| read_wavelength(laser, channel)).await;
+    Ok(control_reply(result))
+}
+
+// Handler for parking an N77 channel at a wavelength
+async fn set_wavelength_handler(
+    channel: u8,
+    identity: Identity,
+    params: SetWavelengthParams,
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
+) -> std::result::Result<impl Reply, Rejection> {
+    if let Err(err) = check_channel("N77 channel", channel, &drivers::n77::CHANNELS)
+        .and_then(|_| safety::check("Wavelength", "nm", &safety::N77_WAVELENGTH_NM, params.wavelength_nm))
+    {
+        return Ok(control_reply::<WavelengthReading>(Err(err)));
+    }
+
+    let result = control(devices, reservations, "n77".to_string(), Some(identity.name), move |laser| {
+        drivers::n77::set_channel_wavelength_nm(laser, channel, params.wavelength_nm)?;
+        if let Some(on) = params.output {
+            drivers::n77::set_channel_output(laser, channel, on)?;
+        }
+        read_wavelength(laser, channel)
+    })
+    .await;
+    Ok(control_reply(result))
+}
+
+// Handler for reading one power meter port
+async fn get_power_handler(
+    port: u8,
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
+) -> std::result::Result<impl Reply, Rejection> {
+    if let Err(err) = check_channel("Power meter port", port, &drivers::power_meter::PORTS) {
+        return Ok(control_reply::<PowerReading>(Err(err)));
+    }
+    let result = control(devices, reservations, "power_meter".to_string(), None, move |meter| {
+        Ok(PowerReading { port, power_dbm: drivers::power_meter::read_port_power_dbm(meter, port)? })
+    })
+    .await;
+    Ok(control_reply(result))
+}
+
+// Handler for taking an OSA sweep and reading the peak
+async fn get_peak_handler(
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let result = control(devices, reservations, "osa".to_string(), None, |osa| {
+        drivers::osa::take_sweep(osa)?;
+        let (wavelength_nm, power_dbm) = drivers::osa::read_peak(osa)?;
+        Ok(PeakReading { wavelength_nm, power_dbm })
+    })
+    .await;
+    Ok(control_reply(result))
+}
+
+// Handler for raw SCPI, bypasses the safety checks so the route is admin only
+async fn scpi_handler(
+    device: String,
+    identity: Identity,
+    params: ScpiParams,
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
+) -> std::result::Result<impl Reply, Rejection> {
+    info!(instrument = %device, command = %params.command, user = %identity.name, "Raw SCPI");
+    let result = control(devices, reservations, device, Some(identity.name), move |inst| {
+        // Audited before it's sent, a query can carry a setting too, e.g. "OUTP 1;*OPC?", so it isn't retried either
+        audit::raw_command(inst.name(), &params.command);
+        if params.command.contains('?') {
+            Ok(ScpiResponse { response: Some(inst.query_once(&params.command, actor::DEFAULT_TIMEOUT)?) })
+        } else {
+            inst.write(&params.command)?;
+            Ok(ScpiResponse { response: None })
+        }
+    })
+    .await;
+    Ok(control_reply(result))
+}
+
+// Handler for closing a device so other programs can use it
+async fn disconnect_handler(
+    device: String,
+    devices: Arc<Devices>,
+    reservations: Arc<Reservations>,
+) -> std::result::Result<impl Reply, Rejection> {
+    // Refuse while a job has reserved the device, even if it hasn't started talking to it yet
+    let names = vec![device.clone()];
+    let result = reservations
+        .try_reserve(&names, "a disconnect request")
+        .and_then(|_guard| devices.disconnect(&device));
+    Ok(warp::reply::json(&DisconnectResult {
+        success: result.is_ok(),
+        error: result.err(),
+    }))
+}
+
+// Handler for the health of every instrument, including recent state changes
+async fn instruments_status_handler(devices: Arc<Devices>) -> std::result::Result<impl Reply, Rejection> {
+    let statuses: Vec<InstrumentStatus> = devices
+        .slots()
+        .iter()
+        .map(|slot| {
+            let status = slot.status();
+            InstrumentStatus {
+                name: slot.info.name,
+                label: slot.info.label,
+                resource: slot.info.resource,
+                connected: status.connected,
+                busy: status.busy,
+                idn: status.idn,
+                health: slot.health(),
+            }
+        })
+        .collect();
+    Ok(warp::reply::json(&statuses))
+}
+
+fn transactions_reply(result: io::Result<Vec<Transaction>>) -> warp::reply::Json {
+    match result {
+        Ok(transactions) => warp::reply::json(&TransactionsResult { success: true, transactions, error: None }),
+        Err(err) => warp::reply::json(&TransactionsResult {
+            success: false,
+            transactions: Vec::new(),
+            error: Some(format!("Failed to read transaction log: {}", err)),
+        }),
+    }
+}
+
+// Handler for the full transaction log of one run
+async fn run_transactions_handler(run_id: String) -> std::result::Result<impl Reply, Rejection> {
+    if !transactions::is_valid_run_id(&run_id) {
+        return Ok(transactions_reply(Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid run id"))));
+    }
+    Ok(transactions_reply(transactions::read_log(&transactions::run_log_path(&run_id))))
+}
+
+// Handler for the most recent transactions of the server log
+async fn recent_transactions_handler(query: TransactionsQuery) -> std::result::Result<impl Reply, Rejection> {
+    let limit = query.limit.unwrap_or(DEFAULT_TRANSACTION_LIMIT);
+    Ok(transactions_reply(transactions::recent(query.instrument.as_deref(), limit)))
+}
+
+// Handler for re-running a recorded run against its own transaction log, without hardware
+async fn replay_handler(
+    source_run_id: String,
+    identity: Identity,
+    devices: Arc<Devices>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let report = {
+        let source_run_id = source_run_id.clone();
+        tokio::task::spawn_blocking(move || {
+            experiments::replay_run(&source_run_id, devices.transactions.clone(), Some(&identity.name))
+        })
+        .await
+        .map_err(|err| format!("Replay panicked: {}", err))
+    };
+
+    match report {
+        Ok(report) => Ok(warp::reply::json(&report)),
+        Err(err) => Ok(warp::reply::json(&ReplayReport {
+            success: false,
+            source_run_id,
+            run_id: String::new(),
+            output_dir: String::new(),
+            replayed: 0,
+            unplayed: 0,
+            mismatches: Vec::new(),
+            error: Some(err),
+        })),
+    }
+}
+
+// Handler for the laser audit trail, optionally for one instrument and time range
+async fn audit_handler(query: AuditQuery) -> std::result::Result<impl Reply, Rejection> {
+    match audit::query(query.instrument.as_deref(), query.from, query.to) {
+        Ok(events) => Ok(warp::reply::json(&AuditResult { success: true, events, error: None })),
+        Err(err) => Ok(warp::reply::json(&AuditResult {
+            success: false,
+            events: Vec::new(),
+            error: Some(format!("Failed to read audit log: {}", err)),
+        })),
+    }
+}
+
+// Handler for searching the run database, newest runs first
+async fn search_runs_handler(filter: RunFilter, devices: Arc<Devices>) -> std::result::Result<impl Reply, Rejection> {
+    let result = tokio::task::spawn_blocking(move || devices.runs.search(&filter))
+        .await
+        .unwrap_or_else(|err| Err(format!("Search failed: {}", err)));
+    match result {
+        Ok(runs) => Ok(warp::reply::json(&RunsResult { success: true, runs, error: None })),
+        Err(err) => Ok(warp::reply::json(&RunsResult { success: false, runs: Vec::new(), error: Some(err) })),
+    }
+}
+
+// Handler for one run's database record
+async fn get_run_handler(run_id: String, devices: Arc<Devices>) -> std::result::Result<impl Reply, Rejection> {
+    let result = tokio::task::spawn_blocking(move || devices.runs.get(&run_id))
+        .await
+        .unwrap_or_else(|err| Err(format!("Lookup failed: {}", err)));
+    match result {
+        Ok(Some(run)) => Ok(warp::reply::json(&RunResult { success: true, run: Some(run), error: None })),
+        Ok(None) => Ok(warp::reply::json(&RunResult { success: false, run: None, error: Some("Unknown run".to_string()) })),
+        Err(err) => Ok(warp::reply::json(&RunResult { success: false, run: None, error: Some(err) })),
+    }
+}
+
+fn sample_reply(result: std::result::Result<Sample, String>) -> warp::reply::Json {
+    match result {
+        Ok(sample) => warp::reply::json(&SampleResult { success: true, sample: Some(sample), error: None }),
+        Err(err) => warp::reply::json(&SampleResult { success: false, sample: None, error: Some(err) }),
+    }
+}
+
+// Handler for registering a sample
+async fn add_sample_handler(
+    identity: Identity,
+    params: NewSampleParams,
+    devices: Arc<Devices>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let result = tokio::task::spawn_blocking(move || {
+        devices.runs.add_sample(&params.sample_id, params.details, Some(&identity.name))
+    })
+    .await
+    .unwrap_or_else(|err| Err(format!("Saving the sample failed: {}", err)));
+    Ok(sample_reply(result))
+}
+
+// Handler for editing a sample's details
+async fn update_sample_handler(
+    sample_id: String,
+    details: SampleDetails,
+    devices: Arc<Devices>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let result = tokio::task::spawn_blocking(move || devices.runs.update_sample(&sample_id, details))
+        .await
+        .unwrap_or_else(|err| Err(format!("Saving the sample failed: {}", err)));
+    Ok(sample_reply(result))
+}
+
+// Handler for one sample
+async fn get_sample_handler(sample_id: String, devices: Arc<Devices>) -> std::result::Result<impl Reply, Rejection> {
+    let result = tokio::task::spawn_blocking(move || {
+        devices.runs.get_sample(&sample_id)?.ok_or_else(|| format!("Unknown sample: {}", sample_id))
+    })
+    .await
+    .unwrap_or_else(|err| Err(format!("Lookup failed: {}", err)));
+    Ok(sample_reply(result))
+}
+
+// Handler for listing every registered sample
+async fn list_samples_handler(devices: Arc<Devices>) -> std::result::Result<impl Reply, Rejection> {
+    let result = tokio::task::spawn_blocking(move || devices.runs.list_samples())
+        .await
+        .unwrap_or_else(|err| Err(format!("Lookup failed: {}", err)));
+    match result {
+        Ok(samples) => Ok(warp::reply::json(&SamplesResult { success: true, samples, error: None })),
+        Err(err) => Ok(warp::reply::json(&SamplesResult { success: false, samples: Vec::new(), error: Some(err) })),
+    }
+}
+
+// Handler for a sample's past runs with their data, optionally of one experiment
+async fn sample_history_handler(
+    sample_id: String,
+    query: SampleHistoryQuery,
+    devices: Arc<Devices>,
+) -> std::result::Result<impl Reply, Rejection> {
+    let result = tokio::task::spawn_blocking(move || devices.runs.sample_history(&sample_id, query.experiment))
+        .await
+        .unwrap_or_else(|err| Err(format!("Lookup failed: {}", err)));
+    match result {
+        Ok(runs) => Ok(warp::reply::json(&SampleHistoryResult { success: true, runs, error: None })),
+        Err(err) => Ok(warp::reply::json(&SampleHistoryResult { success: false, runs: Vec::new(), error: Some(err) })),
+    }
+}
+
+// Handler for listing the optical path calibration tables
+async fn list_calibrations_handler() -> std::result::Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&calibration::list()))
+}
+
+// Handler for one optical path's calibration table
+async fn get_calibration_handler(name: String) -> std::result::Result<impl Reply, Rejection> {
+    match PathCalibration::load(&name) {
+        Ok(calibration) => Ok(warp::reply::json(&CalibrationResult { success: true, calibration: Some(calibration), error: None })),
+        Err(err) => Ok(warp::reply::json(&CalibrationResult { success: false, calibration: None, error: Some(err) })),
+    }
+}
+
+// Handler for listing stored wavelength sweep references
+async fn list_references_handler() -> std::result::Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&reference::list()))
+}
+
+// Handler for one stored reference with its points
+async fn get_reference_handler(name: String) -> std::result::Result<impl Reply, Rejection> {
+    match Reference::load(&name) {
+        Ok(stored) => Ok(warp::reply::json(&ReferenceResult { success: true, reference: Some(stored), error: None })),
+        Err(err) => Ok(warp::reply::json(&ReferenceResult { success: false, reference: None, error: Some(err) })),
+    }
+}
+
+// Handler for listing built-in experiments and recipe files
+async fn list_experiments_handler() -> std::result::Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&ExperimentList {
+        builtin: BUILTIN_EXPERIMENTS,
+        recipes: recipe::list(),
+    }))
+}
+
 // Function to serve frontend
 fn serve_frontend() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     // Read the HTML file - this would be your frontend from the first artifact
@@ -562,34 +992,369 @@ fn serve_frontend() -> impl Filter<Extract = impl Reply, Error = Rejection> + Cl
 
 // Start the web server
 pub async fn start_server() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let state = Arc::new(Mutex::new(AppState::new()));
+    let auth = Arc::new(Auth::new());
+    if !auth.open_access() && !auth.has_users() {
+        return Err("No user accounts, create an admin first with `user add <name> --role admin`".into());
+    }
+    if auth.open_access() {
+        warn!("No user accounts, everyone on this machine has admin access until one is created");
+    }
+
+    let devices = Arc::new(Devices::new());
+    let queue = Arc::new(Mutex::new(JobQueue::load()));
+    let reservations = Arc::new(Reservations::default());
+    let notify = Arc::new(Notify::new());
+
+    // Background worker running queued jobs one at a time
+    let worker_devices = devices.clone();
+    tokio::spawn(queue::run_worker(
+        queue.clone(),
+        reservations.clone(),
+        notify.clone(),
+        move |job: Job| {
+            let run_id = job.run_id.unwrap_or_else(|| format!("job-{}", job.id));
+            let _audit = audit::enter(audit::Context {
+                user: job.submitted_by.clone(),
+                run_id: Some(run_id.clone()),
+                job_id: Some(job.id),
+                simulated: false,
+            });
+            run_experiment(
+                &worker_devices,
+                &run_id,
+                &config::get().data_dir,
+                &job.experiment,
+                job.params,
+                job.submitted_by.as_deref(),
+            )
+        },
+    ));
+    
+    // Background health checks, reopening instruments that stop answering
+    tokio::spawn(health::run_monitor(devices.clone()));
     
     let static_files = warp::path("icons")
-    .and(warp::fs::dir("frontend/icons"));
+    .and(warp::fs::dir(config::get().frontend_dir.join("icons")));
 
+    // Routes for logging in and out
+    let login = warp::path!("api" / "login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_shared(auth.clone()))
+        .and_then(login_handler);
+    
+    let logout = warp::path!("api" / "logout")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_shared(auth.clone()))
+        .and_then(logout_handler);
+    
+    let me = warp::path!("api" / "me")
+        .and(warp::get())
+        .and(auth::require(auth.clone(), Role::Viewer))
+        .map(|identity: Identity| warp::reply::json(&identity));
+    
     // Route for checking device connection
     let check_connection = warp::path!("api" / "check-connection" / String)
         .and(warp::get())
-        .and(with_state(state.clone()))
+        .and(with_role(auth.clone(), Role::Operator))
+        .and(with_shared(devices.clone()))
         .and_then(check_connection_handler);
     
+    // Routes for manual instrument control
+    let get_current = warp::path!("api" / "instruments" / "cld1015" / "current")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(devices.clone()))
+        .and(with_shared(reservations.clone()))
+        .and_then(get_current_handler);
+    
+    let set_current = warp::path!("api" / "instruments" / "cld1015" / "current")
+        .and(warp::post())
+        .and(auth::require(auth.clone(), Role::Operator))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_shared(devices.clone()))
+        .and(with_shared(reservations.clone()))
+        .and_then(set_current_handler);
+    
+    let get_wavelength = warp::path!("api" / "instruments" / "n77" / u8 / "wavelength")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(devices.clone()))
+        .and(with_shared(reservations.clone()))
+        .and_then(get_wavelength_handler);
+    
+    let set_wavelength = warp::path!("api" / "instruments" / "n77" / u8 / "wavelength")
+        .and(warp::post())
+        .and(auth::require(auth.clone(), Role::Operator))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_shared(devices.clone()))
+        .and(with_shared(reservations.clone()))
+        .and_then(set_wavelength_handler);
+    
+    let get_power = warp::path!("api" / "instruments" / "power_meter" / u8 / "power")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(devices.clone()))
+        .and(with_shared(reservations.clone()))
+        .and_then(get_power_handler);
+    
+    let get_peak = warp::path!("api" / "instruments" / "osa" / "peak")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Operator))
+        .and(with_shared(devices.clone()))
+        .and(with_shared(reservations.clone()))
+        .and_then(get_peak_handler);
+    
+    let scpi = warp::path!("api" / "instruments" / String / "scpi")
+        .and(warp::post())
+        .and(auth::require(auth.clone(), Role::Admin))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_shared(devices.clone()))
+        .and(with_shared(reservations.clone()))
+        .and_then(scpi_handler);
+    
+    // Route for releasing a device to other programs
+    let disconnect = warp::path!("api" / "instruments" / String / "disconnect")
+        .and(warp::post())
+        .and(with_role(auth.clone(), Role::Operator))
+        .and(with_shared(devices.clone()))
+        .and(with_shared(reservations.clone()))
+        .and_then(disconnect_handler);
+    
+    // Route for instrument health and status history
+    let instruments_status = warp::path!("api" / "instruments" / "status")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(devices.clone()))
+        .and_then(instruments_status_handler);
+    
     // Route for running experiments
     let run_experiment = warp::path!("api" / "run-experiment" / String)
         .and(warp::post())
+        .and(auth::require(auth.clone(), Role::Operator))
         .and(warp::body::content_length_limit(1024 * 16))
         .and(warp::body::bytes())
-        .and(with_state(state.clone()))
+        .and(with_shared(devices.clone()))
+        .and(with_shared(reservations.clone()))
         .and_then(run_experiment_handler);
     
-    // Combine routes
-    let routes = serve_frontend()
+    let estimate = warp::path!("api" / "estimate" / String)
+        .and(warp::post())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(estimate_handler);
+    
+    // Routes for following time series while they run
+    let list_live = warp::path!("api" / "live")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(devices.live.clone()))
+        .and_then(list_live_handler);
+    
+    let live_stream = warp::path!("api" / "live" / String)
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(devices.live.clone()))
+        .and_then(live_stream_handler);
+    
+    // Routes for the experiment queue
+    let list_queue = warp::path!("api" / "queue")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(queue.clone()))
+        .and_then(list_queue_handler);
+    
+    let submit_jobs = warp::path!("api" / "queue")
+        .and(warp::post())
+        .and(auth::require(auth.clone(), Role::Operator))
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::json())
+        .and(with_shared(queue.clone()))
+        .and(with_shared(notify.clone()))
+        .and(with_shared(devices.clone()))
+        .and_then(submit_jobs_handler);
+    
+    let cancel_job = warp::path!("api" / "queue" / u64 / "cancel")
+        .and(warp::post())
+        .and(with_role(auth.clone(), Role::Operator))
+        .and(with_shared(queue.clone()))
+        .and_then(cancel_job_handler);
+    
+    let move_job = warp::path!("api" / "queue" / u64 / "move")
+        .and(warp::post())
+        .and(with_role(auth.clone(), Role::Operator))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .and(with_shared(queue.clone()))
+        .and_then(move_job_handler);
+    
+    // Routes for the run database
+    let search_runs = warp::path!("api" / "runs")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(warp::query::<RunFilter>())
+        .and(with_shared(devices.clone()))
+        .and_then(search_runs_handler);
+    
+    let get_run = warp::path!("api" / "runs" / String)
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(devices.clone()))
+        .and_then(get_run_handler);
+    
+    // Routes for the sample registry
+    let list_samples = warp::path!("api" / "samples")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(devices.clone()))
+        .and_then(list_samples_handler);
+    
+    let add_sample = warp::path!("api" / "samples")
+        .and(warp::post())
+        .and(auth::require(auth.clone(), Role::Operator))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(with_shared(devices.clone()))
+        .and_then(add_sample_handler);
+    
+    let get_sample = warp::path!("api" / "samples" / String)
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(with_shared(devices.clone()))
+        .and_then(get_sample_handler);
+    
+    let update_sample = warp::path!("api" / "samples" / String)
+        .and(warp::put())
+        .and(with_role(auth.clone(), Role::Operator))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(with_shared(devices.clone()))
+        .and_then(update_sample_handler);
+    
+    let sample_history = warp::path!("api" / "samples" / String / "history")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(warp::query::<SampleHistoryQuery>())
+        .and(with_shared(devices.clone()))
+        .and_then(sample_history_handler);
+    
+    // Routes for instrument transaction logs
+    let run_transactions = warp::path!("api" / "runs" / String / "transactions")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and_then(run_transactions_handler);
+    
+    let replay_run = warp::path!("api" / "runs" / String / "replay")
+        .and(warp::post())
+        .and(auth::require(auth.clone(), Role::Operator))
+        .and(with_shared(devices.clone()))
+        .and_then(replay_handler);
+    
+    let recent_transactions = warp::path!("api" / "transactions")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(warp::query::<TransactionsQuery>())
+        .and_then(recent_transactions_handler);
+    
+    // Route for the audit trail of laser output and setpoint changes
+    let audit_trail = warp::path!("api" / "audit")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and(warp::query::<AuditQuery>())
+        .and_then(audit_handler);
+    
+    // Routes for the optical path calibration tables
+    let list_calibrations = warp::path!("api" / "calibrations")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and_then(list_calibrations_handler);
+    
+    let get_calibration = warp::path!("api" / "calibrations" / String)
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and_then(get_calibration_handler);
+    
+    // Routes for the stored references of insertion loss measurements
+    let list_references = warp::path!("api" / "references")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and_then(list_references_handler);
+    
+    let get_reference = warp::path!("api" / "references" / String)
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and_then(get_reference_handler);
+    
+    // Route for listing experiments
+    let list_experiments = warp::path!("api" / "experiments")
+        .and(warp::get())
+        .and(with_role(auth.clone(), Role::Viewer))
+        .and_then(list_experiments_handler);
+    
+    // Combine routes, boxing each group so the combined filter type stays shallow enough to compile
+    let pages = serve_frontend()
         .or(static_files)
-        .or(check_connection)
-        .or(run_experiment)
-        .with(with_cors());
+        .or(login)
+        .or(logout)
+        .or(me)
+        .boxed();
+    let instruments = check_connection
+        .or(instruments_status)
+        .or(disconnect)
+        .or(get_current)
+        .or(set_current)
+        .or(get_wavelength)
+        .or(set_wavelength)
+        .or(get_power)
+        .or(get_peak)
+        .or(scpi)
+        .boxed();
+    let records = list_samples
+        .or(add_sample)
+        .or(get_sample)
+        .or(update_sample)
+        .or(sample_history)
+        .or(search_runs)
+        .or(get_run)
+        .or(run_transactions)
+        .or(recent_transactions)
+        .or(replay_run)
+        .or(audit_trail)
+        .boxed();
+    let experiments = run_experiment
+        .or(estimate)
+        .or(list_live)
+        .or(live_stream)
+        .or(list_experiments)
+        .or(list_calibrations)
+        .or(get_calibration)
+        .or(list_references)
+        .or(get_reference)
+        .boxed();
+    let queue = list_queue
+        .or(submit_jobs)
+        .or(cancel_job)
+        .or(move_job)
+        .boxed();
+    // Only the served frontend talks to the API from a browser, so no CORS headers are sent and requests
+    // other sites' pages make through the operator's browser are refused
+    let api = pages
+        .or(instruments)
+        .or(records)
+        .or(experiments)
+        .or(queue);
+    let routes = auth::same_origin()
+        .and(api)
+        .recover(auth::handle_rejection);
     
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    println!("Server started at http://{}", addr);
+    let addr = config::get().addr();
+    info!("Server started at http://{}", addr);
     warp::serve(routes).run(addr).await;
     
     Ok(())